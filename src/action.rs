@@ -1,33 +1,96 @@
+mod audit;
+mod backend;
+mod backup;
+mod batch;
+mod ccd;
+mod export;
+mod lock;
+mod ovpn;
+mod passphrase;
+mod rollback;
 mod shared;
+mod template;
+
+pub use batch::BatchOutcome;
+pub use passphrase::PassphraseOutput;
 
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io::Write,
-    path::Path,
+    net::Ipv4Addr,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
 };
 
-use chrono::Duration;
+use chrono::{DateTime, Duration, Local, Utc};
 use color_eyre::eyre::{bail, eyre, Context};
 use fs_more::directory::{
     copy_directory, BrokenSymlinkBehaviour, DestinationDirectoryRule, DirectoryCopyDepthLimit,
     DirectoryCopyOptions, SymlinkBehaviour,
 };
+use ipnet::Ipv4Net;
 use itertools::Itertools;
-use log::info;
+use log::{error, info, warn};
+use serde::Deserialize;
 use temp_dir::TempDir;
 use xshell::{cmd, Shell};
 use zip::ZipWriter;
 use zip_extensions::ZipWriterExtensions;
 
 use crate::{
-    action::shared::{get_cert_path, get_expired_users, get_key_path, get_users, regenerate_crl},
-    config::{Config, Profile},
-    types::Username,
+    action::{
+        audit::{read_audit_log, AuditEntry, AuditLog, AuditOperation},
+        backend::{get_backend, Backend, EasyRsa},
+        backup::{read_backup, write_backup, BackupManifest, StagingDir},
+        batch::Batch,
+        ccd::{allocate_address, check_address, get_assigned_addresses, CcdFile},
+        export::{build_p12, render_pem_bundle},
+        lock::PkiLock,
+        ovpn::render_unified_profile,
+        passphrase::{PackagePassphrases, PackagedKey, PassphraseReader},
+        rollback::Rollback,
+        shared::{
+            get_cert_path, get_current_records, get_expired_users, get_index_records, get_key_path,
+            get_users, read_password_file,
+        },
+        template::TemplateVars,
+    },
+    config::{Config, Management, PackagingMode, Profile},
+    management::ManagementClient,
+    metadata::{MetadataChanges, MetadataFilter, MetadataStore, UserMetadata},
+    output::{
+        print_records, HistoryRecord, ImportRecord, OnlineRecord, OutputFormat, ProfileRecord,
+        UserInfoRecord, UserRecord,
+    },
+    pki::{read_cert, read_crl_serials, verify_cert_issuer, IndexRecord, Revocation},
+    shell::{print_dry_run, run_cmd},
+    status::read_status,
+    types::{ExportFormat, PassphraseSource, Username},
 };
 
-pub fn init_config(config_path: impl AsRef<Path>, allow_overwrite: bool) -> color_eyre::Result<()> {
+/// Options that affect how actions with side effects are carried out.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecOptions {
+    /// Proceed with potentially destructive actions without confirmation.
+    pub force: bool,
+    /// Only print the commands and file writes that would be executed.
+    pub dry_run: bool,
+    /// Carry on past users that fail when acting on several users.
+    pub keep_going: bool,
+    /// Do not restore the PKI when an action fails.
+    pub no_rollback: bool,
+}
+
+pub fn init_config(config_path: impl AsRef<Path>, opts: ExecOptions) -> color_eyre::Result<()> {
+    let ExecOptions { force: allow_overwrite, dry_run, .. } = opts;
     let config_path = config_path.as_ref();
 
+    if dry_run {
+        print_dry_run(format!("write example config file to {config_path:?}"));
+        return Ok(());
+    }
+
     // create parent dir
     let parent = config_path
         .parent()
@@ -55,173 +118,1080 @@ pub fn init_config(config_path: impl AsRef<Path>, allow_overwrite: bool) -> colo
     Ok(())
 }
 
-pub fn list_profiles(config: &Config, active: &Profile) {
-    let output = config
-        .profiles
+pub fn init_pki(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    tls_crypt: bool,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let ExecOptions { force, dry_run, .. } = opts;
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+
+    // sanity checks
+    let Some(ref pki) = profile.pki else {
+        bail!(r#"Profile "{profile_name}" does not contain a "pki" section"#);
+    };
+
+    let easy_rsa = &config.easy_rsa_path;
+    let force_arg = force.then_some("--batch");
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+
+    // `init-pki` deletes everything in the PKI directory
+    let is_populated = fs::read_dir(&pki_dir).is_ok_and(|mut entries| entries.next().is_some());
+    if is_populated && !force {
+        bail!("The PKI directory {pki_dir:?} is not empty; use `--force` to overwrite it");
+    }
+
+    let pki_args = pki.easy_rsa_args();
+    let pki_args = &pki_args; // otherwise use of moved value
+    let ca_cn_arg = format!("--req-cn={}", pki.ca_common_name);
+    let ca_days_arg = pki.ca_days.map(|d| format!("--days={d}"));
+    let server_name = &pki.server_name;
+
+    // all inputs are supplied as arguments, so the remaining commands run in batch mode
+    let sh = Shell::new().wrap_err("Failed to create subshell")?;
+    run_cmd(
+        cmd!(sh, "{easy_rsa} {force_arg...} --pki-dir={pki_dir} init-pki"),
+        dry_run,
+    )
+    .wrap_err("PKI initialisation command failed to execute")?;
+    run_cmd(cmd!(
+        sh,
+        "{easy_rsa} --batch --pki-dir={pki_dir} {pki_args...} {ca_cn_arg} {ca_days_arg...} build-ca nopass"
+    ), dry_run)
+    .wrap_err("CA creation command failed to execute")?;
+    run_cmd(cmd!(
+        sh,
+        "{easy_rsa} --batch --pki-dir={pki_dir} {pki_args...} build-server-full {server_name} nopass"
+    ), dry_run)
+    .wrap_err("Server certificate creation command failed to execute")?;
+
+    // the CRL is always generated by easy-rsa here, as it issued the CA
+    EasyRsa::new(config_dir, config, profile, opts).gen_crl()?;
+    AuditLog::new(config_dir, profile, opts).record(AuditOperation::GenCrl, None, None, None)?;
+
+    if tls_crypt {
+        run_cmd(
+            cmd!(
+                sh,
+                "{easy_rsa} --batch --pki-dir={pki_dir} gen-tls-crypt-key"
+            ),
+            dry_run,
+        )
+        .wrap_err("tls-crypt key generation command failed to execute")?;
+    }
+
+    if !dry_run {
+        info!(r#"Initialised PKI for profile "{profile_name}" in {pki_dir:?}"#);
+    }
+    Ok(())
+}
+
+/// Write a timestamped archive of the PKI and the profile's config section.
+pub fn backup_pki(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    output_path: Option<&Path>,
+    password_file: Option<&Path>,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let ExecOptions { force, dry_run, .. } = opts;
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+    if !pki_dir.is_dir() {
+        bail!("The PKI directory {pki_dir:?} does not exist");
+    }
+    let output_path = match output_path {
+        Some(path) => path.to_owned(),
+        None => {
+            let timestamp = Local::now().format("%Y%m%dT%H%M%S");
+            PathBuf::from(format!("{profile_name}-pki-{timestamp}.zip"))
+        }
+    };
+    let password = password_file.map(read_password_file).transpose()?;
+
+    if dry_run {
+        print_dry_run(format!("write backup of {pki_dir:?} to {output_path:?}"));
+        return Ok(());
+    }
+    // the archive contains private keys, and is not necessarily encrypted
+    let file = create_secret_output_file(&output_path, force)
+        .wrap_err_with(|| format!("Failed to create {output_path:?}"))?;
+    if let Err(err) = write_backup(&pki_dir, profile, file, password.as_deref()) {
+        // don't leave an incomplete archive behind
+        let _ = fs::remove_file(&output_path);
+        return Err(err);
+    }
+    info!(r#"Backed up the PKI of profile "{profile_name}" to {output_path:?}"#);
+
+    Ok(())
+}
+
+/// Restore the PKI from an archive written by [`backup_pki`].
+///
+/// The archive is verified, and the restored certificates are checked against
+/// the restored CA, before anything in the PKI directory is touched.
+pub fn restore_pki(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    archive_path: &Path,
+    password_file: Option<&Path>,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let ExecOptions { force, dry_run, .. } = opts;
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+    let is_populated = fs::read_dir(&pki_dir).is_ok_and(|mut entries| entries.next().is_some());
+    if is_populated && !force {
+        bail!("The PKI directory {pki_dir:?} is not empty; use `--force` to overwrite it");
+    }
+    let password = password_file.map(read_password_file).transpose()?;
+
+    // extract and verify, next to the PKI directory so that it can be moved into
+    // place at once
+    let temp_dir;
+    let staging_dir = match dry_run {
+        true => None,
+        false => Some(StagingDir::create(&pki_dir)?),
+    };
+    let work_dir = match staging_dir {
+        Some(ref staging_dir) => staging_dir.path(),
+        None => {
+            temp_dir = TempDir::with_prefix("openvpn-cred-management-restore-")
+                .wrap_err("Cannot create temporary working directory")?;
+            temp_dir.path()
+        }
+    };
+    // the archive contains private keys
+    fs::set_permissions(work_dir, fs::Permissions::from_mode(0o700))
+        .wrap_err_with(|| format!("Failed to restrict permissions of {work_dir:?}"))?;
+    let backup = read_backup(archive_path, password.as_deref(), work_dir)
+        .wrap_err_with(|| format!("Failed to verify archive {archive_path:?}"))?;
+    let BackupManifest { created, profile: ref backup_profile, .. } = backup.manifest;
+    info!(r#"Verified backup of profile "{backup_profile}" created at {created}"#);
+
+    let ca_path = backup.pki_dir.join("ca.crt");
+    if !ca_path.is_file() {
+        bail!("The archive does not contain a CA certificate");
+    }
+    let issued_dir = backup.pki_dir.join("issued");
+    let cert_paths = match fs::read_dir(&issued_dir) {
+        Ok(entries) => entries
+            .map(|entry| Ok(entry?.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .wrap_err_with(|| format!("Failed to read directory {issued_dir:?}"))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Failed to read directory {issued_dir:?}"))
+        }
+    };
+    let problems = cert_paths
         .iter()
-        .map(|p| {
-            let name = &p.name;
-            let is_active = p == active;
-            let is_default = config.default_profile.as_ref().is_some_and(|dp| name == dp);
-            match (is_active, is_default) {
-                (true, true) => format!("{name} (active, default)"),
-                (true, false) => format!("{name} (active)"),
-                (false, true) => format!("{name} (default)"),
-                (false, false) => name.to_owned(),
+        .sorted()
+        .filter_map(|path| {
+            let err = verify_cert_issuer(path, &ca_path).err()?;
+            let subpath = path.strip_prefix(&backup.pki_dir).unwrap_or(path);
+            Some(format!("{subpath:?}: {err:#}"))
+        })
+        .collect_vec();
+    if !problems.is_empty() {
+        bail!(
+            "The restored certificates do not match the restored CA:\n{}",
+            problems.join("\n")
+        );
+    }
+
+    if backup_profile != profile_name {
+        warn!(r#"Restoring a backup of profile "{backup_profile}" into profile "{profile_name}""#);
+    }
+    let backup_section = toml_edit::de::from_str::<Profile>(&backup.profile_section);
+    if backup_section.as_ref().ok() != Some(profile) {
+        warn!(
+            "The configuration of profile \"{profile_name}\" differs from the backup, which had:\n{}",
+            backup.profile_section
+        );
+    }
+
+    // replace
+    if dry_run {
+        print_dry_run(format!(
+            "replace the content of {pki_dir:?} with the PKI in {archive_path:?}"
+        ));
+        return Ok(());
+    }
+    // the previous PKI is moved aside and removed along with the staging directory
+    let previous_dir = work_dir.join("previous");
+    if let Ok(metadata) = pki_dir.symlink_metadata() {
+        // the archive does not record the mode of the PKI directory itself
+        fs::set_permissions(&backup.pki_dir, metadata.permissions())
+            .wrap_err_with(|| format!("Failed to set permissions of {:?}", backup.pki_dir))?;
+        fs::rename(&pki_dir, &previous_dir)
+            .wrap_err_with(|| format!("Failed to move the PKI directory {pki_dir:?} aside"))?;
+    }
+    if let Err(err) = fs::rename(&backup.pki_dir, &pki_dir) {
+        if previous_dir.exists() {
+            if let Err(err) = fs::rename(&previous_dir, &pki_dir) {
+                error!("Failed to move the previous PKI back to {pki_dir:?}; it is kept in {previous_dir:?}: {err}");
+                if let Some(staging_dir) = staging_dir {
+                    staging_dir.keep();
+                }
             }
+        }
+        return Err(err).wrap_err_with(|| {
+            format!(
+                "Failed to move the restored PKI {:?} to {pki_dir:?}",
+                backup.pki_dir
+            )
+        });
+    }
+    info!(r#"Restored the PKI of profile "{profile_name}" in {pki_dir:?}"#);
+
+    Ok(())
+}
+
+pub fn list_profiles(
+    config: &Config,
+    active: &Profile,
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let records = config
+        .profiles
+        .iter()
+        .map(|p| ProfileRecord {
+            name: p.name.clone(),
+            pki_dir: p.easy_rsa_pki_dir.clone(),
+            is_active: p == active,
+            is_default: config.default_profile.as_ref() == Some(&p.name),
         })
-        .join("\n");
-    println!("{output}");
+        .collect_vec();
+    print_records(&records, format, |p| {
+        let name = &p.name;
+        match (p.is_active, p.is_default) {
+            (true, true) => format!("{name} (active, default)"),
+            (true, false) => format!("{name} (active)"),
+            (false, true) => format!("{name} (default)"),
+            (false, false) => name.to_owned(),
+        }
+    })
 }
 
-pub fn list_users(config_dir: impl AsRef<Path>, profile: &Profile) -> color_eyre::Result<()> {
+pub fn list_users(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    filter: &MetadataFilter,
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
 
-    let output = get_users(config_dir, profile)
-        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?
-        .into_iter()
-        .join("\n");
-    println!("{output}");
-    Ok(())
+    let records = get_current_records(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    print_user_records(config_dir, profile, records.values(), filter, format)
 }
 
 pub fn list_near_expired(
     config_dir: impl AsRef<Path>,
-    config: &Config,
     profile: &Profile,
     near_expiry_period: Duration,
+    filter: &MetadataFilter,
+    format: OutputFormat,
 ) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
 
-    let output = get_expired_users(config_dir, config, profile, near_expiry_period)
-        .wrap_err_with(|| format!(r#"Cannot get expired users of "{profile_name}" profile"#))?
-        .into_iter()
-        .join("\n");
-    println!("{output}");
-    Ok(())
+    let records = get_expired_users(config_dir, profile, near_expiry_period)
+        .wrap_err_with(|| format!(r#"Cannot get expired users of "{profile_name}" profile"#))?;
+    print_user_records(config_dir, profile, records.values(), filter, format)
 }
 
-pub fn info_user(
+pub fn list_online(
     config_dir: impl AsRef<Path>,
-    config: &Config,
     profile: &Profile,
     usernames: &[Username],
+    only_connected: bool,
+    format: OutputFormat,
 ) -> color_eyre::Result<()> {
     let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
 
     // sanity check
+    let Some(ref status_file) = profile.status_file else {
+        bail!(r#"Profile "{profile_name}" does not have a "status-file" set"#);
+    };
+
     let known_users = get_users(config_dir, profile)
         .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
-    for username in usernames {
-        if !known_users.contains(username) {
-            bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
+    // allow `status_file` to be relative to the config file
+    let connections = read_status(config_dir.join(status_file))?;
+
+    // connected clients may not have a current certificate, e.g. if recently revoked
+    let mut records = connections
+        .into_iter()
+        .map(|c| OnlineRecord {
+            is_known: known_users.iter().any(|u| u.as_str() == c.common_name),
+            username: c.common_name,
+            profile: profile_name.clone(),
+            is_online: true,
+            real_address: Some(c.real_address),
+            virtual_address: c.virtual_address,
+            bytes_received: Some(c.bytes_received),
+            bytes_sent: Some(c.bytes_sent),
+            connected_since: c.connected_since,
+        })
+        .collect_vec();
+    if !only_connected {
+        let offline = known_users
+            .iter()
+            .filter(|u| !records.iter().any(|r| r.username == u.as_str()))
+            .map(|u| OnlineRecord {
+                username: u.to_string(),
+                profile: profile_name.clone(),
+                is_known: true,
+                is_online: false,
+                real_address: None,
+                virtual_address: None,
+                bytes_received: None,
+                bytes_sent: None,
+                connected_since: None,
+            })
+            .collect_vec();
+        records.extend(offline);
+    }
+    if !usernames.is_empty() {
+        records.retain(|r| usernames.iter().any(|u| u.as_str() == r.username));
+    }
+    records.sort_by(|a, b| a.username.cmp(&b.username));
+
+    print_records(&records, format, |r| {
+        let username = &r.username;
+        if !r.is_online {
+            return format!("{username}: offline");
+        }
+        let mut line = format!("{username}: online");
+        if let Some(since) = r.connected_since {
+            line.push_str(&format!(" since {since}"));
+        }
+        if let Some(ref address) = r.real_address {
+            line.push_str(&format!(" from {address}"));
         }
+        if let Some(ref address) = r.virtual_address {
+            line.push_str(&format!(" as {address}"));
+        }
+        if !r.is_known {
+            line.push_str(" (no current certificate)");
+        }
+        line
+    })
+}
+
+pub fn show_log(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    usernames: &[Username],
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+
+    // sanity check
+    let Some(ref audit_log) = profile.audit_log else {
+        bail!(r#"Profile "{profile_name}" does not have an "audit-log" set"#);
+    };
+
+    // allow `audit_log` to be relative to the config file
+    let entries = read_audit_log(config_dir.join(audit_log))?
+        .into_iter()
+        // the log may be shared between profiles
+        .filter(|e| &e.profile == profile_name)
+        .filter(|e| {
+            usernames.is_empty()
+                || (e.username.as_ref()).is_some_and(|n| usernames.iter().any(|u| u.as_str() == n))
+        })
+        .filter(|e| since.is_none_or(|since| e.timestamp >= since))
+        .filter(|e| until.is_none_or(|until| e.timestamp < until))
+        .collect_vec();
+
+    print_records(&entries, format, |e: &AuditEntry| {
+        let operator = match (&e.os_user, &e.sudo_user) {
+            (Some(user), Some(sudo_user)) => format!("{user} (via sudo by {sudo_user})"),
+            (Some(user), None) => user.clone(),
+            (None, _) => "unknown user".into(),
+        };
+        let mut line = format!("{}: {operator} ran {}", e.timestamp, e.operation);
+        if let Some(ref username) = e.username {
+            line.push_str(&format!(r#" for "{username}""#));
+        }
+        if let Some(ref serial) = e.serial {
+            line.push_str(&format!(" (serial {serial})"));
+        }
+        if let Some(days) = e.days {
+            line.push_str(&format!(", valid for {days} days"));
+        }
+        if e.force {
+            line.push_str(", forced");
+        }
+        line
+    })
+}
+
+/// Get the users whose current certificate expires within `period`.
+pub fn select_expiring_users(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    period: Duration,
+) -> color_eyre::Result<Vec<Username>> {
+    let profile_name = &profile.name;
+
+    let users = get_expired_users(config_dir, profile, period)
+        .wrap_err_with(|| format!(r#"Cannot get expired users of "{profile_name}" profile"#))?
+        .into_keys()
+        .collect();
+    Ok(users)
+}
+
+/// Show the details of the current certificate of users, read by parsing the
+/// certificate rather than via easy-rsa.
+pub fn info_user(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    usernames: &[Username],
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+
+    // sanity check
+    let records = get_index_records(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    let user_records = usernames
+        .iter()
+        .map(|username| {
+            let user_records = records
+                .iter()
+                .filter(|r| r.common_name() == Some(username.as_str()))
+                .collect_vec();
+            if !user_records.iter().any(|r| r.is_active()) {
+                bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
+            }
+            Ok((username, user_records))
+        })
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+    let metadata_store = MetadataStore::load(config_dir, profile)?;
+    let crl_path = pki_dir.join("crl.pem");
+    let crl_serials = crl_path
+        .is_file()
+        .then(|| read_crl_serials(&crl_path))
+        .transpose()?;
+    // allow `ccd_dir` to be relative to the config file
+    let ccd_dir = profile.ccd_dir.as_ref().map(|dir| config_dir.join(dir));
+
+    let mut infos = vec![];
+    for (username, user_records) in &user_records {
+        let cert_path = get_cert_path(config_dir, profile, username)?;
+        let cert = read_cert(&cert_path)?;
+        let Some(record) = user_records.iter().find(|r| r.serial == cert.serial) else {
+            bail!(
+                r#"The certificate of user "{username}" ({}) is not in the PKI database"#,
+                cert.serial
+            );
+        };
+        let metadata = metadata_store.get(username);
+        infos.push(UserInfoRecord {
+            username: username.to_string(),
+            profile: profile_name.clone(),
+            subject: cert.subject,
+            serial: cert.serial,
+            issuer: cert.issuer,
+            status: record.status,
+            not_before: cert.not_before,
+            not_after: cert.not_after,
+            days_remaining: (cert.not_after - Utc::now()).num_days(),
+            key_type: cert.key_type,
+            key_size: cert.key_size,
+            fingerprint_sha256: cert.fingerprint,
+            in_crl: crl_serials.as_ref().map(|s| s.contains(&record.serial)),
+            has_ccd_file: ccd_dir
+                .as_ref()
+                .is_some_and(|dir| dir.join(username).is_file()),
+            other_serials: user_records
+                .iter()
+                .filter(|r| r.serial != record.serial)
+                .map(|r| r.serial.clone())
+                .collect(),
+            display_name: metadata.and_then(|m| m.display_name.clone()),
+            email: metadata.and_then(|m| m.email.clone()),
+            note: metadata.and_then(|m| m.note.clone()),
+            tags: metadata
+                .map(|m| m.tags.iter().cloned().collect())
+                .unwrap_or_default(),
+        });
     }
 
-    let easy_rsa = &config.easy_rsa_path;
+    print_records(&infos, format, |info| {
+        let mut lines = vec![format!("User: {}", info.username)];
+        let mut push = |label: &str, value: String| lines.push(format!("  {label}: {value}"));
+        if let Some(ref display_name) = info.display_name {
+            push("Name", display_name.clone());
+        }
+        if let Some(ref email) = info.email {
+            push("Email", email.clone());
+        }
+        if let Some(ref note) = info.note {
+            push("Note", note.clone());
+        }
+        if !info.tags.is_empty() {
+            push("Tags", info.tags.join(", "));
+        }
+        push("Subject", info.subject.clone());
+        push("Serial", info.serial.clone());
+        push("Issuer", info.issuer.clone());
+        push("Status", info.status.to_string());
+        let remaining = match info.days_remaining {
+            days @ 0.. => format!("{days} days remaining"),
+            days => format!("expired {} days ago", -days),
+        };
+        push(
+            "Validity",
+            format!("{} to {} ({remaining})", info.not_before, info.not_after),
+        );
+        let key = match info.key_size {
+            Some(size) => format!("{} {size} bits", info.key_type),
+            None => info.key_type.clone(),
+        };
+        push("Key", key);
+        push("SHA-256", info.fingerprint_sha256.clone());
+        let in_crl = match info.in_crl {
+            Some(true) => "listed",
+            Some(false) => "not listed",
+            None => "no CRL found",
+        };
+        push("CRL", in_crl.into());
+        push(
+            "CCD file",
+            if info.has_ccd_file { "yes" } else { "no" }.into(),
+        );
+
+        // the other certificates are listed in full in the text format
+        let others = user_records
+            .iter()
+            .find(|(username, _)| username.as_str() == info.username)
+            .map(|(_, records)| records.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|r| r.serial != info.serial);
+        for IndexRecord { status, expiry, revocation, serial, .. } in others {
+            let mut line = format!("  Other: {serial} | Status: {status} | Expiry: {expiry}");
+            if let Some(Revocation { date, reason }) = revocation {
+                line.push_str(&format!(" | Revoked: {date}"));
+                if let Some(reason) = reason {
+                    line.push_str(&format!(" ({reason})"));
+                }
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    })
+}
+
+/// Show every certificate ever issued to a user, as recorded in the PKI database.
+///
+/// If `at` is specified, only the certificates valid at that time are shown.
+pub fn user_history(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    username: &Username,
+    at: Option<DateTime<Utc>>,
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
     // allow `easy_rsa_pki_dir` to be relative to the config file
     let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
 
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
-    for username in usernames {
-        cmd!(sh, "{easy_rsa} --pki-dir={pki_dir} show-cert {username}")
-            .run_interactive()
-            .wrap_err("User creation command failed to execute")?;
+    let current_serial = get_current_records(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?
+        .remove(username)
+        .map(|r| r.serial);
+    // the PKI database is append-only, so it is already in order of issuance
+    let records = get_index_records(config_dir, profile)?
+        .into_iter()
+        .filter(|r| r.common_name() == Some(username.as_str()))
+        .map(|r| {
+            let is_current = current_serial.as_ref() == Some(&r.serial);
+            HistoryRecord::new(&pki_dir, profile, &r, is_current)
+        })
+        .collect_vec();
+    if records.is_empty() {
+        bail!(r#"User "{username}" has never had a certificate in profile "{profile_name}""#);
     }
+    let records = records
+        .into_iter()
+        .filter(|r| at.is_none_or(|at| r.was_valid_at(at)))
+        .collect_vec();
 
-    Ok(())
+    print_records(&records, format, |r| {
+        let not_before = r
+            .not_before
+            .map_or_else(|| "unknown".into(), |t| t.to_string());
+        let mut line = format!(
+            "{}: {} | Valid: {not_before} to {}",
+            r.serial, r.status, r.not_after
+        );
+        if let Some(revoked_at) = r.revoked_at {
+            line.push_str(&format!(" | Revoked: {revoked_at}"));
+            if let Some(reason) = r.revocation_reason {
+                line.push_str(&format!(" ({reason})"));
+            }
+        }
+        if r.is_current {
+            line.push_str(" (current)");
+        }
+        line
+    })
 }
 
+/// Print certificates of users whose metadata matches the filter, showing only
+/// their usernames in the text format.
+fn print_user_records<'a>(
+    config_dir: &Path,
+    profile: &Profile,
+    records: impl IntoIterator<Item = &'a IndexRecord>,
+    filter: &MetadataFilter,
+    format: OutputFormat,
+) -> color_eyre::Result<()> {
+    let metadata_store = MetadataStore::load(config_dir, profile)?;
+    let get_metadata = |record: &IndexRecord| {
+        record
+            .common_name()
+            .and_then(|name| name.parse::<Username>().ok())
+            .and_then(|username| metadata_store.get(&username))
+    };
+    let records = records
+        .into_iter()
+        .filter(|r| filter.matches(get_metadata(r)))
+        .collect_vec();
+
+    // no need to read the certificates for the text format
+    if format == OutputFormat::Text {
+        let output = records
+            .into_iter()
+            .filter_map(IndexRecord::common_name)
+            .join("\n");
+        println!("{output}");
+        return Ok(());
+    }
+
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+
+    let records = records
+        .into_iter()
+        .map(|r| UserRecord::new(&pki_dir, profile, r, get_metadata(r)))
+        .collect_vec();
+    print_records(&records, format, |r| r.username.clone())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn new_user(
     config_dir: impl AsRef<Path>,
     config: &Config,
     profile: &Profile,
     usernames: &[Username],
     days: Option<usize>,
-    force: bool,
-) -> color_eyre::Result<()> {
+    metadata: &MetadataChanges,
+    passphrase: Option<&PassphraseSource>,
+    passphrase_output: &mut PassphraseOutput,
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<BatchOutcome> {
     let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
+    let mut passphrases = profile
+        .passphrase_source(passphrase)?
+        .map(|source| PassphraseReader::for_new_keys(source, opts.dry_run));
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+    let backend = get_backend(config_dir, config, profile, opts);
+    let audit = AuditLog::new(config_dir, profile, opts);
+    let mut batch = Batch::with_rollback(config_dir, profile, opts);
 
     // sanity check
-    let known_users = get_users(config_dir, profile)
+    let known_users = backend
+        .users()
         .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
     for username in usernames {
         if known_users.contains(username) {
-            bail!(r#"User "{username}" already exists in profile "{profile_name}""#);
+            batch.skip(
+                username,
+                eyre!(r#"User "{username}" already exists in profile "{profile_name}""#),
+            )?;
         }
     }
+    let usernames = batch.remaining(usernames);
+    // restore the PKI if anything below fails
+    let rollback = Rollback::begin(config_dir, profile, &usernames, opts)?;
 
-    let easy_rsa = &config.easy_rsa_path;
-    let force_arg = force.then_some("--batch");
-    // allow `easy_rsa_pki_dir` to be relative to the config file
-    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
-    let days_arg = days.or(profile.default_days).map(|d| format!("--days={d}"));
-    let days_arg = days_arg.as_ref(); // otherwise use of moved value
+    // allocate static addresses before issuing anything
+    let mut allocations = BTreeMap::new();
+    if let Some(ref subnet) = profile.ccd_subnet {
+        let ccd_dir = get_ccd_dir(config_dir, profile)?;
+        let mut assigned = get_assigned_addresses(&ccd_dir)?;
+        for &username in &usernames {
+            let address = allocate_address(subnet, &assigned)?;
+            assigned.insert(address, username.to_string());
+            allocations.insert(username, (CcdFile::load(&ccd_dir, username)?, address));
+        }
+    }
 
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
     for username in usernames {
-        cmd!(
-            sh,
-            "{easy_rsa} {force_arg...} --pki-dir={pki_dir} --no-pass {days_arg...} build-client-full {username}"
+        batch.run(username, || {
+            let passphrase = passphrases
+                .as_mut()
+                .map(|passphrases| passphrases.next(username))
+                .transpose()?;
+            backend.build_client(username, days, passphrase.as_deref())?;
+            audit.record(
+                AuditOperation::Issue,
+                Some(username),
+                audit.current_serial(username)?,
+                days.or(profile.default_days),
+            )?;
+
+            // reloaded for each user, as a failed user's changes may have been rolled back
+            let mut metadata_store = MetadataStore::load(config_dir, profile)?;
+            // metadata left over from a removed user of the same name is replaced
+            let user_metadata = metadata_store.get_mut(username);
+            if !metadata.is_empty() || *user_metadata != UserMetadata::default() {
+                *user_metadata = UserMetadata::default();
+                metadata.apply(user_metadata);
+                save_metadata(&metadata_store, opts.dry_run)?;
+            }
+
+            if let (Some((ccd, address)), Some(subnet)) =
+                (allocations.get_mut(username), profile.ccd_subnet)
+            {
+                ccd.set_ifconfig_push(*address, subnet.netmask());
+                ccd.save(opts.dry_run)?;
+                info!(r#"Assigned {address} to user "{username}""#);
+            }
+
+            let generated = passphrases
+                .as_mut()
+                .and_then(|passphrases| passphrases.take_generated(username));
+            passphrase_output.deliver(username, generated)
+        })?;
+    }
+
+    rollback.commit();
+    batch.finish(format)
+}
+
+/// A row of a CSV file of users to import.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ImportRow {
+    username: String,
+    #[serde(default)]
+    days: Option<usize>,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    note: Option<String>,
+    /// Separated by commas.
+    #[serde(default)]
+    tags: Option<String>,
+}
+
+/// A validated row of a CSV file of users to import.
+#[derive(Clone, Debug)]
+pub struct ImportEntry {
+    /// The line of the row in the file.
+    line: u64,
+    pub username: Username,
+    row: ImportRow,
+}
+
+/// Read and validate every row of a CSV file of users to import.
+pub fn read_import_file(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    file: impl AsRef<Path>,
+) -> color_eyre::Result<Vec<ImportEntry>> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let file = file.as_ref();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(file)
+        .wrap_err_with(|| format!("Failed to open {file:?}"))?;
+    let headers = reader
+        .headers()
+        .wrap_err_with(|| format!("Failed to read the header row of {file:?}"))?
+        .clone();
+    let known_users = get_users(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    let mut entries: Vec<ImportEntry> = vec![];
+    let mut problems = vec![];
+    for (i, record) in reader.records().enumerate() {
+        let line = match record {
+            Ok(ref record) => record.position().map_or(i as u64 + 2, |p| p.line()),
+            Err(ref err) => err.position().map_or(i as u64 + 2, |p| p.line()),
+        };
+        let row = record
+            .and_then(|record| record.deserialize::<ImportRow>(Some(&headers)))
+            .wrap_err("Malformed row")
+            .and_then(|row| {
+                let username = profile.normalise_username(&row.username.parse()?)?;
+                Ok((username, row))
+            });
+        match row {
+            Ok((username, _)) if known_users.contains(&username) => problems.push(format!(
+                r#"line {line}: User "{username}" already exists in profile "{profile_name}""#
+            )),
+            Ok((username, _)) if entries.iter().any(|e| e.username == username) => {
+                problems.push(format!(r#"line {line}: User "{username}" is listed twice"#))
+            }
+            Ok((username, row)) => entries.push(ImportEntry { line, username, row }),
+            Err(err) => problems.push(format!("line {line}: {err:#}")),
+        }
+    }
+    if !problems.is_empty() {
+        bail!(
+            "{} rows of {file:?} are invalid, so no users were imported:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+    Ok(entries)
+}
+
+/// Issue certificates for the users read by [`read_import_file`], and optionally
+/// package them.
+///
+/// A failing row does not stop the remaining rows from being processed; a
+/// summary of all rows is printed instead.
+///
+/// Returns the outcome of every row, so that the caller can fail after acting
+/// on the imported users.
+#[allow(clippy::too_many_arguments)]
+pub fn import_users(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    entries: Vec<ImportEntry>,
+    output_dir: Option<&Path>,
+    passphrase_output: &mut PassphraseOutput,
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<Vec<ImportRecord>> {
+    let config_dir = config_dir.as_ref();
+    // rows are summarised below instead
+    let opts = ExecOptions { keep_going: false, ..opts };
+
+    // process every row, even if earlier ones failed
+    let mut records = vec![];
+    for ImportEntry { line, username, row } in entries {
+        let metadata = MetadataChanges {
+            display_name: row.display_name,
+            email: row.email,
+            note: row.note,
+            add_tags: (row.tags.iter())
+                .flat_map(|tags| tags.split(','))
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            remove_tags: vec![],
+        };
+        let usernames = std::slice::from_ref(&username);
+
+        let mut record = ImportRecord {
+            line,
+            username: username.clone(),
+            issued: false,
+            package_path: None,
+            error: None,
+        };
+        if let Err(err) = new_user(
+            config_dir,
+            config,
+            profile,
+            usernames,
+            row.days,
+            &metadata,
+            None,
+            passphrase_output,
+            opts,
+            format,
         )
-        .run_interactive()
-        .wrap_err("User creation command failed to execute")?;
+        .and_then(BatchOutcome::into_result)
+        {
+            record.error = Some(format!("{err:#}"));
+            records.push(record);
+            continue;
+        }
+        record.issued = true;
+
+        if let Some(output_dir) = output_dir {
+            match package(
+                config_dir,
+                profile,
+                usernames,
+                false,
+                output_dir,
+                false,
+                None,
+                None,
+                passphrase_output,
+                opts,
+                format,
+            )
+            .and_then(|(paths, outcome)| outcome.into_result().map(|_| paths))
+            {
+                Ok(mut paths) => record.package_path = paths.remove(&username),
+                Err(err) => record.error = Some(format!("{err:#}")),
+            }
+        }
+        records.push(record);
     }
 
-    Ok(())
+    print_records(&records, format, |r| {
+        let ImportRecord { line, username, .. } = r;
+        match (r.issued, &r.package_path, &r.error) {
+            (false, _, Some(err)) => format!("{username} (line {line}): failed: {err}"),
+            (true, _, Some(err)) => {
+                format!("{username} (line {line}): issued, but failed to package: {err}")
+            }
+            (true, Some(path), None) => {
+                format!("{username} (line {line}): issued, packaged at {path:?}")
+            }
+            (_, _, None) => format!("{username} (line {line}): issued"),
+        }
+    })?;
+    Ok(records)
 }
 
-pub fn renew_user(
+pub fn edit_user(
     config_dir: impl AsRef<Path>,
     config: &Config,
     profile: &Profile,
     usernames: &[Username],
-    days: Option<usize>,
-    keep_old: bool,
-    force: bool,
+    changes: &MetadataChanges,
+    opts: ExecOptions,
 ) -> color_eyre::Result<()> {
     let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
 
-    let known_users = get_users(config_dir, profile)
+    // sanity checks
+    if changes.is_empty() {
+        bail!("No changes specified");
+    }
+    // removed users can be edited too, since their metadata is kept
+    let records = get_index_records(config_dir, profile)
         .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
     for username in usernames {
-        if !known_users.contains(username) {
+        if !records
+            .iter()
+            .any(|r| r.common_name() == Some(username.as_str()))
+        {
             bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
         }
     }
 
-    let easy_rsa = &config.easy_rsa_path;
-    let force_arg = force.then_some("--batch");
-    // allow `easy_rsa_pki_dir` to be relative to the config file
-    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
-    let days_arg = days.or(profile.default_days).map(|d| format!("--days={d}"));
-    let days_arg = days_arg.as_ref(); // otherwise use of moved value
-
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
+    let mut metadata_store = MetadataStore::load(config_dir, profile)?;
     for username in usernames {
-        cmd!(
-            sh,
-            "{easy_rsa} {force_arg...} --pki-dir={pki_dir} {days_arg...} renew {username}"
-        )
-        .run_interactive()
-        .wrap_err("User renewal command failed to execute")?;
+        changes.apply(metadata_store.get_mut(username));
+    }
+    save_metadata(&metadata_store, opts.dry_run)
+}
 
-        if !keep_old {
-            cmd!(
-                sh,
-                "{easy_rsa} {force_arg...} --pki-dir={pki_dir} revoke-renewed {username}"
-            )
-            .run_interactive()
-            .wrap_err("User revoke renewed command failed to execute")?;
+fn save_metadata(store: &MetadataStore, dry_run: bool) -> color_eyre::Result<()> {
+    if dry_run {
+        let path = store.path();
+        print_dry_run(format!(
+            "write metadata file {path:?}:\n{}",
+            store.content()?
+        ));
+        return Ok(());
+    }
+    store.save()
+}
 
-            regenerate_crl(config_dir, config, profile, force)?;
+#[allow(clippy::too_many_arguments)]
+pub fn renew_user(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    usernames: &[Username],
+    days: Option<usize>,
+    keep_old: bool,
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<BatchOutcome> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+    let backend = get_backend(config_dir, config, profile, opts);
+    let audit = AuditLog::new(config_dir, profile, opts);
+    let mut batch = Batch::with_rollback(config_dir, profile, opts);
+
+    let known_users = backend
+        .users()
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    for username in usernames {
+        if !known_users.contains(username) {
+            batch.skip(
+                username,
+                eyre!(r#"User "{username}" does not exist in profile "{profile_name}""#),
+            )?;
         }
     }
 
-    Ok(())
+    let usernames = batch.remaining(usernames);
+    let rollback = Rollback::begin(config_dir, profile, &usernames, opts)?;
+    for username in usernames {
+        batch.run(username, || {
+            backend.renew(username, days)?;
+            audit.record(
+                AuditOperation::Renew,
+                Some(username),
+                audit.current_serial(username)?,
+                days.or(profile.default_days),
+            )?;
+
+            if !keep_old {
+                backend.revoke_renewed(username)?;
+                backend.gen_crl()?;
+                audit.record(AuditOperation::GenCrl, None, None, None)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    rollback.commit();
+    batch.finish(format)
 }
 
 pub fn remove_user(
@@ -229,48 +1199,266 @@ pub fn remove_user(
     config: &Config,
     profile: &Profile,
     usernames: &[Username],
-    force: bool,
-) -> color_eyre::Result<()> {
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<BatchOutcome> {
     let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+    let backend = get_backend(config_dir, config, profile, opts);
+    let audit = AuditLog::new(config_dir, profile, opts);
+    let ccd_dir = profile
+        .ccd_dir
+        .is_some()
+        .then(|| get_ccd_dir(config_dir, profile))
+        .transpose()?;
+    let mut batch = Batch::with_rollback(config_dir, profile, opts);
 
-    let known_users = get_users(config_dir, profile)
+    let known_users = backend
+        .users()
         .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
     for username in usernames {
         if !known_users.contains(username) {
-            bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
+            batch.skip(
+                username,
+                eyre!(r#"User "{username}" does not exist in profile "{profile_name}""#),
+            )?;
         }
     }
 
-    let easy_rsa = &config.easy_rsa_path;
-    let force_arg = force.then_some("--batch");
-    // allow `easy_rsa_pki_dir` to be relative to the config file
-    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+    let usernames = batch.remaining(usernames);
+    let rollback = Rollback::begin(config_dir, profile, &usernames, opts)?;
+    for username in usernames {
+        let revoked = batch.run(username, || {
+            let serial = audit.current_serial(username)?;
+            backend.revoke(username)?;
+            audit.record(AuditOperation::Revoke, Some(username), serial, None)
+        })?;
+        if revoked.is_none() {
+            continue;
+        }
 
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        // clean up client-specific configs; a leftover one does not undo the revocation
+        let Some(ref ccd_dir) = ccd_dir else {
+            continue;
+        };
+        let path = ccd_dir.join(username);
+        if !path.is_file() {
+            continue;
+        }
+        if opts.dry_run {
+            print_dry_run(format!("remove CCD file {path:?}"));
+            continue;
+        }
+        if let Err(err) = fs::remove_file(&path) {
+            warn!(r#"Revoked user "{username}", but failed to remove CCD file {path:?}: {err}"#);
+        }
+    }
+
+    // every user that succeeded was revoked
+    let removed = batch.succeeded();
+    if !removed.is_empty() {
+        backend.gen_crl()?;
+        audit.record(AuditOperation::GenCrl, None, None, None)?;
+    }
+    rollback.commit();
+
+    if !removed.is_empty() {
+        // revoked clients stay connected until renegotiation otherwise
+        if let Some(ref management) = profile.management {
+            if let Err(err) = disconnect(config_dir, management, &removed, opts) {
+                warn!("Failed to disconnect removed users; they stay connected until their next renegotiation: {err:?}");
+            }
+        }
+    }
+
+    batch.finish(format)
+}
+
+pub fn kick_user(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    usernames: &[Username],
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+
+    // sanity check
+    let Some(ref management) = profile.management else {
+        bail!(r#"Profile "{profile_name}" does not contain a "management" section"#);
+    };
+
+    disconnect(config_dir, management, usernames, opts)
+}
+
+/// Disconnect users via the management interface.
+fn disconnect(
+    config_dir: &Path,
+    management: &Management,
+    usernames: &[Username],
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    if opts.dry_run {
+        for username in usernames {
+            print_dry_run(format!(
+                r#"disconnect user "{username}" via the management interface"#
+            ));
+        }
+        return Ok(());
+    }
+
+    let mut client = ManagementClient::connect(config_dir, management)
+        .wrap_err("Failed to connect to the management interface")?;
     for username in usernames {
-        cmd!(
-            sh,
-            "{easy_rsa} {force_arg...} --pki-dir={pki_dir} revoke {username}"
-        )
-        .run_interactive()
-        .wrap_err("User deletion command failed to execute")?;
+        let was_connected = client
+            .kill(username)
+            .wrap_err_with(|| format!(r#"Failed to disconnect user "{username}""#))?;
+        if was_connected {
+            info!(r#"Disconnected user "{username}""#);
+        } else {
+            info!(r#"User "{username}" is not connected"#);
+        }
+    }
+    Ok(())
+}
+
+pub fn ccd_set_ip(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    username: &Username,
+    address: Ipv4Addr,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+
+    // sanity checks
+    let Some(ref subnet) = profile.ccd_subnet else {
+        bail!(r#"Profile "{profile_name}" does not have a "ccd-subnet" set"#);
+    };
+    let ccd_dir = get_ccd_dir(config_dir, profile)?;
+    ensure_user_exists(config_dir, profile, username)?;
+    let assigned = get_assigned_addresses(&ccd_dir)?;
+    check_address(subnet, address, username, &assigned)?;
+
+    let mut ccd = CcdFile::load(&ccd_dir, username)?;
+    ccd.set_ifconfig_push(address, subnet.netmask());
+    ccd.save(opts.dry_run)
+}
+
+pub fn ccd_add_route(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    username: &Username,
+    network: Ipv4Net,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let network = network.trunc();
+    let directive = format!(
+        r#"push "route {} {}""#,
+        network.network(),
+        network.netmask()
+    );
+    ccd_add_directive(config_dir, config, profile, username, directive, opts)
+}
+
+pub fn ccd_push(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    username: &Username,
+    option: &str,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    if option.contains('"') {
+        bail!("Pushed options cannot contain double quotes");
     }
+    let directive = format!(r#"push "{}""#, option.trim());
+    ccd_add_directive(config_dir, config, profile, username, directive, opts)
+}
 
-    regenerate_crl(config_dir, config, profile, force)?;
+pub fn ccd_show(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    username: &Username,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
 
+    let ccd_dir = get_ccd_dir(config_dir, profile)?;
+    let ccd = CcdFile::load(&ccd_dir, username)?;
+    if !ccd.path().is_file() {
+        bail!(r#"User "{username}" does not have a CCD file"#);
+    }
+    print!("{}", ccd.content());
     Ok(())
 }
 
+/// Append a directive to the CCD file of a user.
+fn ccd_add_directive(
+    config_dir: impl AsRef<Path>,
+    config: &Config,
+    profile: &Profile,
+    username: &Username,
+    directive: String,
+    opts: ExecOptions,
+) -> color_eyre::Result<()> {
+    let config_dir = config_dir.as_ref();
+    let _lock = PkiLock::acquire(config_dir, config, profile, opts)?;
+
+    // sanity checks
+    let ccd_dir = get_ccd_dir(config_dir, profile)?;
+    ensure_user_exists(config_dir, profile, username)?;
+
+    let mut ccd = CcdFile::load(&ccd_dir, username)?;
+    if !ccd.add_directive(directive) {
+        info!(r#"The CCD file of user "{username}" already contains this directive"#);
+        return Ok(());
+    }
+    ccd.save(opts.dry_run)
+}
+
+/// Get the client-config-dir of a profile.
+fn get_ccd_dir(config_dir: &Path, profile: &Profile) -> color_eyre::Result<PathBuf> {
+    let profile_name = &profile.name;
+    let Some(ref ccd_dir) = profile.ccd_dir else {
+        bail!(r#"Profile "{profile_name}" does not have a "ccd-dir" set"#);
+    };
+    // allow `ccd_dir` to be relative to the config file
+    Ok(config_dir.join(ccd_dir))
+}
+
+fn ensure_user_exists(
+    config_dir: &Path,
+    profile: &Profile,
+    username: &Username,
+) -> color_eyre::Result<()> {
+    let profile_name = &profile.name;
+    let known_users = get_users(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    if !known_users.contains(username) {
+        bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn package(
     config_dir: impl AsRef<Path>,
     profile: &Profile,
     usernames: &[Username],
     add_prefix: bool,
     output_dir: impl AsRef<Path>,
-    force: bool,
     keep_temp: bool,
-) -> color_eyre::Result<()> {
+    passphrase: Option<&PassphraseSource>,
+    current_passphrase: Option<&PassphraseSource>,
+    passphrase_output: &mut PassphraseOutput,
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<(BTreeMap<Username, PathBuf>, BatchOutcome)> {
     const COPY_DIR_DEFAULT_OPTS: DirectoryCopyOptions = DirectoryCopyOptions {
         destination_directory_rule: DestinationDirectoryRule::AllowEmpty,
         copy_depth_limit: DirectoryCopyDepthLimit::Limited { maximum_depth: 64 },
@@ -278,25 +1466,103 @@ pub fn package(
         broken_symlink_behaviour: BrokenSymlinkBehaviour::Abort,
     };
 
+    let ExecOptions { force, dry_run, .. } = opts;
     let config_dir = config_dir.as_ref();
     let profile_name = &profile.name;
     let output_dir = output_dir.as_ref();
+    let audit = AuditLog::new(config_dir, profile, opts);
+    let mut batch = Batch::new(opts);
 
     // sanity checks
     let Some(ref packaging) = profile.packaging else {
         bail!(r#"Profile "{profile_name}" does not contain a "packaging" section"#);
     };
 
-    let known_users = get_users(config_dir, profile)
+    let current_records = get_current_records(config_dir, profile)
         .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    let mut user_vars = vec![];
     for username in usernames {
-        if !known_users.contains(username) {
-            bail!(r#"User "{username}" does not exist in profile "{profile_name}""#);
+        let Some(record) = current_records.get(username) else {
+            batch.skip(
+                username,
+                eyre!(r#"User "{username}" does not exist in profile "{profile_name}""#),
+            )?;
+            continue;
+        };
+        match TemplateVars::for_user(profile, username, record) {
+            Ok(vars) => user_vars.push((username, vars)),
+            Err(err) => batch.skip(username, err)?,
         }
     }
 
-    // allow `skel_dir` to be relative to the config file
-    let skel_dir = config_dir.join(&packaging.skel_dir);
+    let archive_needs_content = packaging.skel_dir.is_some()
+        || packaging.cert_subpath.is_some()
+        || packaging.key_subpath.is_some()
+        || packaging.p12_subpath.is_some()
+        || packaging.unified_profile.is_some();
+    match packaging.mode {
+        PackagingMode::Archive if !archive_needs_content => {
+            bail!(r#"The "packaging" section of profile "{profile_name}" produces empty archives"#)
+        }
+        PackagingMode::Ovpn if packaging.unified_profile.is_none() => bail!(
+            r#"The "packaging" section of profile "{profile_name}" is in "ovpn" mode, but does not contain a "unified-profile" section"#
+        ),
+        _ => (),
+    }
+    let has_key = packaging.key_subpath.is_some()
+        || packaging.p12_subpath.is_some()
+        || packaging.unified_profile.is_some();
+    if !has_key && passphrase.is_some() {
+        bail!(r#"The "packaging" section of profile "{profile_name}" does not include keys"#)
+    }
+    let mut passphrases =
+        PackagePassphrases::new(profile, passphrase, current_passphrase, dry_run)?;
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let ca_path = config_dir.join(&profile.easy_rsa_pki_dir).join("ca.crt");
+
+    let mut output_paths = BTreeMap::new();
+
+    // write unified profiles directly
+    if packaging.mode == PackagingMode::Ovpn {
+        let unified_profile = packaging.unified_profile.as_ref().unwrap(); // checked above
+        for (username, vars) in &user_vars {
+            batch.run(username, || {
+                let key = read_packaged_key(config_dir, profile, username, &mut passphrases)?;
+                let rendered = render_unified_profile(
+                    config_dir,
+                    profile,
+                    unified_profile,
+                    username,
+                    &key.pem,
+                    vars,
+                )
+                .wrap_err_with(|| {
+                    format!(r#"Failed to render unified profile for user "{username}""#)
+                })?;
+
+                let file_name = if add_prefix {
+                    format!("{profile_name}-{username}.ovpn")
+                } else {
+                    format!("{username}.ovpn")
+                };
+                let output_path = output_dir.join(&file_name);
+                if dry_run {
+                    print_dry_run(format!("write unified profile to {output_path:?}"));
+                } else {
+                    // the unified profile inlines the key
+                    let mut file = create_secret_output_file(&output_path, force)?;
+                    file.write_all(rendered.as_bytes())
+                        .wrap_err_with(|| format!(r#"Failed while writing into "{file_name}""#))?;
+                }
+                output_paths.insert((*username).clone(), output_path);
+                let serial = audit.current_serial(username)?;
+                audit.record(AuditOperation::Package, Some(username), serial, None)?;
+                passphrase_output.deliver(username, passphrases.take_generated(username))
+            })?;
+        }
+        let outcome = batch.finish(format)?;
+        return Ok((output_paths, outcome));
+    }
 
     // create temporary directory
     let temp_dir = TempDir::with_prefix("openvpn-cred-management-")
@@ -309,17 +1575,25 @@ pub fn package(
 
     // copy skeleton directory
     let mapped_skel_dir = temp_dir_path.join("mapped-skel");
-    copy_directory(&skel_dir, &mapped_skel_dir, COPY_DIR_DEFAULT_OPTS).wrap_err_with(|| {
-        format!("Failed to copy skeleton directory {skel_dir:?} to {mapped_skel_dir:?}")
-    })?;
+    match packaging.skel_dir {
+        Some(ref skel_dir) => {
+            // allow `skel_dir` to be relative to the config file
+            let skel_dir = config_dir.join(skel_dir);
+            copy_directory(&skel_dir, &mapped_skel_dir, COPY_DIR_DEFAULT_OPTS).wrap_err_with(
+                || format!("Failed to copy skeleton directory {skel_dir:?} to {mapped_skel_dir:?}"),
+            )?;
+        }
+        None => fs::create_dir_all(&mapped_skel_dir).wrap_err_with(|| {
+            format!("Failed to create empty skeleton directory {mapped_skel_dir:?}")
+        })?,
+    }
 
     // apply transforms
     let sh = Shell::new()
         .wrap_err("Failed to create subshell")?
         .with_current_dir(&mapped_skel_dir);
     for script in &packaging.skel_map_scripts {
-        cmd!(sh, "bash -c {script}")
-            .run_interactive()
+        run_cmd(cmd!(sh, "bash -c {script}"), dry_run)
             .wrap_err("A skeleton transform script failed to execute")?;
     }
     drop(sh);
@@ -331,67 +1605,273 @@ pub fn package(
     })?;
 
     // package for each user
-    for username in usernames {
-        // copy skeleton directory
-        let pkg_dir = pkg_parent_dir.join(username);
-        copy_directory(&mapped_skel_dir, &pkg_dir, COPY_DIR_DEFAULT_OPTS).wrap_err_with(|| {
-            format!(
-                "Failed to copy transformed skeleton directory {mapped_skel_dir:?} to {pkg_dir:?}"
-            )
-        })?;
+    for (username, vars) in &user_vars {
+        batch.run(username, || {
+            // copy skeleton directory
+            let pkg_dir = pkg_parent_dir.join(username);
+            copy_directory(&mapped_skel_dir, &pkg_dir, COPY_DIR_DEFAULT_OPTS).wrap_err_with(|| {
+                format!(
+                    "Failed to copy transformed skeleton directory {mapped_skel_dir:?} to {pkg_dir:?}"
+                )
+            })?;
 
-        // create subdirectories for certificate and key
-        for subpath in [&packaging.cert_subpath, &packaging.key_subpath] {
-            match subpath.parent() {
-                Some(parent) if parent != Path::new("") => {
-                    let full_dir_path = pkg_dir.join(parent);
-                    fs::create_dir_all(&full_dir_path).wrap_err_with(|| {
-                        format!(
-                            "Failed to create parent path {full_dir_path:?} for certificate or key"
-                        )
-                    })?
+            // render templates
+            for subpath in &packaging.templates {
+                let template_path = pkg_dir.join(subpath);
+                let template = fs::read_to_string(&template_path)
+                    .wrap_err_with(|| format!("Failed to read template {template_path:?}"))?;
+                let rendered = vars.render(&template).wrap_err_with(|| {
+                    format!(r#"Failed to render template {subpath:?} for user "{username}""#)
+                })?;
+                fs::write(&template_path, rendered)
+                    .wrap_err_with(|| format!("Failed to write rendered template {template_path:?}"))?;
+            }
+
+            // copy certificate
+            if let Some(ref cert_subpath) = packaging.cert_subpath {
+                let cert_source_path =
+                    get_cert_path(config_dir, profile, username).wrap_err_with(|| {
+                        format!(r#"Failed to get certificate path for user "{username}" in profile "{profile_name}""#)
+                    })?;
+                let cert_target_path = pkg_dir.join(cert_subpath);
+                create_parent_dir(&cert_target_path)?;
+                if dry_run {
+                    print_dry_run(format!("copy {cert_source_path:?} to {cert_target_path:?}"));
                 }
-                Some(_) | None => (), // no intermediate directories to create
+                fs::copy(&cert_source_path, &cert_target_path).wrap_err_with(|| {
+                    format!(
+                        r#"Failed to copy certificate {cert_source_path:?} to {cert_target_path:?}"#
+                    )
+                })?;
+            }
+
+            // copy key, which is encrypted on the way if needed
+            let key = match has_key {
+                true => Some(read_packaged_key(config_dir, profile, username, &mut passphrases)?),
+                false => None,
+            };
+            if let (Some(ref key_subpath), Some(ref key)) = (&packaging.key_subpath, &key) {
+                let key_target_path = pkg_dir.join(key_subpath);
+                create_parent_dir(&key_target_path)?;
+                if dry_run {
+                    print_dry_run(format!(r#"copy key of user "{username}" to {key_target_path:?}"#));
+                }
+                create_secret_output_file(&key_target_path, true)?
+                    .write_all(key.pem.as_bytes())
+                    .wrap_err_with(|| format!("Failed to write key to {key_target_path:?}"))?;
             }
-        }
 
-        // copy certificate
-        let cert_source_path =
-            get_cert_path(config_dir, profile, username).wrap_err_with(|| {
+            // write PKCS#12 bundle
+            if let (Some(ref p12_subpath), Some(ref key)) = (&packaging.p12_subpath, &key) {
+                let cert_path = get_cert_path(config_dir, profile, username).wrap_err_with(|| {
+                    format!(r#"Failed to get certificate path for user "{username}" in profile "{profile_name}""#)
+                })?;
+                let p12_target_path = pkg_dir.join(p12_subpath);
+                create_parent_dir(&p12_target_path)?;
+                if dry_run {
+                    print_dry_run(format!(
+                        r#"write PKCS#12 bundle of user "{username}" to {p12_target_path:?}"#
+                    ));
+                } else {
+                    let p12 =
+                        build_p12(&cert_path, &ca_path, key, username, packaging.legacy_p12)?;
+                    create_secret_output_file(&p12_target_path, true)?
+                        .write_all(&p12)
+                        .wrap_err_with(|| {
+                            format!("Failed to write PKCS#12 bundle to {p12_target_path:?}")
+                        })?;
+                }
+            }
+
+            // write unified profile
+            if let (Some(ref unified_profile), Some(ref key)) = (&packaging.unified_profile, &key) {
+                let rendered = render_unified_profile(
+                    config_dir,
+                    profile,
+                    unified_profile,
+                    username,
+                    &key.pem,
+                    vars,
+                )
+                .wrap_err_with(|| {
+                    format!(r#"Failed to render unified profile for user "{username}""#)
+                })?;
+                let profile_target_path = match unified_profile.subpath {
+                    Some(ref subpath) => pkg_dir.join(subpath),
+                    None => pkg_dir.join(format!("{username}.ovpn")),
+                };
+                create_parent_dir(&profile_target_path)?;
+                fs::write(&profile_target_path, rendered).wrap_err_with(|| {
+                    format!("Failed to write unified profile to {profile_target_path:?}")
+                })?;
+            }
+
+            // write archive
+            let archive_name = if add_prefix {
+                format!("{profile_name}-{username}.zip")
+            } else {
+                format!("{username}.zip")
+            };
+            let output_path = output_dir.join(&archive_name);
+            if dry_run {
+                print_dry_run(format!("write archive of {pkg_dir:?} to {output_path:?}"));
+            } else {
+                let zip_file = create_secret_output_file(&output_path, force)?;
+                let zip_writer = ZipWriter::new(zip_file);
+                zip_writer
+                    .create_from_directory(&pkg_dir)
+                    .wrap_err_with(|| format!(r#"Failed while writing into "{archive_name}""#))?;
+            }
+            output_paths.insert((*username).clone(), output_path);
+            let serial = audit.current_serial(username)?;
+            audit.record(AuditOperation::Package, Some(username), serial, None)?;
+            passphrase_output.deliver(username, passphrases.take_generated(username))
+        })?;
+    }
+
+    let outcome = batch.finish(format)?;
+    Ok((output_paths, outcome))
+}
+
+/// Export the credentials of users as a single file each.
+///
+/// Returns the paths of the exported files, and which users succeeded.
+#[allow(clippy::too_many_arguments)]
+pub fn export_user(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    usernames: &[Username],
+    export_format: ExportFormat,
+    legacy: bool,
+    add_prefix: bool,
+    output_dir: impl AsRef<Path>,
+    passphrase: Option<&PassphraseSource>,
+    current_passphrase: Option<&PassphraseSource>,
+    passphrase_output: &mut PassphraseOutput,
+    opts: ExecOptions,
+    format: OutputFormat,
+) -> color_eyre::Result<(BTreeMap<Username, PathBuf>, BatchOutcome)> {
+    let ExecOptions { force, dry_run, .. } = opts;
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    let output_dir = output_dir.as_ref();
+    let audit = AuditLog::new(config_dir, profile, opts);
+    let mut batch = Batch::new(opts);
+
+    // sanity checks
+    let unified_profile = profile
+        .packaging
+        .as_ref()
+        .and_then(|packaging| packaging.unified_profile.as_ref());
+    if export_format == ExportFormat::Ovpn && unified_profile.is_none() {
+        bail!(
+            r#"The "packaging" section of profile "{profile_name}" does not contain a "unified-profile" section"#
+        );
+    }
+    if legacy && export_format != ExportFormat::P12 {
+        bail!("`--legacy` only applies to PKCS#12 exports");
+    }
+    let legacy = legacy || profile.packaging.as_ref().is_some_and(|p| p.legacy_p12);
+    let current_records = get_current_records(config_dir, profile)
+        .wrap_err_with(|| format!(r#"Cannot get users of "{profile_name}" profile"#))?;
+    let mut passphrases =
+        PackagePassphrases::new(profile, passphrase, current_passphrase, dry_run)?;
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let ca_path = config_dir.join(&profile.easy_rsa_pki_dir).join("ca.crt");
+
+    let mut output_paths = BTreeMap::new();
+    for username in usernames {
+        let Some(record) = current_records.get(username) else {
+            batch.skip(
+                username,
+                eyre!(r#"User "{username}" does not exist in profile "{profile_name}""#),
+            )?;
+            continue;
+        };
+        batch.run(username, || {
+            let cert_path = get_cert_path(config_dir, profile, username).wrap_err_with(|| {
                 format!(r#"Failed to get certificate path for user "{username}" in profile "{profile_name}""#)
             })?;
-        let cert_target_path = pkg_dir.join(&packaging.cert_subpath);
-        fs::copy(&cert_source_path, &cert_target_path).wrap_err_with(|| {
-            format!(r#"Failed to copy certificate {cert_source_path:?} to {cert_target_path:?}"#)
-        })?;
+            let key = read_packaged_key(config_dir, profile, username, &mut passphrases)?;
+            let content = match (export_format, unified_profile) {
+                // the passphrase is only a placeholder in dry-run mode
+                (ExportFormat::P12, _) if dry_run => vec![],
+                (ExportFormat::P12, _) => build_p12(&cert_path, &ca_path, &key, username, legacy)?,
+                (ExportFormat::Pem, _) => render_pem_bundle(&cert_path, &ca_path, &key)?.into_bytes(),
+                (ExportFormat::Ovpn, Some(unified_profile)) => {
+                    let vars = TemplateVars::for_user(profile, username, record)?;
+                    render_unified_profile(config_dir, profile, unified_profile, username, &key.pem, &vars)
+                        .wrap_err_with(|| {
+                            format!(r#"Failed to render unified profile for user "{username}""#)
+                        })?
+                        .into_bytes()
+                }
+                (ExportFormat::Ovpn, None) => unreachable!(), // checked above
+            };
 
-        // copy key
-        let key_source_path = get_key_path(config_dir, profile, username).wrap_err_with(|| {
-            format!(r#"Failed to get key path for user "{username}" in profile "{profile_name}""#)
-        })?;
-        let key_target_path = pkg_dir.join(&packaging.key_subpath);
-        fs::copy(&key_source_path, &key_target_path).wrap_err_with(|| {
-            format!(r#"Failed to copy key {key_source_path:?} to {key_target_path:?}"#)
+            let file_name = if add_prefix {
+                format!("{profile_name}-{username}.{export_format}")
+            } else {
+                format!("{username}.{export_format}")
+            };
+            let output_path = output_dir.join(&file_name);
+            if dry_run {
+                print_dry_run(format!("write {export_format} export to {output_path:?}"));
+            } else {
+                create_secret_output_file(&output_path, force)?
+                    .write_all(&content)
+                    .wrap_err_with(|| format!(r#"Failed while writing into "{file_name}""#))?;
+            }
+            output_paths.insert(username.clone(), output_path);
+            let serial = audit.current_serial(username)?;
+            audit.record(AuditOperation::Export, Some(username), serial, None)?;
+            passphrase_output.deliver(username, passphrases.take_generated(username))
         })?;
+    }
 
-        // write archive
-        let archive_name = if add_prefix {
-            format!("{profile_name}-{username}.zip")
-        } else {
-            format!("{username}.zip")
-        };
-        let output_path = output_dir.join(&archive_name);
-        let zip_file = if force {
-            File::create(&output_path)
-        } else {
-            File::create_new(&output_path)
-        }
-        .wrap_err_with(|| format!(r#"Failed to create {output_path:?} for output"#))?;
-        let zip_writer = ZipWriter::new(zip_file);
-        zip_writer
-            .create_from_directory(&pkg_dir)
-            .wrap_err_with(|| format!(r#"Failed while writing into "{archive_name}""#))?;
+    let outcome = batch.finish(format)?;
+    Ok((output_paths, outcome))
+}
+
+/// Read the key of a user for packaging, encrypting it with a new passphrase if
+/// needed.
+fn read_packaged_key(
+    config_dir: &Path,
+    profile: &Profile,
+    username: &Username,
+    passphrases: &mut PackagePassphrases,
+) -> color_eyre::Result<PackagedKey> {
+    let profile_name = &profile.name;
+    let key_path = get_key_path(config_dir, profile, username).wrap_err_with(|| {
+        format!(r#"Failed to get key path for user "{username}" in profile "{profile_name}""#)
+    })?;
+    passphrases.read_key(&key_path, username)
+}
+
+/// Create a file for output, refusing to overwrite an existing file unless forced.
+///
+/// The file is only readable by its owner, since every output contains a key.
+fn create_secret_output_file(path: &Path, force: bool) -> color_eyre::Result<File> {
+    let mut options = File::options();
+    options.write(true).mode(0o600);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
     }
+    .open(path)
+    // the mode only applies to new files
+    .and_then(|file| {
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    })
+    .wrap_err_with(|| format!(r#"Failed to create {path:?} for output"#))
+}
 
-    Ok(())
+/// Create the intermediate directories of a path within a package.
+fn create_parent_dir(path: &Path) -> color_eyre::Result<()> {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create parent path {parent:?} for {path:?}")),
+        Some(_) | None => Ok(()), // no intermediate directories to create
+    }
 }
@@ -0,0 +1,161 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use xshell::{cmd, Shell};
+
+use crate::{
+    action::{shared::get_cert_path, ExecOptions},
+    config::Profile,
+    pki::read_cert,
+    shell::print_dry_run,
+    types::Username,
+};
+
+/// A credential operation recorded in the audit log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum AuditOperation {
+    Issue,
+    Renew,
+    Revoke,
+    Package,
+    Export,
+    GenCrl,
+    /// Changes to the PKI were undone after a failure.
+    Rollback,
+}
+
+/// A single entry in the audit log.
+///
+/// Operations on several users are recorded as one entry per user.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// The OS user that ran the operation.
+    pub os_user: Option<String>,
+    /// The OS user that invoked `sudo`, if any.
+    pub sudo_user: Option<String>,
+    pub profile: String,
+    pub operation: AuditOperation,
+    pub username: Option<String>,
+    /// The serial of the certificate operated on, as in the PKI database.
+    pub serial: Option<String>,
+    pub days: Option<usize>,
+    pub force: bool,
+}
+
+/// The audit log of a profile, as configured by `audit-log`.
+///
+/// If the profile does not have one, nothing is recorded.
+pub struct AuditLog<'a> {
+    config_dir: &'a Path,
+    profile: &'a Profile,
+    path: Option<PathBuf>,
+    opts: ExecOptions,
+}
+impl<'a> AuditLog<'a> {
+    pub fn new(config_dir: &'a Path, profile: &'a Profile, opts: ExecOptions) -> Self {
+        Self {
+            config_dir,
+            profile,
+            // allow `audit_log` to be relative to the config file
+            path: profile.audit_log.as_ref().map(|p| config_dir.join(p)),
+            opts,
+        }
+    }
+
+    /// Get the serial of the current certificate of a user, for recording.
+    ///
+    /// This is the certificate in `issued/`, which is not necessarily the one that
+    /// expires last. Nothing is issued in dry-run mode, so there is never a serial
+    /// to record.
+    pub fn current_serial(&self, username: &Username) -> color_eyre::Result<Option<String>> {
+        if self.path.is_none() || self.opts.dry_run {
+            return Ok(None);
+        }
+        let cert_path = get_cert_path(self.config_dir, self.profile, username)?;
+        Ok(Some(read_cert(cert_path)?.serial))
+    }
+
+    /// Append an entry for an operation that has been carried out.
+    pub fn record(
+        &self,
+        operation: AuditOperation,
+        username: Option<&Username>,
+        serial: Option<String>,
+        days: Option<usize>,
+    ) -> color_eyre::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            os_user: os_user(),
+            sudo_user: env::var("SUDO_USER").ok(),
+            profile: self.profile.name.clone(),
+            operation,
+            username: username.map(ToString::to_string),
+            serial,
+            days,
+            force: self.opts.force,
+        };
+        let line = serde_json::to_string(&entry).wrap_err("Failed to serialise audit entry")?;
+
+        if self.opts.dry_run {
+            print_dry_run(format!("append to audit log {path:?}: {line}"));
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{line}"))
+            .wrap_err_with(|| format!("Failed to write to audit log {path:?}"))
+    }
+}
+
+/// Get the name of the OS user running this process.
+pub fn os_user() -> Option<String> {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .ok()
+        .or_else(|| {
+            // not set in some environments, e.g. systemd services
+            let sh = Shell::new().ok()?;
+            cmd!(sh, "id -un").read().ok()
+        })
+}
+
+/// Read all entries of an audit log, oldest first.
+pub fn read_audit_log(path: impl AsRef<Path>) -> color_eyre::Result<Vec<AuditEntry>> {
+    let path = path.as_ref();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // nothing recorded yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Failed to read audit log {path:?}")),
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<AuditEntry>(line)
+                .wrap_err_with(|| format!("Failed to parse line {} of audit log {path:?}", i + 1))
+        })
+        .collect()
+}
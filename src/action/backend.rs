@@ -0,0 +1,59 @@
+mod easy_rsa;
+mod native;
+
+use std::path::Path;
+
+use crate::{
+    action::ExecOptions,
+    config::{BackendKind, Config, Profile},
+    types::Username,
+};
+
+pub use easy_rsa::EasyRsa;
+pub use native::Native;
+
+/// The certificate operations performed on a PKI.
+///
+/// All implementations operate on the on-disk layout of easy-rsa, so that
+/// they can be used interchangeably on the same PKI.
+pub trait Backend {
+    /// Get all users with an active certificate.
+    fn users(&self) -> color_eyre::Result<Vec<Username>>;
+
+    /// Issue a new key and client certificate for a user.
+    ///
+    /// If a passphrase is given, the key is encrypted with it.
+    fn build_client(
+        &self,
+        username: &Username,
+        days: Option<usize>,
+        passphrase: Option<&str>,
+    ) -> color_eyre::Result<()>;
+
+    /// Issue a new certificate for a user, keeping the old one as renewed.
+    ///
+    /// The existing key is reused, even if it is encrypted.
+    fn renew(&self, username: &Username, days: Option<usize>) -> color_eyre::Result<()>;
+
+    /// Revoke the certificate of a user superseded by the last renewal.
+    fn revoke_renewed(&self, username: &Username) -> color_eyre::Result<()>;
+
+    /// Revoke the current certificate of a user.
+    fn revoke(&self, username: &Username) -> color_eyre::Result<()>;
+
+    /// Regenerate the certificate revocation list.
+    fn gen_crl(&self) -> color_eyre::Result<()>;
+}
+
+/// Get the backend configured for a profile.
+pub fn get_backend<'a>(
+    config_dir: &'a Path,
+    config: &'a Config,
+    profile: &'a Profile,
+    opts: ExecOptions,
+) -> Box<dyn Backend + 'a> {
+    match profile.backend {
+        BackendKind::EasyRsa => Box::new(EasyRsa::new(config_dir, config, profile, opts)),
+        BackendKind::Native => Box::new(Native::new(config_dir, profile, opts)),
+    }
+}
@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use xshell::{cmd, Shell};
+
+use crate::{
+    action::{
+        backend::Backend,
+        passphrase::PASSOUT_VAR,
+        shared::{get_max_days, get_users},
+        ExecOptions,
+    },
+    config::{Config, Profile},
+    shell::run_cmd,
+    types::Username,
+};
+
+/// The backend that runs the easy-rsa executable.
+pub struct EasyRsa<'a> {
+    config_dir: &'a Path,
+    easy_rsa: &'a Path,
+    profile: &'a Profile,
+    pki_dir: PathBuf,
+    opts: ExecOptions,
+}
+impl<'a> EasyRsa<'a> {
+    pub fn new(
+        config_dir: &'a Path,
+        config: &'a Config,
+        profile: &'a Profile,
+        opts: ExecOptions,
+    ) -> Self {
+        Self {
+            config_dir,
+            easy_rsa: &config.easy_rsa_path,
+            profile,
+            // allow `easy_rsa_pki_dir` to be relative to the config file
+            pki_dir: config_dir.join(&profile.easy_rsa_pki_dir),
+            opts,
+        }
+    }
+
+    fn days_arg(&self, days: Option<usize>) -> Option<String> {
+        days.or(self.profile.default_days)
+            .map(|d| format!("--days={d}"))
+    }
+}
+impl Backend for EasyRsa<'_> {
+    fn users(&self) -> color_eyre::Result<Vec<Username>> {
+        get_users(self.config_dir, self.profile)
+    }
+
+    fn build_client(
+        &self,
+        username: &Username,
+        days: Option<usize>,
+        passphrase: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let Self { easy_rsa, ref pki_dir, opts, .. } = *self;
+        let force_arg = opts.force.then_some("--batch");
+        let days_arg = self.days_arg(days);
+        let pass_arg = match passphrase {
+            Some(_) => format!("--passout=env:{PASSOUT_VAR}"),
+            None => "--no-pass".into(),
+        };
+
+        let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        let mut cmd = cmd!(
+            sh,
+            "{easy_rsa} {force_arg...} --pki-dir={pki_dir} {pass_arg} {days_arg...} build-client-full {username}"
+        );
+        if let Some(passphrase) = passphrase {
+            cmd = cmd.env(PASSOUT_VAR, passphrase);
+        }
+        run_cmd(cmd, opts.dry_run).wrap_err("User creation command failed to execute")?;
+        Ok(())
+    }
+
+    fn renew(&self, username: &Username, days: Option<usize>) -> color_eyre::Result<()> {
+        let Self { easy_rsa, ref pki_dir, opts, .. } = *self;
+        let force_arg = opts.force.then_some("--batch");
+        let days_arg = self.days_arg(days);
+
+        let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        run_cmd(
+            cmd!(
+                sh,
+                "{easy_rsa} {force_arg...} --pki-dir={pki_dir} {days_arg...} renew {username}"
+            ),
+            opts.dry_run,
+        )
+        .wrap_err("User renewal command failed to execute")?;
+        Ok(())
+    }
+
+    fn revoke_renewed(&self, username: &Username) -> color_eyre::Result<()> {
+        let Self { easy_rsa, ref pki_dir, opts, .. } = *self;
+        let force_arg = opts.force.then_some("--batch");
+
+        let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        run_cmd(
+            cmd!(
+                sh,
+                "{easy_rsa} {force_arg...} --pki-dir={pki_dir} revoke-renewed {username}"
+            ),
+            opts.dry_run,
+        )
+        .wrap_err("User revoke renewed command failed to execute")?;
+        Ok(())
+    }
+
+    fn revoke(&self, username: &Username) -> color_eyre::Result<()> {
+        let Self { easy_rsa, ref pki_dir, opts, .. } = *self;
+        let force_arg = opts.force.then_some("--batch");
+
+        let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        run_cmd(
+            cmd!(
+                sh,
+                "{easy_rsa} {force_arg...} --pki-dir={pki_dir} revoke {username}"
+            ),
+            opts.dry_run,
+        )
+        .wrap_err("User deletion command failed to execute")?;
+        Ok(())
+    }
+
+    fn gen_crl(&self) -> color_eyre::Result<()> {
+        let Self { easy_rsa, ref pki_dir, opts, .. } = *self;
+        let force_arg = opts.force.then_some("--batch");
+        // an expired CRL causes all clients to be rejected
+        // this CRL is self-managed anyways, so we set it to practically-unlimited
+        let days_arg = format!("--days={}", get_max_days());
+
+        let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        run_cmd(
+            cmd!(
+                sh,
+                "{easy_rsa} {force_arg...} --pki-dir={pki_dir} {days_arg} gen-crl"
+            ),
+            opts.dry_run,
+        )
+        .wrap_err("CRL regenerate command failed to execute")?;
+        Ok(())
+    }
+}
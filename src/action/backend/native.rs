@@ -0,0 +1,697 @@
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{bail, eyre, Context};
+use rcgen::{
+    CertificateParams, CertificateRevocationListParams, CertificateSigningRequestParams,
+    DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyIdMethod, KeyPair,
+    KeyUsagePurpose, PublicKeyData, RevokedCertParams, SerialNumber, SignatureAlgorithm,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use time::OffsetDateTime;
+use x509_parser::{extensions::ParsedExtension, pem::parse_x509_pem};
+
+use crate::{
+    action::{
+        backend::Backend,
+        passphrase::{encrypt_key, is_encrypted},
+        shared::{get_max_days, get_users},
+        ExecOptions,
+    },
+    config::{KeyAlgorithm, Profile},
+    pki::{
+        append_index_record, read_cert, read_index, to_hex, update_index_record, CertStatus,
+        IndexRecord, Revocation, RevocationReason,
+    },
+    shell::print_dry_run,
+    types::Username,
+};
+
+/// The number of days a certificate is valid for if unspecified,
+/// same as easy-rsa's default.
+const DEFAULT_DAYS: usize = 825;
+
+/// The backend that signs certificates and CRLs directly.
+///
+/// Files are written to the same locations as easy-rsa 3 would, and the PKI
+/// database is kept in OpenSSL's format.
+pub struct Native<'a> {
+    config_dir: &'a Path,
+    profile: &'a Profile,
+    pki_dir: PathBuf,
+    dry_run: bool,
+}
+impl<'a> Native<'a> {
+    pub fn new(config_dir: &'a Path, profile: &'a Profile, opts: ExecOptions) -> Self {
+        Self {
+            config_dir,
+            profile,
+            // allow `easy_rsa_pki_dir` to be relative to the config file
+            pki_dir: config_dir.join(&profile.easy_rsa_pki_dir),
+            dry_run: opts.dry_run,
+        }
+    }
+
+    /// Load the CA certificate and key.
+    ///
+    /// The CA's key identifier is also returned, since CRLs do not take it from the issuer.
+    fn load_issuer(&self) -> color_eyre::Result<(Issuer<'static, KeyPair>, KeyIdMethod)> {
+        let cert_path = self.pki_dir.join("ca.crt");
+        let key_path = self.pki_dir.join("private").join("ca.key");
+
+        let cert_pem = fs::read_to_string(&cert_path)
+            .wrap_err_with(|| format!("Failed to read CA certificate {cert_path:?}"))?;
+        let key = load_key(&key_path)?;
+
+        let (_, pem) = parse_x509_pem(cert_pem.as_bytes())
+            .map_err(|err| eyre!("{err}"))
+            .wrap_err_with(|| format!("{cert_path:?} is not PEM-encoded"))?;
+        let cert = pem
+            .parse_x509()
+            .map_err(|err| eyre!("{err}"))
+            .wrap_err_with(|| format!("{cert_path:?} is not a valid X.509 certificate"))?;
+        let key_id = cert
+            .iter_extensions()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) => Some(id.0.to_vec()),
+                _ => None,
+            })
+            .map_or(KeyIdMethod::Sha256, KeyIdMethod::PreSpecified);
+
+        let issuer = Issuer::from_ca_cert_pem(&cert_pem, key)
+            .wrap_err_with(|| format!("Failed to parse CA certificate {cert_path:?}"))?;
+        Ok((issuer, key_id))
+    }
+
+    /// Generate a new key according to the profile's PKI settings.
+    fn generate_key(&self) -> color_eyre::Result<KeyPair> {
+        let (algorithm, curve) = match self.profile.pki {
+            Some(ref pki) => (pki.key_algorithm, pki.curve.as_deref()),
+            None => (KeyAlgorithm::Ec, None),
+        };
+        let alg: &'static SignatureAlgorithm = match (algorithm, curve) {
+            (KeyAlgorithm::Rsa, _) => {
+                bail!("The native backend cannot generate RSA keys; use EC or EdDSA instead")
+            }
+            (KeyAlgorithm::Ec, None | Some("secp384r1")) => &rcgen::PKCS_ECDSA_P384_SHA384,
+            (KeyAlgorithm::Ec, Some("prime256v1" | "secp256r1")) => &rcgen::PKCS_ECDSA_P256_SHA256,
+            (KeyAlgorithm::Ed, None | Some("ed25519")) => &rcgen::PKCS_ED25519,
+            (algorithm, Some(curve)) => {
+                bail!(r#"The native backend does not support the {algorithm} curve "{curve}""#)
+            }
+        };
+        KeyPair::generate_for(alg).wrap_err("Failed to generate key")
+    }
+
+    /// Pick a random serial number that is not yet in the PKI database.
+    ///
+    /// easy-rsa uses random 128-bit serial numbers too.
+    fn new_serial(&self, records: &[IndexRecord]) -> color_eyre::Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        loop {
+            let mut bytes = [0; 16];
+            rng.fill(&mut bytes)
+                .map_err(|_| eyre!("Failed to generate random serial number"))?;
+            // keep the serial positive and of a fixed length
+            bytes[0] = bytes[0] & 0x7F | 0x10;
+            let serial = to_hex(&bytes, "");
+            if !records.iter().any(|r| r.serial == serial) {
+                return Ok(bytes.to_vec());
+            }
+        }
+    }
+
+    /// Sign a client certificate for a public key, and record it in the PKI database.
+    fn sign(
+        &self,
+        username: &Username,
+        key: &impl PublicKeyData,
+        days: Option<usize>,
+    ) -> color_eyre::Result<()> {
+        let (issuer, _) = self.load_issuer()?;
+        let records = read_index(&self.pki_dir)?;
+        let serial = self.new_serial(&records)?;
+        let serial_hex = to_hex(&serial, "");
+
+        let days = days.or(self.profile.default_days).unwrap_or(DEFAULT_DAYS);
+        let not_before = truncate_to_secs(Utc::now());
+        let not_after = not_before + Duration::days(days as i64);
+
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, username.as_str());
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name;
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        params.not_before = to_offset_date_time(not_before)?;
+        params.not_after = to_offset_date_time(not_after)?;
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.use_authority_key_identifier_extension = true;
+        params.key_identifier_method = KeyIdMethod::Sha256;
+
+        let cert = params
+            .signed_by(key, &issuer)
+            .wrap_err_with(|| format!(r#"Failed to sign certificate for user "{username}""#))?;
+
+        let cert_path = self.pki_dir.join("issued").join(format!("{username}.crt"));
+        let by_serial_path = self
+            .pki_dir
+            .join("certs_by_serial")
+            .join(format!("{serial_hex}.pem"));
+        write_file(&cert_path, cert.pem(), false)?;
+        write_file(&by_serial_path, cert.pem(), false)?;
+
+        let record = IndexRecord {
+            status: CertStatus::Valid,
+            expiry: not_after,
+            revocation: None,
+            serial: serial_hex,
+            // unused, as with OpenSSL
+            filename: "unknown".into(),
+            subject: format!("/CN={username}"),
+        };
+        append_index_record(&self.pki_dir, &record)?;
+        update_serial_file(&self.pki_dir, &serial)?;
+        Ok(())
+    }
+
+    /// Mark a certificate as revoked in the PKI database.
+    fn mark_revoked(
+        &self,
+        serial: &str,
+        reason: Option<RevocationReason>,
+    ) -> color_eyre::Result<()> {
+        let mut record = read_index(&self.pki_dir)?
+            .into_iter()
+            .find(|r| r.serial == serial)
+            .ok_or_else(|| eyre!("Certificate {serial} is not in the PKI database"))?;
+        if record.status == CertStatus::Revoked {
+            bail!("Certificate {serial} is already revoked");
+        }
+        record.status = CertStatus::Revoked;
+        record.revocation = Some(Revocation { date: truncate_to_secs(Utc::now()), reason });
+        update_index_record(&self.pki_dir, &record)
+    }
+}
+impl Backend for Native<'_> {
+    fn users(&self) -> color_eyre::Result<Vec<Username>> {
+        get_users(self.config_dir, self.profile)
+    }
+
+    fn build_client(
+        &self,
+        username: &Username,
+        days: Option<usize>,
+        passphrase: Option<&str>,
+    ) -> color_eyre::Result<()> {
+        let key_path = self.pki_dir.join("private").join(format!("{username}.key"));
+        let req_path = self.pki_dir.join("reqs").join(format!("{username}.req"));
+        let cert_path = self.pki_dir.join("issued").join(format!("{username}.crt"));
+        for path in [&key_path, &req_path, &cert_path] {
+            if path.exists() {
+                bail!(r#"{path:?} already exists; refusing to overwrite it"#);
+            }
+        }
+
+        if self.dry_run {
+            let key_kind = if passphrase.is_some() { "passphrase-protected key" } else { "key" };
+            print_dry_run(format!(
+                r#"generate {key_kind} and request for user "{username}""#
+            ));
+            print_dry_run(format!(r#"sign client certificate for user "{username}""#));
+            return Ok(());
+        }
+
+        let key = self.generate_key()?;
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, username.as_str());
+        let mut req_params = CertificateParams::default();
+        req_params.distinguished_name = distinguished_name;
+        let req = req_params
+            .serialize_request(&key)
+            .and_then(|req| req.pem())
+            .wrap_err_with(|| format!(r#"Failed to create request for user "{username}""#))?;
+
+        let key_pem = match passphrase {
+            Some(passphrase) => encrypt_key(&key.serialize_pem(), passphrase, None)
+                .wrap_err_with(|| format!(r#"Failed to encrypt key for user "{username}""#))?,
+            None => key.serialize_pem(),
+        };
+
+        write_file(&key_path, key_pem, true)?;
+        write_file(&req_path, req, false)?;
+        self.sign(username, &key, days)
+    }
+
+    fn renew(&self, username: &Username, days: Option<usize>) -> color_eyre::Result<()> {
+        let key_path = self.pki_dir.join("private").join(format!("{username}.key"));
+        let req_path = self.pki_dir.join("reqs").join(format!("{username}.req"));
+        let cert_path = self.pki_dir.join("issued").join(format!("{username}.crt"));
+        let renewed_path = self
+            .pki_dir
+            .join("renewed")
+            .join("issued")
+            .join(format!("{username}.crt"));
+        if renewed_path.exists() {
+            bail!(
+                r#"User "{username}" has a renewed certificate that was not revoked; revoke it first"#
+            );
+        }
+        let serial = read_cert(&cert_path)?.serial;
+
+        if self.dry_run {
+            print_dry_run(format!("move {cert_path:?} to {renewed_path:?}"));
+            print_dry_run(format!(r#"sign client certificate for user "{username}""#));
+            return Ok(());
+        }
+
+        // the existing key is reused, as easy-rsa does
+        let public_key = load_public_key(&key_path, &req_path)?;
+        move_file(&cert_path, &renewed_path)?;
+        let by_serial_path = self
+            .pki_dir
+            .join("certs_by_serial")
+            .join(format!("{serial}.pem"));
+        if by_serial_path.is_file() {
+            let renewed_by_serial_path = self
+                .pki_dir
+                .join("renewed")
+                .join("certs_by_serial")
+                .join(format!("{serial}.crt"));
+            move_file(&by_serial_path, &renewed_by_serial_path)?;
+        }
+        self.sign(username, &public_key.as_ref(), days)
+    }
+
+    fn revoke_renewed(&self, username: &Username) -> color_eyre::Result<()> {
+        let renewed_dir = self.pki_dir.join("renewed");
+        let renewed_path = renewed_dir.join("issued").join(format!("{username}.crt"));
+        let revoked_dir = self.pki_dir.join("revoked").join("certs_by_serial");
+
+        if self.dry_run {
+            // the certificate was not actually moved aside by the renewal
+            print_dry_run(format!(
+                r#"revoke the replaced certificate of user "{username}" as superseded"#
+            ));
+            print_dry_run(format!("move {renewed_path:?} into {revoked_dir:?}"));
+            return Ok(());
+        }
+
+        let serial = read_cert(&renewed_path)?.serial;
+        let revoked_path = revoked_dir.join(format!("{serial}.crt"));
+
+        self.mark_revoked(&serial, Some(RevocationReason::Superseded))?;
+        move_file(&renewed_path, &revoked_path)?;
+        let by_serial_path = renewed_dir
+            .join("certs_by_serial")
+            .join(format!("{serial}.crt"));
+        if by_serial_path.is_file() {
+            fs::remove_file(&by_serial_path)
+                .wrap_err_with(|| format!("Failed to remove {by_serial_path:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn revoke(&self, username: &Username) -> color_eyre::Result<()> {
+        let cert_path = self.pki_dir.join("issued").join(format!("{username}.crt"));
+        let serial = read_cert(&cert_path)?.serial;
+        let revoked_dir = self.pki_dir.join("revoked");
+        let moves = [
+            (
+                cert_path,
+                revoked_dir
+                    .join("certs_by_serial")
+                    .join(format!("{serial}.crt")),
+            ),
+            (
+                self.pki_dir.join("private").join(format!("{username}.key")),
+                revoked_dir
+                    .join("private_by_serial")
+                    .join(format!("{serial}.key")),
+            ),
+            (
+                self.pki_dir.join("reqs").join(format!("{username}.req")),
+                revoked_dir
+                    .join("reqs_by_serial")
+                    .join(format!("{serial}.req")),
+            ),
+        ];
+
+        if self.dry_run {
+            print_dry_run(format!("revoke certificate {serial}"));
+            for (from, to) in &moves {
+                print_dry_run(format!("move {from:?} to {to:?}"));
+            }
+            return Ok(());
+        }
+
+        self.mark_revoked(&serial, None)?;
+        for (from, to) in &moves {
+            if from.is_file() {
+                move_file(from, to)?;
+            }
+        }
+        let by_serial_path = self
+            .pki_dir
+            .join("certs_by_serial")
+            .join(format!("{serial}.pem"));
+        if by_serial_path.is_file() {
+            fs::remove_file(&by_serial_path)
+                .wrap_err_with(|| format!("Failed to remove {by_serial_path:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn gen_crl(&self) -> color_eyre::Result<()> {
+        let crl_path = self.pki_dir.join("crl.pem");
+        if self.dry_run {
+            print_dry_run(format!("write CRL to {crl_path:?}"));
+            return Ok(());
+        }
+
+        let (issuer, key_id) = self.load_issuer()?;
+        let revoked_certs = read_index(&self.pki_dir)?
+            .into_iter()
+            .filter_map(|r| r.revocation.map(|rev| (r.serial, rev)))
+            .map(|(serial, Revocation { date, reason })| {
+                Ok(RevokedCertParams {
+                    serial_number: SerialNumber::from_slice(&from_hex(&serial)?),
+                    revocation_time: to_offset_date_time(date)?,
+                    reason_code: reason.map(to_crl_reason),
+                    invalidity_date: None,
+                })
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+
+        // the CRL number is stored in the same way as OpenSSL does
+        let crl_number_path = self.pki_dir.join("crlnumber");
+        let crl_number = match fs::read_to_string(&crl_number_path) {
+            Ok(s) => {
+                from_hex(s.trim()).wrap_err_with(|| format!("{crl_number_path:?} is malformed"))?
+            }
+            // no CRL was generated yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![1],
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Failed to read {crl_number_path:?}"))
+            }
+        };
+
+        // an expired CRL causes all clients to be rejected
+        // this CRL is self-managed anyways, so we set it to practically-unlimited
+        let this_update = Utc::now();
+        let next_update = this_update + Duration::days(get_max_days());
+        let params = CertificateRevocationListParams {
+            this_update: to_offset_date_time(this_update)?,
+            next_update: to_offset_date_time(next_update)?,
+            crl_number: SerialNumber::from_slice(&crl_number),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: key_id,
+        };
+        let crl = params
+            .signed_by(&issuer)
+            .and_then(|crl| crl.pem())
+            .wrap_err("Failed to sign CRL")?;
+
+        write_file(&crl_path, crl, false)?;
+        write_file(
+            &crl_number_path,
+            format!("{}\n", to_hex(&increment(&crl_number), "")),
+            false,
+        )
+    }
+}
+
+/// Load an unencrypted private key.
+fn load_key(path: &Path) -> color_eyre::Result<KeyPair> {
+    let pem = fs::read_to_string(path).wrap_err_with(|| format!("Failed to read key {path:?}"))?;
+    if is_encrypted(&pem) {
+        bail!("{path:?} is passphrase-protected, which the native backend does not support");
+    }
+    KeyPair::from_pem(&pem).wrap_err_with(|| format!("Failed to parse key {path:?}"))
+}
+
+/// Load the public key of a user.
+///
+/// Passphrase-protected keys cannot be read, so the public key is taken from
+/// the user's request instead.
+fn load_public_key(key_path: &Path, req_path: &Path) -> color_eyre::Result<Box<dyn PublicKeyData>> {
+    let pem = fs::read_to_string(key_path)
+        .wrap_err_with(|| format!("Failed to read key {key_path:?}"))?;
+    if !is_encrypted(&pem) {
+        let key = KeyPair::from_pem(&pem)
+            .wrap_err_with(|| format!("Failed to parse key {key_path:?}"))?;
+        return Ok(Box::new(key));
+    }
+
+    let req = fs::read_to_string(req_path).wrap_err_with(|| {
+        format!("{key_path:?} is passphrase-protected, and its request {req_path:?} cannot be read")
+    })?;
+    let req = CertificateSigningRequestParams::from_pem(&req)
+        .wrap_err_with(|| format!("Failed to parse request {req_path:?}"))?;
+    Ok(Box::new(req.public_key))
+}
+
+/// Record the next serial number as OpenSSL does, for tools that still read it.
+fn update_serial_file(pki_dir: &Path, serial: &[u8]) -> color_eyre::Result<()> {
+    let serial_path = pki_dir.join("serial");
+    write_file(
+        &serial_path,
+        format!("{}\n", to_hex(&increment(serial), "")),
+        false,
+    )
+}
+
+/// Write a file, creating its parent directory if needed.
+fn write_file(path: &Path, content: impl AsRef<[u8]>, is_secret: bool) -> color_eyre::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+    }
+    let mode = if is_secret { 0o600 } else { 0o644 };
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .wrap_err_with(|| format!("Failed to create {path:?}"))?;
+    file.write_all(content.as_ref())
+        .wrap_err_with(|| format!("Failed to write into {path:?}"))
+}
+
+/// Move a file, creating the destination directory if needed.
+fn move_file(from: &Path, to: &Path) -> color_eyre::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+    }
+    fs::rename(from, to).wrap_err_with(|| format!("Failed to move {from:?} to {to:?}"))
+}
+
+fn to_offset_date_time(time: DateTime<Utc>) -> color_eyre::Result<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(time.timestamp())
+        .wrap_err_with(|| format!("{time} is out of range"))
+}
+
+/// The PKI database only stores times to the second.
+fn truncate_to_secs(time: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_timestamp(time.timestamp(), 0).unwrap_or(time)
+}
+
+fn to_crl_reason(reason: RevocationReason) -> rcgen::RevocationReason {
+    use rcgen::RevocationReason as R;
+    match reason {
+        RevocationReason::Unspecified => R::Unspecified,
+        RevocationReason::KeyCompromise => R::KeyCompromise,
+        RevocationReason::CaCompromise => R::CaCompromise,
+        RevocationReason::AffiliationChanged => R::AffiliationChanged,
+        RevocationReason::Superseded => R::Superseded,
+        RevocationReason::CessationOfOperation => R::CessationOfOperation,
+        RevocationReason::CertificateHold => R::CertificateHold,
+        RevocationReason::RemoveFromCrl => R::RemoveFromCrl,
+    }
+}
+
+fn from_hex(s: &str) -> color_eyre::Result<Vec<u8>> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!(r#""{s}" is not hexadecimal"#);
+    }
+    // OpenSSL may drop the leading zero
+    let s = if s.len() % 2 == 1 { format!("0{s}") } else { s.to_owned() };
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).wrap_err("Invalid hexadecimal"))
+        .collect()
+}
+
+/// Add one to a big-endian unsigned integer.
+fn increment(bytes: &[u8]) -> Vec<u8> {
+    let mut output = bytes.to_vec();
+    for byte in output.iter_mut().rev() {
+        let (sum, overflow) = byte.overflowing_add(1);
+        *byte = sum;
+        if !overflow {
+            return output;
+        }
+    }
+    output.insert(0, 1);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use rcgen::BasicConstraints;
+    use temp_dir::TempDir;
+
+    use super::*;
+    use crate::pki::{read_crl_serials, verify_cert_issuer};
+
+    fn profile() -> Profile {
+        toml_edit::de::from_str("name = \"test\"\neasy-rsa-pki-dir = \"pki\"").unwrap()
+    }
+
+    /// Create a PKI with a self-signed CA, as `build-ca` would.
+    fn build_ca(pki_dir: &Path) -> color_eyre::Result<()> {
+        let key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384)?;
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let cert = params.self_signed(&key)?;
+
+        write_file(&pki_dir.join("ca.crt"), cert.pem(), false)?;
+        write_file(
+            &pki_dir.join("private").join("ca.key"),
+            key.serialize_pem(),
+            true,
+        )?;
+        write_file(&pki_dir.join("index.txt"), "", false)
+    }
+
+    fn crl_number(pki_dir: &Path) -> color_eyre::Result<String> {
+        Ok(fs::read_to_string(pki_dir.join("crlnumber"))?
+            .trim()
+            .to_owned())
+    }
+
+    #[test]
+    fn build_revoke_and_gen_crl() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let profile = profile();
+        let pki_dir = dir.child("pki");
+        build_ca(&pki_dir)?;
+        let native = Native::new(dir.path(), &profile, ExecOptions::default());
+        let (alice, bob) = ("alice".parse()?, "bob".parse()?);
+
+        native.build_client(&alice, Some(30), None)?;
+        native.build_client(&bob, None, None)?;
+        let alice_cert = pki_dir.join("issued").join("alice.crt");
+        let alice_key = pki_dir.join("private").join("alice.key");
+        verify_cert_issuer(&alice_cert, pki_dir.join("ca.crt"))?;
+        assert_eq!(
+            fs::metadata(&alice_key)?.permissions().mode() & 0o777,
+            0o600
+        );
+        let alice_serial = read_cert(&alice_cert)?.serial;
+
+        let records = read_index(&pki_dir)?;
+        assert_eq!(records.len(), 2);
+        let record = &records[0];
+        assert_eq!(record.serial, alice_serial);
+        assert_eq!(record.subject, "/CN=alice");
+        assert_eq!(record.status, CertStatus::Valid);
+        assert_eq!(record.expiry, read_cert(&alice_cert)?.not_after);
+        assert!(
+            native.build_client(&alice, None, None).is_err(),
+            "already exists"
+        );
+
+        native.revoke(&alice)?;
+        let record = read_index(&pki_dir)?.remove(0);
+        assert_eq!(record.status, CertStatus::Revoked);
+        assert!(record.revocation.is_some_and(|rev| rev.reason.is_none()));
+        assert!(!alice_cert.exists() && !alice_key.exists());
+        let revoked_dir = pki_dir.join("revoked");
+        assert!(revoked_dir
+            .join("certs_by_serial")
+            .join(format!("{alice_serial}.crt"))
+            .is_file());
+        assert!(revoked_dir
+            .join("private_by_serial")
+            .join(format!("{alice_serial}.key"))
+            .is_file());
+        assert!(native.revoke(&alice).is_err(), "already revoked");
+
+        native.gen_crl()?;
+        let crl_path = pki_dir.join("crl.pem");
+        assert_eq!(read_crl_serials(&crl_path)?, [alice_serial].into());
+        assert_eq!(crl_number(&pki_dir)?, "02");
+        native.gen_crl()?;
+        assert_eq!(crl_number(&pki_dir)?, "03");
+        Ok(())
+    }
+
+    #[test]
+    fn renew_and_revoke_renewed() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let profile = profile();
+        let pki_dir = dir.child("pki");
+        build_ca(&pki_dir)?;
+        let native = Native::new(dir.path(), &profile, ExecOptions::default());
+        let alice = "alice".parse()?;
+
+        native.build_client(&alice, None, None)?;
+        let cert_path = pki_dir.join("issued").join("alice.crt");
+        let old_serial = read_cert(&cert_path)?.serial;
+        native.renew(&alice, None)?;
+        let new_serial = read_cert(&cert_path)?.serial;
+        assert_ne!(old_serial, new_serial);
+        verify_cert_issuer(&cert_path, pki_dir.join("ca.crt"))?;
+        assert!(
+            native.renew(&alice, None).is_err(),
+            "renewed certificate not revoked"
+        );
+
+        native.revoke_renewed(&alice)?;
+        let records = read_index(&pki_dir)?;
+        let old = records.iter().find(|r| r.serial == old_serial).unwrap();
+        let reason = old.revocation.as_ref().and_then(|rev| rev.reason);
+        assert_eq!(reason, Some(RevocationReason::Superseded));
+        let new = records.iter().find(|r| r.serial == new_serial).unwrap();
+        assert_eq!(new.status, CertStatus::Valid);
+
+        native.gen_crl()?;
+        assert_eq!(
+            read_crl_serials(pki_dir.join("crl.pem"))?,
+            [old_serial].into()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn gen_crl_propagates_unreadable_crl_number() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let profile = profile();
+        let pki_dir = dir.child("pki");
+        build_ca(&pki_dir)?;
+        let native = Native::new(dir.path(), &profile, ExecOptions::default());
+
+        // a directory cannot be read as a file
+        fs::create_dir(pki_dir.join("crlnumber"))?;
+        let err = native.gen_crl().unwrap_err();
+        assert!(format!("{err:#}").contains("Failed to read"), "{err:#}");
+        assert!(!pki_dir.join("crl.pem").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, eyre, Context};
+use log::warn;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use zip::{result::ZipError, write::SimpleFileOptions, AesMode, ZipArchive, ZipWriter};
+
+use crate::config::Profile;
+
+/// The path of the manifest in a backup archive.
+const MANIFEST_PATH: &str = "manifest.json";
+
+/// The path of the profile's config section in a backup archive.
+const PROFILE_PATH: &str = "profile.toml";
+
+/// The directory in a backup archive that contains the PKI.
+const PKI_PREFIX: &str = "pki";
+
+/// Describes the content of a backup archive, so that it can be verified on restore.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupManifest {
+    pub created: DateTime<Utc>,
+    /// The name of the profile that was backed up.
+    pub profile: String,
+    /// The SHA-256 digest of every other file in the archive, keyed by path.
+    pub files: BTreeMap<String, String>,
+}
+
+/// The content of a backup archive, extracted and verified.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Backup {
+    pub manifest: BackupManifest,
+    /// The profile's config section at the time of the backup, as TOML.
+    pub profile_section: String,
+    /// The directory the PKI was extracted to.
+    pub pki_dir: PathBuf,
+}
+
+/// Write an archive of a PKI directory and the profile's config section.
+///
+/// If a password is given, every file in the archive is encrypted with AES-256.
+pub fn write_backup(
+    pki_dir: &Path,
+    profile: &Profile,
+    output: File,
+    password: Option<&str>,
+) -> color_eyre::Result<()> {
+    let profile_section =
+        toml_edit::ser::to_string_pretty(profile).wrap_err("Failed to serialise the profile")?;
+
+    let mut dirs = vec![];
+    let mut files = vec![(PROFILE_PATH.to_owned(), profile_section.into_bytes(), 0o600)];
+    for (subpath, is_dir) in list_entries(pki_dir)? {
+        let path = pki_dir.join(&subpath);
+        let mode = fs::metadata(&path)
+            .wrap_err_with(|| format!("Failed to read metadata of {path:?}"))?
+            .permissions()
+            .mode();
+        let name = Path::new(PKI_PREFIX).join(subpath);
+        let name = name
+            .to_str()
+            .ok_or_else(|| eyre!("{path:?} is not valid UTF-8"))?
+            .to_owned();
+        if is_dir {
+            // kept so that empty directories are restored too
+            dirs.push((name, mode));
+        } else {
+            let content = fs::read(&path).wrap_err_with(|| format!("Failed to read {path:?}"))?;
+            files.push((name, content, mode));
+        }
+    }
+
+    let manifest = BackupManifest {
+        created: Utc::now(),
+        profile: profile.name.clone(),
+        files: files
+            .iter()
+            .map(|(name, content, _)| (name.clone(), sha256_hex(content)))
+            .collect(),
+    };
+    let manifest =
+        serde_json::to_vec_pretty(&manifest).wrap_err("Failed to serialise the manifest")?;
+
+    let mut writer = ZipWriter::new(output);
+    for (name, mode) in &dirs {
+        let options = SimpleFileOptions::default().unix_permissions(*mode);
+        writer
+            .add_directory(name, options)
+            .wrap_err_with(|| format!(r#"Failed to write "{name}" into the archive"#))?;
+    }
+    let manifest_entry = (MANIFEST_PATH.to_owned(), manifest, 0o600);
+    for (name, content, mode) in std::iter::once(&manifest_entry).chain(&files) {
+        let options = SimpleFileOptions::default().unix_permissions(*mode);
+        let options = match password {
+            Some(password) => options.with_aes_encryption(AesMode::Aes256, password),
+            None => options,
+        };
+        writer
+            .start_file(name, options)
+            .and_then(|_| Ok(writer.write_all(content)?))
+            .wrap_err_with(|| format!(r#"Failed to write "{name}" into the archive"#))?;
+    }
+    writer.finish().wrap_err("Failed to finish the archive")?;
+    Ok(())
+}
+
+/// Extract a backup archive into a directory, verifying every file against the
+/// manifest.
+pub fn read_backup(
+    archive_path: &Path,
+    password: Option<&str>,
+    target_dir: &Path,
+) -> color_eyre::Result<Backup> {
+    let file = File::open(archive_path)
+        .wrap_err_with(|| format!("Failed to open archive {archive_path:?}"))?;
+    let mut archive = ZipArchive::new(file)
+        .wrap_err_with(|| format!("{archive_path:?} is not a valid archive"))?;
+
+    let (manifest, _) = read_entry(&mut archive, MANIFEST_PATH, password)?;
+    let manifest: BackupManifest =
+        serde_json::from_slice(&manifest).wrap_err("Failed to parse the manifest")?;
+
+    let mut dirs = vec![];
+    for i in 0..archive.len() {
+        // directories are never encrypted
+        let entry = archive
+            .by_index_raw(i)
+            .wrap_err_with(|| format!("Failed to read entry {i} in the archive"))?;
+        if entry.is_dir() {
+            dirs.push((entry.name().to_owned(), entry.unix_mode()));
+        }
+    }
+    for (name, _) in &dirs {
+        let path = target_dir.join(safe_subpath(name)?);
+        fs::create_dir_all(&path)
+            .wrap_err_with(|| format!("Failed to create directory {path:?}"))?;
+    }
+
+    let mut profile_section = None;
+    let mut seen = vec![];
+    for name in manifest.files.keys() {
+        let subpath = safe_subpath(name)?;
+        let (content, mode) = read_entry(&mut archive, name, password)?;
+        if sha256_hex(&content) != manifest.files[name] {
+            bail!(r#"The checksum of "{name}" does not match the manifest"#);
+        }
+        seen.push(name.as_str());
+
+        if name == PROFILE_PATH {
+            let section = String::from_utf8(content)
+                .wrap_err_with(|| format!(r#""{name}" is not valid UTF-8"#))?;
+            profile_section = Some(section);
+            continue;
+        }
+        let path = target_dir.join(subpath);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::write(&path, content).wrap_err_with(|| format!("Failed to write {path:?}"))?;
+        fs::set_permissions(
+            &path,
+            fs::Permissions::from_mode(mode.unwrap_or(0o600) & 0o7777),
+        )
+        .wrap_err_with(|| format!("Failed to set permissions of {path:?}"))?;
+    }
+
+    // once the files are written, and children first, in case a directory is
+    // read-only
+    dirs.sort();
+    for (name, mode) in dirs.iter().rev() {
+        let Some(mode) = mode else {
+            continue;
+        };
+        let path = target_dir.join(safe_subpath(name)?);
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+            .wrap_err_with(|| format!("Failed to set permissions of {path:?}"))?;
+    }
+
+    // anything not in the manifest may have been tampered with
+    let unlisted = archive
+        .file_names()
+        .filter(|name| *name != MANIFEST_PATH && !name.ends_with('/'))
+        .filter(|name| !seen.contains(name))
+        .map(|name| format!(r#""{name}""#))
+        .collect::<Vec<_>>();
+    if !unlisted.is_empty() {
+        bail!(
+            "The archive contains files not listed in the manifest: {}",
+            unlisted.join(", ")
+        );
+    }
+
+    let profile_section =
+        profile_section.ok_or_else(|| eyre!(r#"The archive does not contain "{PROFILE_PATH}""#))?;
+    Ok(Backup {
+        manifest,
+        profile_section,
+        pki_dir: target_dir.join(PKI_PREFIX),
+    })
+}
+
+/// A directory created next to another one, so that content prepared in it can
+/// be moved into place with a rename; removed when dropped.
+pub struct StagingDir(PathBuf);
+impl StagingDir {
+    pub fn create(target: &Path) -> color_eyre::Result<Self> {
+        let name = target
+            .file_name()
+            .ok_or_else(|| eyre!("{target:?} does not name a directory"))?;
+        let path = target.with_file_name(format!(
+            ".{}.restore-{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::create_dir(&path)
+            .and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(0o700)))
+            .wrap_err_with(|| format!("Failed to create staging directory {path:?}"))?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Keep the directory and its content, e.g. when something in it could not
+    /// be moved back.
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove staging directory {:?}: {err}", self.0);
+        }
+    }
+}
+
+/// Read a whole file and its Unix permissions from an archive, decrypting it
+/// if needed.
+fn read_entry(
+    archive: &mut ZipArchive<File>,
+    name: &str,
+    password: Option<&str>,
+) -> color_eyre::Result<(Vec<u8>, Option<u32>)> {
+    let entry = match password {
+        Some(password) => archive.by_name_decrypt(name, password.as_bytes()),
+        None => archive.by_name(name),
+    };
+    let mut entry = match entry {
+        Ok(entry) => entry,
+        Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+            bail!("The archive is encrypted; use `--password-file` to decrypt it")
+        }
+        Err(ZipError::InvalidPassword) => bail!("The password of the archive is incorrect"),
+        Err(ZipError::FileNotFound) => bail!(r#"The archive does not contain "{name}""#),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!(r#"Failed to read "{name}" in the archive"#))
+        }
+    };
+    let mut content = vec![];
+    // this also checks the CRC, and the authentication code if encrypted
+    entry
+        .read_to_end(&mut content)
+        .wrap_err_with(|| format!(r#"Failed to read "{name}" in the archive"#))?;
+    Ok((content, entry.unix_mode()))
+}
+
+/// Check that a path in an archive stays inside the directory it is extracted to.
+fn safe_subpath(name: &str) -> color_eyre::Result<&Path> {
+    let subpath = Path::new(name);
+    let is_safe = subpath.components().next().is_some()
+        && subpath
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+    if !is_safe {
+        bail!(r#"The archive contains an unsafe path "{name}""#);
+    }
+    Ok(subpath)
+}
+
+/// List all files and directories in a directory recursively, relative to it,
+/// in order, and whether each is a directory.
+fn list_entries(dir: &Path) -> color_eyre::Result<Vec<(PathBuf, bool)>> {
+    let mut entries = vec![];
+    let mut pending = vec![PathBuf::new()];
+    while let Some(subdir) = pending.pop() {
+        let path = dir.join(&subdir);
+        let dir_entries =
+            fs::read_dir(&path).wrap_err_with(|| format!("Failed to read directory {path:?}"))?;
+        for entry in dir_entries {
+            let entry = entry.wrap_err_with(|| format!("Failed to read directory {path:?}"))?;
+            let subpath = subdir.join(entry.file_name());
+            let is_dir = entry.path().is_dir();
+            if is_dir {
+                pending.push(subpath.clone());
+            }
+            entries.push((subpath, is_dir));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    digest(&SHA256, content)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn profile() -> Profile {
+        toml_edit::de::from_str("name = \"test\"\neasy-rsa-pki-dir = \"pki\"").unwrap()
+    }
+
+    /// Create a small PKI directory, with a private directory and an empty one.
+    fn create_pki(dir: &Path) -> color_eyre::Result<PathBuf> {
+        let pki_dir = dir.join("pki");
+        fs::create_dir_all(pki_dir.join("private"))?;
+        fs::create_dir_all(pki_dir.join("issued"))?;
+        fs::create_dir_all(pki_dir.join("revoked"))?;
+        fs::write(pki_dir.join("ca.crt"), "certificate")?;
+        fs::write(pki_dir.join("private").join("ca.key"), "key")?;
+        fs::write(pki_dir.join("issued").join("alice.crt"), "alice")?;
+        fs::set_permissions(
+            pki_dir.join("private").join("ca.key"),
+            fs::Permissions::from_mode(0o600),
+        )?;
+        fs::set_permissions(pki_dir.join("private"), fs::Permissions::from_mode(0o700))?;
+        Ok(pki_dir)
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn write_archive(
+        path: &Path,
+        entries: &[(&str, &[u8])],
+        manifest: &BackupManifest,
+    ) -> color_eyre::Result<()> {
+        let mut writer = ZipWriter::new(File::create(path)?);
+        let manifest = serde_json::to_vec(manifest)?;
+        let entries =
+            std::iter::once((MANIFEST_PATH, &manifest[..])).chain(entries.iter().copied());
+        for (name, content) in entries {
+            writer.start_file(name, SimpleFileOptions::default())?;
+            writer.write_all(content)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn safe_subpath_rejects_escaping_paths() {
+        assert_eq!(
+            safe_subpath("pki/issued/alice.crt").unwrap(),
+            Path::new("pki/issued/alice.crt")
+        );
+        assert!(safe_subpath("pki/issued/").is_ok());
+        for name in ["../ca.key", "pki/../../ca.key", "/etc/passwd", "./pki", ""] {
+            assert!(safe_subpath(name).is_err(), "{name:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn round_trip() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let pki_dir = create_pki(dir.path())?;
+        let archive_path = dir.child("backup.zip");
+        let profile = profile();
+        write_backup(&pki_dir, &profile, File::create(&archive_path)?, None)?;
+
+        let target_dir = dir.child("restored");
+        fs::create_dir(&target_dir)?;
+        let backup = read_backup(&archive_path, None, &target_dir)?;
+        assert_eq!(backup.manifest.profile, "test");
+        assert_eq!(
+            toml_edit::de::from_str::<Profile>(&backup.profile_section)?,
+            profile
+        );
+        assert_eq!(backup.pki_dir, target_dir.join("pki"));
+
+        let restored = &backup.pki_dir;
+        assert_eq!(list_entries(restored)?, list_entries(&pki_dir)?);
+        assert_eq!(fs::read(restored.join("private/ca.key"))?, b"key");
+        assert_eq!(fs::read(restored.join("issued/alice.crt"))?, b"alice");
+        assert_eq!(mode(&restored.join("private")), 0o700);
+        assert_eq!(mode(&restored.join("private/ca.key")), 0o600);
+        assert!(restored.join("revoked").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_encrypted() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let pki_dir = create_pki(dir.path())?;
+        let archive_path = dir.child("backup.zip");
+        write_backup(
+            &pki_dir,
+            &profile(),
+            File::create(&archive_path)?,
+            Some("secret"),
+        )?;
+
+        let target_dir = dir.child("restored");
+        let err = read_backup(&archive_path, None, &target_dir).unwrap_err();
+        assert!(err.to_string().contains("encrypted"), "{err:#}");
+        let err = read_backup(&archive_path, Some("wrong"), &target_dir).unwrap_err();
+        assert!(err.to_string().contains("incorrect"), "{err:#}");
+
+        let backup = read_backup(&archive_path, Some("secret"), &target_dir)?;
+        assert_eq!(fs::read(backup.pki_dir.join("private/ca.key"))?, b"key");
+        Ok(())
+    }
+
+    #[test]
+    fn reject_checksum_mismatch() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let archive_path = dir.child("backup.zip");
+        let manifest = BackupManifest {
+            created: Utc::now(),
+            profile: "test".into(),
+            files: [
+                (PROFILE_PATH.to_owned(), sha256_hex(b"name = \"test\"")),
+                ("pki/ca.crt".to_owned(), sha256_hex(b"certificate")),
+            ]
+            .into(),
+        };
+        let entries: &[(&str, &[u8])] = &[
+            (PROFILE_PATH, b"name = \"test\""),
+            ("pki/ca.crt", b"tampered"),
+        ];
+        write_archive(&archive_path, entries, &manifest)?;
+
+        let err = read_backup(&archive_path, None, &dir.child("restored")).unwrap_err();
+        assert!(
+            err.to_string().contains(r#""pki/ca.crt" does not match"#),
+            "{err:#}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unlisted_files() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let archive_path = dir.child("backup.zip");
+        let manifest = BackupManifest {
+            created: Utc::now(),
+            profile: "test".into(),
+            files: [(PROFILE_PATH.to_owned(), sha256_hex(b"name = \"test\""))].into(),
+        };
+        let entries: &[(&str, &[u8])] = &[
+            (PROFILE_PATH, b"name = \"test\""),
+            ("pki/private/extra.key", b"key"),
+        ];
+        write_archive(&archive_path, entries, &manifest)?;
+
+        let err = read_backup(&archive_path, None, &dir.child("restored")).unwrap_err();
+        assert!(err.to_string().contains("not listed"), "{err:#}");
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use color_eyre::eyre::eyre;
+
+use crate::{
+    action::{rollback::Rollback, ExecOptions},
+    config::Profile,
+    output::{print_records, OutcomeRecord, OutcomeStatus, OutputFormat},
+    types::Username,
+};
+
+/// The result of an action on several users.
+#[must_use]
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// The users whose steps succeeded, in order.
+    pub succeeded: Vec<Username>,
+    /// Why the action failed as a whole, if any user failed in `--keep-going` mode.
+    pub error: Option<color_eyre::Report>,
+}
+impl BatchOutcome {
+    /// Fail if any user failed.
+    pub fn into_result(self) -> color_eyre::Result<Vec<Username>> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.succeeded),
+        }
+    }
+}
+
+/// Tracks the outcome for each user of an action on several users.
+///
+/// Unless in `--keep-going` mode, the first error aborts the whole action as usual,
+/// and nothing is printed.
+pub struct Batch<'a> {
+    opts: ExecOptions,
+    /// The PKI to roll back when the steps for a user fail.
+    pki: Option<(&'a Path, &'a Profile)>,
+    records: Vec<(Username, OutcomeRecord)>,
+}
+impl<'a> Batch<'a> {
+    pub fn new(opts: ExecOptions) -> Self {
+        Self { opts, pki: None, records: vec![] }
+    }
+
+    /// Same as [`Batch::new`], but in `--keep-going` mode, the changes to the PKI
+    /// and to the user's metadata and CCD file made by a user's failed steps are
+    /// also rolled back.
+    ///
+    /// Otherwise the caller is expected to roll back the whole action.
+    pub fn with_rollback(config_dir: &'a Path, profile: &'a Profile, opts: ExecOptions) -> Self {
+        Self { opts, pki: Some((config_dir, profile)), records: vec![] }
+    }
+
+    /// Skip a user that cannot be processed, e.g. because it does not exist.
+    pub fn skip(
+        &mut self,
+        username: &Username,
+        reason: color_eyre::Report,
+    ) -> color_eyre::Result<()> {
+        if !self.opts.keep_going {
+            return Err(reason);
+        }
+        self.push(username, OutcomeStatus::Skipped, Some(reason));
+        Ok(())
+    }
+
+    /// Run the steps for a user.
+    ///
+    /// Returns `None` if they failed in `--keep-going` mode.
+    pub fn run<T>(
+        &mut self,
+        username: &Username,
+        steps: impl FnOnce() -> color_eyre::Result<T>,
+    ) -> color_eyre::Result<Option<T>> {
+        let rollback = match self.pki {
+            Some((config_dir, profile)) if self.opts.keep_going => Some(Rollback::begin_for_user(
+                config_dir, profile, username, self.opts,
+            )?),
+            _ => None,
+        };
+
+        match steps() {
+            Ok(value) => {
+                if let Some(rollback) = rollback {
+                    rollback.commit();
+                }
+                self.push(username, OutcomeStatus::Succeeded, None);
+                Ok(Some(value))
+            }
+            Err(err) if self.opts.keep_going => {
+                drop(rollback);
+                self.push(username, OutcomeStatus::Failed, Some(err));
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the users that have not been skipped or processed yet, in order.
+    pub fn remaining<'u>(&self, usernames: &'u [Username]) -> Vec<&'u Username> {
+        usernames
+            .iter()
+            .filter(|u| !self.records.iter().any(|(v, _)| v == *u))
+            .collect()
+    }
+
+    /// Get the users whose steps succeeded, in order.
+    pub fn succeeded(&self) -> Vec<Username> {
+        self.records
+            .iter()
+            .filter(|(_, r)| r.status == OutcomeStatus::Succeeded)
+            .map(|(u, _)| u.clone())
+            .collect()
+    }
+
+    /// Print the outcome of every user in `--keep-going` mode.
+    ///
+    /// The failure of any user is returned in the outcome instead of as an error,
+    /// so that the caller can still act on the users that succeeded.
+    pub fn finish(self, format: OutputFormat) -> color_eyre::Result<BatchOutcome> {
+        let succeeded = self.succeeded();
+        if !self.opts.keep_going {
+            return Ok(BatchOutcome { succeeded, error: None });
+        }
+
+        let records = self.records.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+        print_records(&records, format, |r| match r.error {
+            Some(ref error) => format!("{}: {}: {error}", r.username, r.status),
+            None => format!("{}: {}", r.username, r.status),
+        })?;
+
+        let failed_count = records
+            .iter()
+            .filter(|r| r.status == OutcomeStatus::Failed)
+            .count();
+        let error =
+            (failed_count > 0).then(|| eyre!("{failed_count} of {} users failed", records.len()));
+        Ok(BatchOutcome { succeeded, error })
+    }
+
+    fn push(
+        &mut self,
+        username: &Username,
+        status: OutcomeStatus,
+        error: Option<color_eyre::Report>,
+    ) {
+        let record = OutcomeRecord {
+            username: username.to_string(),
+            status,
+            error: error.map(|err| format!("{err:#}")),
+        };
+        self.records.push((username.clone(), record));
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use ipnet::Ipv4Net;
+use log::warn;
+
+use crate::{shell::print_dry_run, types::Username};
+
+/// The client-specific config file of a user in the client-config-dir.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CcdFile {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+impl CcdFile {
+    /// Load the file of a user, or start an empty one if it does not exist.
+    pub fn load(ccd_dir: impl AsRef<Path>, username: &Username) -> color_eyre::Result<Self> {
+        let path = ccd_dir.as_ref().join(username);
+        let lines = match fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(str::to_owned).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Failed to read CCD file {path:?}"))
+            }
+        };
+        Ok(Self { path, lines })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn content(&self) -> String {
+        self.lines.iter().map(|l| format!("{l}\n")).collect()
+    }
+
+    /// Set the static address pushed to the client, replacing any existing one.
+    pub fn set_ifconfig_push(&mut self, address: Ipv4Addr, netmask: Ipv4Addr) {
+        let line = format!("ifconfig-push {address} {netmask}");
+        match self
+            .lines
+            .iter_mut()
+            .find(|l| parse_ifconfig_push(l).is_some())
+        {
+            Some(existing) => *existing = line,
+            None => self.lines.push(line),
+        }
+    }
+
+    /// Append a directive, unless it is already present.
+    ///
+    /// Returns whether the directive was added.
+    pub fn add_directive(&mut self, directive: String) -> bool {
+        if self.lines.iter().any(|l| l.trim() == directive) {
+            return false;
+        }
+        self.lines.push(directive);
+        true
+    }
+
+    /// Write the file, creating the client-config-dir if needed.
+    pub fn save(&self, dry_run: bool) -> color_eyre::Result<()> {
+        let path = &self.path;
+        if dry_run {
+            print_dry_run(format!("write CCD file {path:?}:\n{}", self.content()));
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::write(path, self.content()).wrap_err_with(|| format!("Failed to write {path:?}"))
+    }
+}
+
+/// Parse the address of an `ifconfig-push` directive.
+fn parse_ifconfig_push(line: &str) -> Option<Ipv4Addr> {
+    let mut tokens = line.split_whitespace();
+    (tokens.next()? == "ifconfig-push")
+        .then(|| tokens.next()?.parse().ok())
+        .flatten()
+}
+
+/// Get the static addresses assigned in all CCD files, by address.
+pub fn get_assigned_addresses(
+    ccd_dir: impl AsRef<Path>,
+) -> color_eyre::Result<BTreeMap<Ipv4Addr, String>> {
+    let ccd_dir = ccd_dir.as_ref();
+    let entries = match fs::read_dir(ccd_dir) {
+        Ok(entries) => entries,
+        // no CCD files yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Failed to read {ccd_dir:?}")),
+    };
+
+    let mut assigned = BTreeMap::new();
+    for entry in entries {
+        let path = entry
+            .wrap_err_with(|| format!("Failed to read {ccd_dir:?}"))?
+            .path();
+        if !path.is_file() {
+            continue;
+        }
+        let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            continue;
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("Cannot read CCD file {path:?}; ignoring: {err}");
+                continue;
+            }
+        };
+        for address in content.lines().filter_map(parse_ifconfig_push) {
+            if let Some(other) = assigned.insert(address, name.clone()) {
+                warn!(r#"{address} is assigned to both "{other}" and "{name}""#);
+            }
+        }
+    }
+    Ok(assigned)
+}
+
+/// Check that an address can be assigned to a user.
+pub fn check_address(
+    subnet: &Ipv4Net,
+    address: Ipv4Addr,
+    username: &Username,
+    assigned: &BTreeMap<Ipv4Addr, String>,
+) -> color_eyre::Result<()> {
+    if !subnet.contains(&address) {
+        bail!("{address} is not in the VPN subnet {subnet}");
+    }
+    if reserved_addresses(subnet).contains(&address) {
+        bail!("{address} is reserved in the VPN subnet {subnet}");
+    }
+    match assigned.get(&address) {
+        Some(other) if other != username.as_str() => {
+            bail!(r#"{address} is already assigned to "{other}""#)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Find the lowest address in the subnet that is not yet assigned.
+pub fn allocate_address(
+    subnet: &Ipv4Net,
+    assigned: &BTreeMap<Ipv4Addr, String>,
+) -> color_eyre::Result<Ipv4Addr> {
+    let reserved = reserved_addresses(subnet);
+    subnet
+        .hosts()
+        .find(|a| !reserved.contains(a) && !assigned.contains_key(a))
+        .ok_or_else(|| eyre!("There are no free addresses left in the VPN subnet {subnet}"))
+}
+
+/// Addresses that are never assigned to clients.
+///
+/// In OpenVPN's "subnet" topology, the server takes the first usable address.
+fn reserved_addresses(subnet: &Ipv4Net) -> BTreeSet<Ipv4Addr> {
+    let mut reserved = BTreeSet::from([subnet.network(), subnet.broadcast()]);
+    reserved.extend(subnet.hosts().next());
+    reserved
+}
+
+#[cfg(test)]
+mod tests {
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    fn assigned(entries: &[(&str, &str)]) -> BTreeMap<Ipv4Addr, String> {
+        entries
+            .iter()
+            .map(|(address, name)| (address.parse().unwrap(), (*name).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn reserved_addresses_of_subnets() {
+        let subnet = "10.8.0.0/24".parse().unwrap();
+        let expected = ["10.8.0.0", "10.8.0.1", "10.8.0.255"].map(|a| a.parse().unwrap());
+        assert_eq!(reserved_addresses(&subnet), expected.into());
+
+        let subnet = "10.8.0.4/30".parse().unwrap();
+        let expected = ["10.8.0.4", "10.8.0.5", "10.8.0.7"].map(|a| a.parse().unwrap());
+        assert_eq!(reserved_addresses(&subnet), expected.into());
+    }
+
+    #[test]
+    fn allocate_lowest_free_address() -> color_eyre::Result<()> {
+        let subnet = "10.8.0.0/24".parse()?;
+        assert_eq!(
+            allocate_address(&subnet, &assigned(&[]))?,
+            Ipv4Addr::new(10, 8, 0, 2)
+        );
+
+        let taken = assigned(&[("10.8.0.2", "alice"), ("10.8.0.4", "bob")]);
+        assert_eq!(
+            allocate_address(&subnet, &taken)?,
+            Ipv4Addr::new(10, 8, 0, 3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_from_exhausted_subnet() -> color_eyre::Result<()> {
+        // a /30 only has room for the server and a single client
+        let subnet = "10.8.0.4/30".parse()?;
+        assert_eq!(
+            allocate_address(&subnet, &assigned(&[]))?,
+            Ipv4Addr::new(10, 8, 0, 6)
+        );
+        let taken = assigned(&[("10.8.0.6", "alice")]);
+        assert!(allocate_address(&subnet, &taken).is_err());
+
+        let subnet = "10.8.0.0/24".parse()?;
+        let taken = (2..255)
+            .map(|i| (Ipv4Addr::new(10, 8, 0, i), format!("user{i}")))
+            .collect();
+        assert!(allocate_address(&subnet, &taken).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_reserved_and_foreign_addresses() -> color_eyre::Result<()> {
+        let subnet = "10.8.0.0/24".parse()?;
+        let alice = "alice".parse()?;
+        let taken = assigned(&[("10.8.0.10", "alice"), ("10.8.0.11", "bob")]);
+
+        for reserved in ["10.8.0.0", "10.8.0.1", "10.8.0.255"] {
+            let err = check_address(&subnet, reserved.parse()?, &alice, &taken).unwrap_err();
+            assert!(err.to_string().contains("reserved"), "{err}");
+        }
+        let err = check_address(&subnet, "10.9.0.2".parse()?, &alice, &taken).unwrap_err();
+        assert!(err.to_string().contains("not in the VPN subnet"), "{err}");
+        let err = check_address(&subnet, "10.8.0.11".parse()?, &alice, &taken).unwrap_err();
+        assert!(err.to_string().contains(r#""bob""#), "{err}");
+
+        // users may be re-assigned their own address
+        check_address(&subnet, "10.8.0.10".parse()?, &alice, &taken)?;
+        check_address(&subnet, "10.8.0.12".parse()?, &alice, &taken)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_assigned_addresses() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        assert!(get_assigned_addresses(dir.child("missing"))?.is_empty());
+
+        fs::write(
+            dir.child("alice"),
+            "push \"route 10.0.0.0 255.0.0.0\"\nifconfig-push 10.8.0.2 255.255.255.0\n",
+        )?;
+        fs::write(dir.child("bob"), "# ifconfig-push 10.8.0.9 255.255.255.0\n")?;
+        let found = get_assigned_addresses(dir.path())?;
+        assert_eq!(found, assigned(&[("10.8.0.2", "alice")]));
+        Ok(())
+    }
+}
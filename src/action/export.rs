@@ -0,0 +1,91 @@
+use std::{fmt::Write, fs, path::Path};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use xshell::{cmd, Shell};
+
+use crate::{
+    action::{
+        ovpn::strip_to_armour,
+        passphrase::{is_encrypted, PackagedKey, PASSOUT_VAR},
+    },
+    types::Username,
+};
+
+/// Bundle the certificate and key of a user with the CA certificate as PKCS#12,
+/// using OpenSSL.
+///
+/// The bundle is protected by the passphrase of the key, or by an empty password
+/// if the key is not protected. With `legacy`, it is encrypted with 3DES and
+/// SHA-1 instead of OpenSSL 3's defaults, for clients that cannot read those.
+pub fn build_p12(
+    cert_path: &Path,
+    ca_path: &Path,
+    key: &PackagedKey,
+    username: &Username,
+    legacy: bool,
+) -> color_eyre::Result<Vec<u8>> {
+    let cert = fs::read_to_string(cert_path)
+        .wrap_err_with(|| format!("Failed to read certificate {cert_path:?}"))?;
+    // otherwise OpenSSL prompts for it
+    if is_encrypted(&key.pem) && key.passphrase.is_none() {
+        bail!(
+            r#"The key of user "{username}" is passphrase-protected; use `--current-passphrase` to bundle it"#
+        );
+    }
+
+    let sh = Shell::new().wrap_err("Failed to create subshell")?;
+    let passin_args = match key.passphrase {
+        Some(_) => vec!["-passin".to_owned(), format!("env:{PASSOUT_VAR}")],
+        None => vec![],
+    };
+    let passout = match key.passphrase {
+        Some(_) => format!("env:{PASSOUT_VAR}"),
+        None => "pass:".into(),
+    };
+    // unlike `-legacy`, these do not need OpenSSL 3's legacy provider, and are
+    // also understood by OpenSSL 1.1
+    let legacy_args: &[&str] = if legacy {
+        &[
+            "-certpbe",
+            "PBE-SHA1-3DES",
+            "-keypbe",
+            "PBE-SHA1-3DES",
+            "-macalg",
+            "sha1",
+        ]
+    } else {
+        &[]
+    };
+    // the key and certificate are both read from stdin
+    let mut cmd = cmd!(
+        sh,
+        "openssl pkcs12 -export -certfile {ca_path} -name {username} -passout {passout} {passin_args...} {legacy_args...}"
+    )
+    .stdin(format!("{}\n{cert}", key.pem));
+    if let Some(ref passphrase) = key.passphrase {
+        cmd = cmd.env(PASSOUT_VAR, passphrase);
+    }
+    let output = cmd
+        .output()
+        .wrap_err("PKCS#12 bundle command failed to execute")?;
+    Ok(output.stdout)
+}
+
+/// Concatenate the key and certificate of a user and the CA certificate, in
+/// that order, as PEM.
+pub fn render_pem_bundle(
+    cert_path: &Path,
+    ca_path: &Path,
+    key: &PackagedKey,
+) -> color_eyre::Result<String> {
+    let mut output = String::new();
+    writeln!(output, "{}", key.pem.trim_end())?;
+    for path in [cert_path, ca_path] {
+        let content =
+            fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {path:?}"))?;
+        let content = strip_to_armour(&content)
+            .ok_or_else(|| eyre!("{path:?} does not contain a PEM block"))?;
+        writeln!(output, "{}", content.trim_end())?;
+    }
+    Ok(output)
+}
@@ -0,0 +1,151 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::{bail, Context};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    action::{audit::os_user, ExecOptions},
+    config::{Config, Profile},
+};
+
+/// How long to wait for a lock if the config does not say.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to retry while waiting for a lock.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The process holding a lock, as written into the lock file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct LockHolder {
+    pid: u32,
+    os_user: Option<String>,
+    since: DateTime<Utc>,
+}
+
+/// An exclusive advisory lock on the PKI directory of a profile, released when
+/// dropped.
+///
+/// The lock is taken on a file next to the PKI directory rather than inside it,
+/// since `pki init` and `pki restore` replace the directory entirely.
+///
+/// Nothing is locked in dry-run mode.
+pub struct PkiLock {
+    file: Option<File>,
+}
+impl PkiLock {
+    /// Acquire the lock, waiting up to `lock-timeout` for another process to
+    /// release it.
+    pub fn acquire(
+        config_dir: impl AsRef<Path>,
+        config: &Config,
+        profile: &Profile,
+        opts: ExecOptions,
+    ) -> color_eyre::Result<Self> {
+        if opts.dry_run {
+            return Ok(Self { file: None });
+        }
+
+        // allow `easy_rsa_pki_dir` to be relative to the config file
+        let pki_dir = config_dir.as_ref().join(&profile.easy_rsa_pki_dir);
+        let path = get_lock_path(&pki_dir)?;
+        let timeout = config
+            .lock_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("Failed to open lock file {path:?}"))?;
+
+        let start = Instant::now();
+        let mut has_waited = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(err)) => {
+                    return Err(err).wrap_err_with(|| format!("Failed to lock {path:?}"))
+                }
+            }
+            let holder = describe_holder(&path);
+            if start.elapsed() >= timeout {
+                bail!(
+                    "The PKI directory {pki_dir:?} is locked by {holder}; gave up after {}s",
+                    timeout.as_secs()
+                );
+            }
+            if !has_waited {
+                info!("Waiting for {holder} to release the lock on {pki_dir:?}");
+                has_waited = true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let holder = LockHolder { pid: process::id(), os_user: os_user(), since: Utc::now() };
+        let content = serde_json::to_string(&holder).wrap_err("Failed to serialise lock holder")?;
+        file.set_len(0)
+            .and_then(|_| writeln!(file, "{content}"))
+            .wrap_err_with(|| format!("Failed to write lock file {path:?}"))?;
+        debug!("Locked {pki_dir:?} via {path:?}");
+
+        Ok(Self { file: Some(file) })
+    }
+}
+impl Drop for PkiLock {
+    fn drop(&mut self) {
+        // so that the next holder is not confused with this process
+        if let Some(ref file) = self.file {
+            let _ = file.set_len(0);
+        }
+    }
+}
+
+/// Get the path of the lock file of a PKI directory.
+fn get_lock_path(pki_dir: &Path) -> color_eyre::Result<PathBuf> {
+    let pki_dir = std::path::absolute(pki_dir)
+        .wrap_err_with(|| format!("Failed to get the absolute path of {pki_dir:?}"))?;
+    let (Some(parent), Some(name)) = (pki_dir.parent(), pki_dir.file_name()) else {
+        bail!("Cannot lock the PKI directory {pki_dir:?}");
+    };
+    let mut lock_name = OsString::from(".");
+    lock_name.push(name);
+    lock_name.push(".lock");
+    Ok(parent.join(lock_name))
+}
+
+/// Describe the process holding a lock, as far as known from the lock file.
+fn describe_holder(path: &Path) -> String {
+    let holder = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<LockHolder>(content.trim()).ok());
+    match holder {
+        // the lock file is left behind if the holder crashed
+        Some(LockHolder { pid, os_user, since }) if Path::new(&format!("/proc/{pid}")).exists() => {
+            let since = since.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+            match os_user {
+                Some(user) => format!(r#"process {pid} of user "{user}" (since {since})"#),
+                None => format!("process {pid} (since {since})"),
+            }
+        }
+        // the holder may not have written it yet, or may not be `ocm`
+        _ => "another process".into(),
+    }
+}
@@ -0,0 +1,77 @@
+use std::{fmt::Write, fs, path::Path};
+
+use color_eyre::eyre::{eyre, Context};
+
+use crate::{
+    action::{shared::get_cert_path, template::TemplateVars},
+    config::{Profile, UnifiedProfile},
+    types::Username,
+};
+
+/// Render a unified `.ovpn` profile for a user, with all credentials inlined.
+///
+/// The user's key is given as content, since it may have been encrypted for packaging.
+pub fn render_unified_profile(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    unified_profile: &UnifiedProfile,
+    username: &Username,
+    key: &str,
+    vars: &TemplateVars,
+) -> color_eyre::Result<String> {
+    let config_dir = config_dir.as_ref();
+    let profile_name = &profile.name;
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+
+    // allow `template` to be relative to the config file
+    let template_path = config_dir.join(&unified_profile.template);
+    let template = fs::read_to_string(&template_path)
+        .wrap_err_with(|| format!("Failed to read profile template {template_path:?}"))?;
+    let mut output = vars
+        .render(&template)
+        .wrap_err_with(|| format!("Failed to render profile template {template_path:?}"))?;
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+
+    let ca_path = pki_dir.join("ca.crt");
+    let cert_path = get_cert_path(config_dir, profile, username).wrap_err_with(|| {
+        format!(
+            r#"Failed to get certificate path for user "{username}" in profile "{profile_name}""#
+        )
+    })?;
+    let key = strip_to_armour(key)
+        .ok_or_else(|| eyre!(r#"The key of user "{username}" does not contain a PEM block"#))?;
+
+    let mut blocks = vec![
+        ("ca", read_armoured(&ca_path)?),
+        ("cert", read_armoured(&cert_path)?),
+        ("key", key.to_owned()),
+    ];
+    // allow `tls_crypt_key` to be relative to the config file
+    if let Some(ref path) = unified_profile.tls_crypt_key {
+        blocks.push(("tls-crypt", read_armoured(&config_dir.join(path))?));
+    }
+    for (tag, content) in blocks {
+        writeln!(output, "<{tag}>\n{}\n</{tag}>", content.trim_end())?;
+    }
+
+    Ok(output)
+}
+
+/// Read a file for inlining, from its first armoured block on.
+fn read_armoured(path: &Path) -> color_eyre::Result<String> {
+    let content = fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {path:?}"))?;
+    let content = strip_to_armour(&content)
+        .ok_or_else(|| eyre!("{path:?} does not contain a PEM or OpenVPN static key block"))?;
+    Ok(content.to_owned())
+}
+
+/// Strip any human-readable preamble before the first armoured block.
+///
+/// easy-rsa prepends a text dump of the certificate to issued certificates,
+/// which is not accepted in inline blocks.
+pub fn strip_to_armour(content: &str) -> Option<&str> {
+    content.find("-----BEGIN ").map(|start| &content[start..])
+}
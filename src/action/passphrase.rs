@@ -0,0 +1,370 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use ring::rand::{SecureRandom, SystemRandom};
+use xshell::{cmd, Shell};
+
+use crate::{
+    action::{shared::read_password_file, ExecOptions},
+    config::Profile,
+    shell::print_dry_run,
+    types::{PassphraseSource, Username},
+};
+
+/// The shortest passphrase that OpenSSL accepts for encrypting keys.
+const MIN_LENGTH: usize = 4;
+
+/// The number of characters in a generated passphrase, i.e. 120 bits.
+const GENERATED_LENGTH: usize = 20;
+
+/// The characters of generated passphrases.
+///
+/// There are 64 of them, so that each is picked uniformly from a random byte.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// The environment variable through which new passphrases are passed to OpenSSL
+/// and easy-rsa, so that they do not show up in process listings.
+pub const PASSOUT_VAR: &str = "OCM_KEY_PASSPHRASE";
+
+/// Same as [`PASSOUT_VAR`], but for the current passphrases of encrypted keys.
+const PASSIN_VAR: &str = "OCM_KEY_CURRENT_PASSPHRASE";
+
+/// Hands out a passphrase for each user from a [`PassphraseSource`].
+///
+/// In dry-run mode, nothing is prompted for or read, and a placeholder is
+/// handed out instead.
+pub struct PassphraseReader {
+    source: PassphraseSource,
+    /// Whether the passphrases are for encrypting keys, rather than decrypting them.
+    is_new: bool,
+    dry_run: bool,
+    /// The passphrase read from a file, which is shared by all users.
+    shared: Option<String>,
+    /// The passphrase generated last, with its user.
+    generated: Option<(Username, String)>,
+}
+impl PassphraseReader {
+    /// Hand out passphrases for encrypting keys.
+    pub fn for_new_keys(source: PassphraseSource, dry_run: bool) -> Self {
+        Self { source, is_new: true, dry_run, shared: None, generated: None }
+    }
+
+    /// Hand out the passphrases of keys that are already encrypted.
+    pub fn for_existing_keys(source: PassphraseSource, dry_run: bool) -> color_eyre::Result<Self> {
+        if source == PassphraseSource::Generate {
+            bail!("The passphrases of existing keys cannot be generated");
+        }
+        Ok(Self {
+            source,
+            is_new: false,
+            dry_run,
+            shared: None,
+            generated: None,
+        })
+    }
+
+    /// Get the passphrase for a user.
+    pub fn next(&mut self, username: &Username) -> color_eyre::Result<String> {
+        let source = &self.source;
+        if self.dry_run {
+            print_dry_run(format!(
+                r#"get passphrase for user "{username}" via "{source}""#
+            ));
+            return Ok("dry-run".into());
+        }
+
+        let passphrase = match source {
+            PassphraseSource::Prompt => prompt(username, self.is_new)?,
+            PassphraseSource::Generate => {
+                let passphrase = generate()?;
+                self.generated = Some((username.clone(), passphrase.clone()));
+                return Ok(passphrase);
+            }
+            PassphraseSource::File(path) => match self.shared {
+                Some(ref passphrase) => passphrase.clone(),
+                None => self.shared.insert(read_password_file(path)?).clone(),
+            },
+            PassphraseSource::Stdin => {
+                let mut line = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .wrap_err("Failed to read passphrase from stdin")?;
+                if line.is_empty() {
+                    bail!(r#"No passphrase left on stdin for user "{username}""#);
+                }
+                trim_line_ending(line)
+            }
+        };
+        if self.is_new && passphrase.chars().count() < MIN_LENGTH {
+            bail!(
+                r#"The passphrase for user "{username}" is shorter than {MIN_LENGTH} characters"#
+            );
+        }
+        Ok(passphrase)
+    }
+
+    /// Take the passphrase generated for a user, if any.
+    ///
+    /// One left over from a user whose steps failed is discarded.
+    pub fn take_generated(&mut self, username: &Username) -> Option<String> {
+        self.generated
+            .take()
+            .filter(|(generated_for, _)| generated_for == username)
+            .map(|(_, passphrase)| passphrase)
+    }
+}
+
+/// Delivers generated passphrases through a channel of their own, so that they
+/// are not mixed into the results printed on stdout.
+///
+/// They are written into a file only readable by its owner if one is given, and
+/// to stderr otherwise, one `USERNAME: PASSPHRASE` line each. Callers deliver a
+/// passphrase as soon as the steps of its user succeed, so that it is not lost
+/// if a later user fails.
+pub struct PassphraseOutput {
+    path: Option<PathBuf>,
+    /// Opened on the first delivery, so that no empty file is left behind.
+    file: Option<File>,
+    force: bool,
+}
+impl PassphraseOutput {
+    pub fn new(path: Option<PathBuf>, opts: ExecOptions) -> color_eyre::Result<Self> {
+        let ExecOptions { force, dry_run, .. } = opts;
+        // fail before any key is encrypted with a passphrase that cannot be delivered
+        if let Some(ref path) = path {
+            if path.exists() && !force {
+                bail!("{path:?} already exists; use `--force` to overwrite it");
+            }
+            if dry_run {
+                print_dry_run(format!("write generated passphrases to {path:?}"));
+            }
+        }
+        Ok(Self { path, file: None, force })
+    }
+
+    /// Deliver the passphrase generated for a user, if any.
+    pub fn deliver(
+        &mut self,
+        username: &Username,
+        passphrase: Option<String>,
+    ) -> color_eyre::Result<()> {
+        let Some(passphrase) = passphrase else {
+            return Ok(());
+        };
+        let line = format!("{username}: {passphrase}");
+        let Some(ref path) = self.path else {
+            eprintln!("{line}");
+            return Ok(());
+        };
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => {
+                let mut options = OpenOptions::new();
+                options.write(true).mode(0o600);
+                match self.force {
+                    true => options.create(true).truncate(true),
+                    false => options.create_new(true),
+                };
+                let file = options
+                    .open(path)
+                    .wrap_err_with(|| format!("Failed to create {path:?} for output"))?;
+                self.file.insert(file)
+            }
+        };
+        writeln!(file, "{line}")
+            .and_then(|_| file.sync_data())
+            .wrap_err_with(|| {
+                format!(r#"Failed to write the passphrase of user "{username}" to {path:?}"#)
+            })
+    }
+}
+
+/// The copy of a user's key that goes into a package.
+pub struct PackagedKey {
+    /// The key, PEM-encoded.
+    pub pem: String,
+    /// The passphrase of the key, if it is encrypted and the passphrase is known.
+    pub passphrase: Option<String>,
+}
+
+/// The passphrases for protecting the copies of users' keys in their packages.
+pub struct PackagePassphrases {
+    /// Where to take new passphrases from, if packaged keys are to be protected.
+    new: Option<PassphraseReader>,
+    /// Whether a new passphrase was requested, rather than required by the profile.
+    is_requested: bool,
+    /// Where to take the passphrases of keys that are already protected from.
+    current: Option<PassphraseReader>,
+    dry_run: bool,
+}
+impl PackagePassphrases {
+    pub fn new(
+        profile: &Profile,
+        passphrase: Option<&PassphraseSource>,
+        current_passphrase: Option<&PassphraseSource>,
+        dry_run: bool,
+    ) -> color_eyre::Result<Self> {
+        let new = profile
+            .passphrase_source(passphrase)?
+            .map(|source| PassphraseReader::for_new_keys(source, dry_run));
+        let current = current_passphrase
+            .map(|source| PassphraseReader::for_existing_keys(source.clone(), dry_run))
+            .transpose()?;
+        Ok(Self { new, is_requested: passphrase.is_some(), current, dry_run })
+    }
+
+    /// Read the key of a user for packaging, encrypting it with a new passphrase
+    /// if needed.
+    ///
+    /// Keys that are already protected are packaged as they are, unless a new
+    /// passphrase is requested explicitly.
+    pub fn read_key(
+        &mut self,
+        key_path: &Path,
+        username: &Username,
+    ) -> color_eyre::Result<PackagedKey> {
+        let pem = fs::read_to_string(key_path)
+            .wrap_err_with(|| format!("Failed to read key {key_path:?}"))?;
+        let is_protected = is_encrypted(&pem);
+        let current = match self.current {
+            Some(ref mut current) if is_protected => Some(current.next(username)?),
+            _ => None,
+        };
+        let new = match self.new {
+            Some(ref mut new) if !is_protected || self.is_requested => new,
+            _ => return Ok(PackagedKey { pem, passphrase: current }),
+        };
+
+        if is_protected && current.is_none() {
+            bail!(
+                r#"The key of user "{username}" is already passphrase-protected; use `--current-passphrase` to re-encrypt it"#
+            );
+        }
+        let passphrase = new.next(username)?;
+        if self.dry_run {
+            print_dry_run(format!(r#"encrypt the packaged key of user "{username}""#));
+            return Ok(PackagedKey { pem, passphrase: Some(passphrase) });
+        }
+        let pem = encrypt_key(&pem, &passphrase, current.as_deref())
+            .wrap_err_with(|| format!(r#"Failed to encrypt the key of user "{username}""#))?;
+        Ok(PackagedKey { pem, passphrase: Some(passphrase) })
+    }
+
+    /// Same as [`PassphraseReader::take_generated`].
+    pub fn take_generated(&mut self, username: &Username) -> Option<String> {
+        self.new
+            .as_mut()
+            .and_then(|new| new.take_generated(username))
+    }
+}
+
+/// Whether a PEM-encoded private key is encrypted, in either the PKCS#8 or the
+/// legacy OpenSSL format.
+pub fn is_encrypted(pem: &str) -> bool {
+    pem.contains("ENCRYPTED")
+}
+
+/// Encrypt a PEM-encoded private key with a passphrase, using OpenSSL.
+///
+/// If the key is already encrypted, its current passphrase has to be given.
+pub fn encrypt_key(
+    pem: &str,
+    passphrase: &str,
+    current_passphrase: Option<&str>,
+) -> color_eyre::Result<String> {
+    // otherwise OpenSSL prompts for it
+    if is_encrypted(pem) && current_passphrase.is_none() {
+        bail!("The key is already passphrase-protected");
+    }
+
+    let sh = Shell::new().wrap_err("Failed to create subshell")?;
+    let passout = format!("env:{PASSOUT_VAR}");
+    let passin_args = match current_passphrase {
+        Some(_) => vec!["-passin".to_owned(), format!("env:{PASSIN_VAR}")],
+        None => vec![],
+    };
+    let mut cmd = cmd!(
+        sh,
+        "openssl pkey -aes256 -passout {passout} {passin_args...}"
+    )
+    .env(PASSOUT_VAR, passphrase)
+    .stdin(pem);
+    if let Some(current_passphrase) = current_passphrase {
+        cmd = cmd.env(PASSIN_VAR, current_passphrase);
+    }
+    cmd.read()
+        .wrap_err("Key encryption command failed to execute")
+}
+
+/// Generate a random passphrase.
+fn generate() -> color_eyre::Result<String> {
+    let mut bytes = [0; GENERATED_LENGTH];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| eyre!("Failed to generate random passphrase"))?;
+    Ok(bytes
+        .iter()
+        .map(|b| ALPHABET[(b & 0x3F) as usize] as char)
+        .collect())
+}
+
+/// Prompt for the passphrase of a user on the terminal, asking twice for new ones.
+fn prompt(username: &Username, is_new: bool) -> color_eyre::Result<String> {
+    let label = if is_new { "New" } else { "Current" };
+    let passphrase = prompt_hidden(&format!(
+        r#"{label} passphrase for the key of user "{username}": "#
+    ))?;
+    if is_new {
+        let repeated = prompt_hidden(&format!(r#"Repeat the passphrase for user "{username}": "#))?;
+        if repeated != passphrase {
+            bail!(r#"The passphrases for user "{username}" do not match"#);
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Read a line from the terminal without echoing it.
+fn prompt_hidden(prompt: &str) -> color_eyre::Result<String> {
+    const TTY_PATH: &str = "/dev/tty";
+
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TTY_PATH)
+        .wrap_err("Cannot prompt for a passphrase without a terminal")?;
+    // stty acts on its stdin, which xshell cannot attach to the terminal
+    let set_echo = |arg: &str| -> color_eyre::Result<()> {
+        let stdin = tty.try_clone()?;
+        let status = Command::new("stty").arg(arg).stdin(stdin).status()?;
+        if !status.success() {
+            bail!("`stty {arg}` exited with {status}");
+        }
+        Ok(())
+    };
+
+    set_echo("-echo").wrap_err("Failed to turn off echo on the terminal")?;
+    let mut line = String::new();
+    let read = write!(&tty, "{prompt}")
+        .and_then(|_| (&tty).flush())
+        .and_then(|_| BufReader::new(&tty).read_line(&mut line));
+    // restore echo even if reading failed
+    let restored = set_echo("echo");
+    let _ = writeln!(&tty);
+
+    read.wrap_err("Failed to read passphrase from the terminal")?;
+    restored.wrap_err("Failed to turn on echo on the terminal")?;
+    Ok(trim_line_ending(line))
+}
+
+fn trim_line_ending(mut line: String) -> String {
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    line
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::Context;
+use log::{debug, error, warn};
+use temp_dir::TempDir;
+
+use crate::{
+    action::{
+        audit::{AuditLog, AuditOperation},
+        ExecOptions,
+    },
+    config::Profile,
+    metadata::MetadataStore,
+    pki::read_index,
+    types::Username,
+};
+
+/// The entries of a PKI directory that are modified when issuing, renewing and
+/// revoking certificates.
+const MUTABLE_ENTRIES: &[&str] = &[
+    "index.txt",
+    "index.txt.attr",
+    "index.txt.old",
+    "index.txt.attr.old",
+    "serial",
+    "serial.old",
+    "crlnumber",
+    "crl.pem",
+    "issued",
+    "private",
+    "reqs",
+    "revoked",
+    "renewed",
+    "certs_by_serial",
+    "inline",
+];
+
+/// A snapshot of the mutable parts of a PKI directory, and of the metadata and
+/// CCD files of the affected users, which is restored when dropped unless
+/// committed.
+///
+/// Nothing is snapshotted in dry-run mode or with `--no-rollback`.
+pub struct Rollback<'a> {
+    snapshot: Option<Snapshot>,
+    audit: AuditLog<'a>,
+}
+impl<'a> Rollback<'a> {
+    /// Take a snapshot of the whole PKI directory of a profile, and of the
+    /// metadata and CCD files of the users about to be changed.
+    pub fn begin(
+        config_dir: &'a Path,
+        profile: &'a Profile,
+        usernames: &[&Username],
+        opts: ExecOptions,
+    ) -> color_eyre::Result<Self> {
+        let usernames = usernames.iter().map(|&u| u.clone()).collect();
+        Self::take(config_dir, profile, usernames, opts, |_| true)
+    }
+
+    /// Same as [`Rollback::begin`] for a single user, but only the files of the
+    /// PKI directory that belong to the user are copied.
+    ///
+    /// Those are the PKI database and the files named after the user or one of
+    /// their serials. Anything else added since is still removed on rollback.
+    pub fn begin_for_user(
+        config_dir: &'a Path,
+        profile: &'a Profile,
+        username: &Username,
+        opts: ExecOptions,
+    ) -> color_eyre::Result<Self> {
+        if opts.dry_run || opts.no_rollback {
+            return Ok(Self::disabled(config_dir, profile, opts));
+        }
+
+        // allow `easy_rsa_pki_dir` to be relative to the config file
+        let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+        let mut stems = read_index(&pki_dir)?
+            .into_iter()
+            .filter(|r| r.common_name() == Some(username.as_str()))
+            .map(|r| r.serial)
+            .collect::<BTreeSet<_>>();
+        stems.insert(username.to_string());
+
+        let is_related = |path: &Path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .is_some_and(|stem| stems.contains(stem))
+        };
+        Self::take(
+            config_dir,
+            profile,
+            vec![username.clone()],
+            opts,
+            is_related,
+        )
+    }
+
+    /// Keep the changes made since the snapshot was taken.
+    pub fn commit(mut self) {
+        self.snapshot = None;
+    }
+
+    fn disabled(config_dir: &'a Path, profile: &'a Profile, opts: ExecOptions) -> Self {
+        Self {
+            snapshot: None,
+            audit: AuditLog::new(config_dir, profile, opts),
+        }
+    }
+
+    fn take(
+        config_dir: &'a Path,
+        profile: &'a Profile,
+        usernames: Vec<Username>,
+        opts: ExecOptions,
+        is_related: impl Fn(&Path) -> bool,
+    ) -> color_eyre::Result<Self> {
+        if opts.dry_run || opts.no_rollback {
+            return Ok(Self::disabled(config_dir, profile, opts));
+        }
+
+        // allow `easy_rsa_pki_dir` to be relative to the config file
+        let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+        let mut extras = vec![MetadataStore::path_for(config_dir, profile)];
+        if let Some(ref ccd_dir) = profile.ccd_dir {
+            // allow `ccd_dir` to be relative to the config file
+            let ccd_dir = config_dir.join(ccd_dir);
+            extras.extend(usernames.iter().map(|u| ccd_dir.join(u)));
+        }
+
+        let snapshot = Snapshot::take(pki_dir, extras, usernames, is_related)
+            .wrap_err("Failed to take a snapshot of the PKI")?;
+        Ok(Self {
+            snapshot: Some(snapshot),
+            audit: AuditLog::new(config_dir, profile, opts),
+        })
+    }
+}
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        let Some(mut snapshot) = self.snapshot.take() else {
+            return;
+        };
+        let usernames = std::mem::take(&mut snapshot.usernames);
+        let pki_dir = snapshot.pki_dir.clone();
+        let snapshot_dir = snapshot.temp_dir.path().to_owned();
+        if let Err(err) = snapshot.restore() {
+            error!("Failed to roll back changes to the PKI {pki_dir:?}; a copy of its previous state is kept in {snapshot_dir:?}: {err:?}");
+            return;
+        }
+        warn!("Rolled back the changes to the PKI {pki_dir:?}");
+
+        let recorded = match usernames.is_empty() {
+            true => self
+                .audit
+                .record(AuditOperation::Rollback, None, None, None),
+            false => usernames.iter().try_for_each(|username| {
+                self.audit
+                    .record(AuditOperation::Rollback, Some(username), None, None)
+            }),
+        };
+        if let Err(err) = recorded {
+            warn!("Failed to record the rollback in the audit log: {err:?}");
+        }
+    }
+}
+
+/// The copied files and the layout of a PKI directory at some point.
+struct Snapshot {
+    pki_dir: PathBuf,
+    temp_dir: TempDir,
+    /// Every entry in the mutable parts of the PKI, relative to it, with the
+    /// modes of directories.
+    listing: BTreeMap<PathBuf, Option<u32>>,
+    /// The files of the PKI that were copied, relative to it.
+    copied: Vec<PathBuf>,
+    /// Files outside of the PKI, and whether they existed.
+    extras: Vec<(PathBuf, bool)>,
+    usernames: Vec<Username>,
+}
+impl Snapshot {
+    /// Copy the files of the PKI that are at its top level or related, and the
+    /// extra files.
+    fn take(
+        pki_dir: PathBuf,
+        extras: Vec<PathBuf>,
+        usernames: Vec<Username>,
+        is_related: impl Fn(&Path) -> bool,
+    ) -> color_eyre::Result<Self> {
+        let temp_dir = TempDir::with_prefix("openvpn-cred-management-snapshot-")
+            .wrap_err("Cannot create temporary directory for a PKI snapshot")?;
+        let snapshot_dir = temp_dir.path();
+        // the snapshot contains private keys
+        fs::set_permissions(snapshot_dir, fs::Permissions::from_mode(0o700))
+            .wrap_err_with(|| format!("Failed to restrict permissions of {snapshot_dir:?}"))?;
+
+        let listing = list_entries(&pki_dir)?;
+        let mut copied = vec![];
+        for (path, mode) in &listing {
+            let is_top_level = path.components().count() == 1;
+            if mode.is_none() && (is_top_level || is_related(path)) {
+                copy_entry(&pki_dir.join(path), &snapshot_dir.join("pki").join(path))?;
+                copied.push(path.clone());
+            }
+        }
+        let extras = extras
+            .into_iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let exists = path.symlink_metadata().is_ok();
+                if exists {
+                    copy_entry(&path, &snapshot_dir.join("extra").join(i.to_string()))?;
+                }
+                Ok((path, exists))
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        debug!(
+            "Took a snapshot of {} files of {pki_dir:?} in {snapshot_dir:?}",
+            copied.len()
+        );
+
+        Ok(Self { pki_dir, temp_dir, listing, copied, extras, usernames })
+    }
+
+    /// Restore the PKI and the extra files, keeping the copies if that fails.
+    fn restore(self) -> color_eyre::Result<()> {
+        let Self { pki_dir, temp_dir, listing, copied, extras, .. } = self;
+        let snapshot_dir = temp_dir.path();
+        let restore = || -> color_eyre::Result<()> {
+            // remove anything added since
+            for path in list_entries(&pki_dir)?.into_keys() {
+                if !listing.contains_key(&path) {
+                    remove_entry(&pki_dir.join(path))?;
+                }
+            }
+            // parents are listed before their children
+            for (path, mode) in &listing {
+                let Some(mode) = mode else {
+                    continue;
+                };
+                let path = pki_dir.join(path);
+                fs::create_dir_all(&path)
+                    .and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(*mode)))
+                    .wrap_err_with(|| format!("Failed to restore directory {path:?}"))?;
+            }
+            for path in &copied {
+                let target = pki_dir.join(path);
+                remove_entry(&target)?;
+                copy_entry(&snapshot_dir.join("pki").join(path), &target)?;
+            }
+            for (i, (path, existed)) in extras.iter().enumerate() {
+                remove_entry(path)?;
+                if *existed {
+                    copy_entry(&snapshot_dir.join("extra").join(i.to_string()), path)?;
+                }
+            }
+            Ok(())
+        };
+        let restored = restore();
+        if restored.is_err() {
+            temp_dir.leak();
+        }
+        restored
+    }
+}
+
+/// List the mutable entries of a PKI directory recursively, relative to it, with
+/// the modes of directories.
+fn list_entries(pki_dir: &Path) -> color_eyre::Result<BTreeMap<PathBuf, Option<u32>>> {
+    fn visit(
+        pki_dir: &Path,
+        path: PathBuf,
+        listing: &mut BTreeMap<PathBuf, Option<u32>>,
+    ) -> color_eyre::Result<()> {
+        let full_path = pki_dir.join(&path);
+        let metadata = match full_path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Failed to inspect {full_path:?}"))
+            }
+        };
+        if !metadata.is_dir() {
+            listing.insert(path, None);
+            return Ok(());
+        }
+        listing.insert(path.clone(), Some(metadata.permissions().mode()));
+        let entries = fs::read_dir(&full_path)
+            .wrap_err_with(|| format!("Failed to read directory {full_path:?}"))?;
+        for entry in entries {
+            let entry =
+                entry.wrap_err_with(|| format!("Failed to read directory {full_path:?}"))?;
+            visit(pki_dir, path.join(entry.file_name()), listing)?;
+        }
+        Ok(())
+    }
+
+    let mut listing = BTreeMap::new();
+    for entry in MUTABLE_ENTRIES {
+        visit(pki_dir, entry.into(), &mut listing)?;
+    }
+    Ok(listing)
+}
+
+/// Copy a file or symlink, creating the parent directories of the target.
+fn copy_entry(source: &Path, target: &Path) -> color_eyre::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+    }
+    let copied = match fs::read_link(source) {
+        Ok(link) => symlink(link, target),
+        // permissions are copied along
+        Err(_) => fs::copy(source, target).map(|_| ()),
+    };
+    copied.wrap_err_with(|| format!("Failed to copy {source:?} to {target:?}"))
+}
+
+/// Remove a file or directory, if it exists.
+fn remove_entry(path: &Path) -> color_eyre::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {
+            fs::remove_dir_all(path).wrap_err_with(|| format!("Failed to remove {path:?}"))
+        }
+        Ok(_) => fs::remove_file(path).wrap_err_with(|| format!("Failed to remove {path:?}")),
+        Err(_) => Ok(()),
+    }
+}
@@ -1,20 +1,16 @@
 use std::{
-    borrow::Cow,
-    collections::BTreeSet,
-    ffi::{OsStr, OsString},
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
-    sync::LazyLock,
 };
 
 use chrono::{DateTime, Duration, NaiveDate, Utc};
-use color_eyre::eyre::{eyre, Context};
-use log::{debug, trace, warn};
-use regex::Regex;
-use xshell::{cmd, Shell};
+use color_eyre::eyre::{bail, eyre, Context};
+use log::warn;
 
 use crate::{
-    config::{Config, Profile},
+    config::Profile,
+    pki::{read_index, IndexRecord},
     types::Username,
 };
 
@@ -31,139 +27,116 @@ pub fn get_max_days() -> i64 {
     (TARGET_DATE - Utc::now()).num_days()
 }
 
-pub fn get_users(
-    config_dir: impl AsRef<Path>,
-    profile: &Profile,
-) -> color_eyre::Result<Vec<Username>> {
-    fn list_names(dir: impl AsRef<Path>) -> color_eyre::Result<BTreeSet<OsString>> {
-        let dir = dir.as_ref();
-        let names = fs::read_dir(dir)
-            .wrap_err_with(|| format!("Failed to read {dir:?}"))?
-            .filter_map(|de| {
-                de.inspect_err(|e| {
-                    warn!("Failed to read a file in {dir:?}; the user list may be incomplete");
-                    warn!("{e}");
-                })
-                .ok()
-            })
-            .filter_map(|de| {
-                let path = de.path();
-                if !path.is_file() {
-                    warn!("{path:?} is not a regular file; ignoring");
-                    return None;
-                }
-                match path.file_stem() {
-                    Some(stem) => Some(stem.to_owned()),
-                    None => {
-                        warn!("{path:?} does not have a file stem; ignoring");
-                        None
-                    }
-                }
-            })
-            .collect();
-        Ok(names)
+/// Read a password from the first line of a file.
+pub fn read_password_file(path: &Path) -> color_eyre::Result<String> {
+    let content = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read password file {path:?}"))?;
+    let password = content.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        bail!("The password file {path:?} is empty");
     }
+    Ok(password.to_owned())
+}
 
+/// Get all records in the PKI database of a profile.
+pub fn get_index_records(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+) -> color_eyre::Result<Vec<IndexRecord>> {
     // allow `easy_rsa_pki_dir` to be relative to the config file
     let pki_dir = config_dir.as_ref().join(&profile.easy_rsa_pki_dir);
+    read_index(&pki_dir).wrap_err_with(|| format!("Cannot read the PKI database in {pki_dir:?}"))
+}
+
+/// Get the current certificate of every user with at least one unrevoked certificate.
+///
+/// A user may hold several unrevoked certificates (e.g. renewed with `--keep-old`),
+/// in which case the one that expires last is considered current.
+pub fn get_current_records(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+) -> color_eyre::Result<BTreeMap<Username, IndexRecord>> {
+    let config_dir = config_dir.as_ref();
+    // allow `easy_rsa_pki_dir` to be relative to the config file
+    let pki_dir = config_dir.join(&profile.easy_rsa_pki_dir);
+
+    let mut current = BTreeMap::<String, IndexRecord>::new();
+    for record in get_index_records(config_dir, profile)? {
+        if !record.is_active() {
+            continue;
+        }
+        let Some(name) = record.common_name() else {
+            warn!(
+                "Certificate {} does not have a common name; ignoring",
+                record.serial
+            );
+            continue;
+        };
+        match current.get(name) {
+            Some(existing) if existing.expiry >= record.expiry => {}
+            _ => {
+                current.insert(name.to_owned(), record);
+            }
+        }
+    }
 
-    // list all certificates
-    let cert_dir = pki_dir.join("issued");
-    let cert_names = list_names(&cert_dir)
-        .wrap_err_with(|| format!("Cannot read certificate directory {cert_dir:?}"))?;
-
-    // list all keys
-    let key_dir = pki_dir.join("private");
-    let key_names = {
-        let mut names = list_names(&key_dir)
-            .wrap_err_with(|| format!("Cannot read key directory {key_dir:?}"))?;
-        names.remove(OsStr::new("ca")); // filter out the CA's key
-        names
-    };
-
-    // warn about difference
-    cert_names
-        .difference(&key_names)
-        .for_each(|n| warn!("User {n:?} seems to have a certificate but no key"));
-    key_names
-        .difference(&cert_names)
-        .for_each(|n| warn!("User {n:?} seems to have a key but no certificate"));
+    // warn about missing files
+    for name in current.keys() {
+        let cert_path = pki_dir.join("issued").join(format!("{name}.crt"));
+        if !cert_path.is_file() {
+            warn!(r#"User "{name}" seems to have no certificate file at {cert_path:?}"#);
+        }
+        let key_path = pki_dir.join("private").join(format!("{name}.key"));
+        if !key_path.is_file() {
+            warn!(r#"User "{name}" seems to have no key file at {key_path:?}"#);
+        }
+    }
 
     // build output
-    let output = cert_names
-        .union(&key_names)
-        .filter_map(|n| {
-            let s = n.to_string_lossy();
-            if let Cow::Owned(_) = s {
-                warn!("User {n:?} seems to have a non-UTF8 name");
-            }
-            s.parse::<Username>()
-                .inspect_err(|err| warn!("The username {s:?} failed parsing; ignoring: {err:?}"))
-                .ok()
+    let output = current
+        .into_iter()
+        .filter(|(name, _)| {
+            // e.g. the server's certificate
+            !profile.is_reserved_name(name)
+        })
+        .filter_map(|(name, record)| {
+            let username = name
+                .parse::<Username>()
+                .and_then(|username| {
+                    profile.check_username(&username)?;
+                    Ok(username)
+                })
+                .inspect_err(|err| warn!(r#"The username "{name}" is invalid; ignoring: {err:#}"#))
+                .ok()?;
+            Some((username, record))
         })
         .collect();
 
     Ok(output)
 }
 
-pub fn get_expired_users(
+/// Get all users with at least one unrevoked certificate.
+pub fn get_users(
     config_dir: impl AsRef<Path>,
-    config: &Config,
     profile: &Profile,
-    near_expiry_period: Duration,
 ) -> color_eyre::Result<Vec<Username>> {
-    let easy_rsa = &config.easy_rsa_path;
-    // allow `easy_rsa_pki_dir` to be relative to the config file
-    let pki_dir = config_dir.as_ref().join(&profile.easy_rsa_pki_dir);
-    let days_arg = format!("--days={}", get_max_days());
-
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
-    let show_expire_output = cmd!(sh, "{easy_rsa} --pki-dir={pki_dir} {days_arg} show-expire")
-        .read()
-        .wrap_err("List expired command failed to execute")?;
-    debug!("`easy-rsa show-expire` output: {show_expire_output}");
-
-    // easy-rsa's output format of each line that describes a certificate
-    static LINE_MATCHER: LazyLock<Regex> = LazyLock::new(|| {
-        Regex::new(
-            r"^V \| Serial: (?<serial>[\dA-F]+) \| (Expire(s|d): )?(?<date>[\d\-]+) (?<time>[\d:Z+\-]+) \| CN: (?<name>[^\s]+)$",
-        )
-        .unwrap()
-    });
-    let now = Utc::now();
-
-    let expired = show_expire_output
-        .lines()
-        .filter_map(|line| {
-            let Some(captures) = LINE_MATCHER.captures(line) else {
-                trace!("`{line}` does not look like a certificate line");
-                return None;
-            };
-
-            let name = {
-                let raw = captures.name("name").unwrap().as_str(); // capture always exists
-                raw.parse::<Username>().inspect_err(|err| {
-                    warn!(r#"The username "{raw}" failed parsing; ignoring: {err:?}"#)
-                })
-            }
-            .ok()?;
-
-            let expiry = {
-                let date = captures.name("date").unwrap().as_str(); // capture always exists
-                let time = captures.name("time").unwrap().as_str(); // capture always exists
-                DateTime::parse_from_rfc3339(&format!("{date}T{time}")).inspect_err(|_| {
-                    warn!(
-                        "easy-rsa reported expiry time of `{name}` \
-                        in an unexpected format: `{date} {time}`"
-                    )
-                })
-            }
-            .ok()?;
-
-            (now + near_expiry_period > expiry).then_some(name)
-        })
+    let users = get_current_records(config_dir, profile)?
+        .into_keys()
         .collect();
+    Ok(users)
+}
 
+/// Get all users whose current certificate expires within `near_expiry_period`.
+pub fn get_expired_users(
+    config_dir: impl AsRef<Path>,
+    profile: &Profile,
+    near_expiry_period: Duration,
+) -> color_eyre::Result<BTreeMap<Username, IndexRecord>> {
+    let threshold = Utc::now() + near_expiry_period;
+    let expired = get_current_records(config_dir, profile)?
+        .into_iter()
+        .filter(|(_, record)| record.expiry < threshold)
+        .collect();
     Ok(expired)
 }
 
@@ -194,28 +167,3 @@ pub fn get_key_path(
         .then_some(path)
         .ok_or_else(|| eyre!(r#"Cannot find a key for user "{username}""#))
 }
-
-pub fn regenerate_crl(
-    config_dir: impl AsRef<Path>,
-    config: &Config,
-    profile: &Profile,
-    force: bool,
-) -> color_eyre::Result<()> {
-    let easy_rsa = &config.easy_rsa_path;
-    let force_arg = force.then_some("--batch");
-    // allow `easy_rsa_pki_dir` to be relative to the config file
-    let pki_dir = config_dir.as_ref().join(&profile.easy_rsa_pki_dir);
-    // an expired CRL causes all clients to be rejected
-    // this CRL is self-managed anyways, so we set it to practically-unlimited
-    let days_arg = format!("--days={}", get_max_days());
-
-    let sh = Shell::new().wrap_err("Failed to create subshell")?;
-    cmd!(
-        sh,
-        "{easy_rsa} {force_arg...} --pki-dir={pki_dir} {days_arg} gen-crl"
-    )
-    .run_interactive()
-    .wrap_err("CRL regenerate command failed to execute")?;
-
-    Ok(())
-}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::{bail, eyre};
+
+use crate::{config::Profile, pki::IndexRecord, types::Username};
+
+/// Variables available to templates, by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TemplateVars(BTreeMap<String, String>);
+impl TemplateVars {
+    /// Names of the variables that are always defined when packaging for a user.
+    pub const BUILTIN: [&str; 4] = ["username", "profile", "serial", "expiry"];
+
+    /// Create the set of variables for packaging a user.
+    ///
+    /// Custom variables from the profile's packaging settings are included,
+    /// but may not shadow the built-in ones.
+    pub fn for_user(
+        profile: &Profile,
+        username: &Username,
+        record: &IndexRecord,
+    ) -> color_eyre::Result<Self> {
+        let custom = profile
+            .packaging
+            .as_ref()
+            .map(|p| p.template_variables.clone())
+            .unwrap_or_default();
+        if let Some(name) = custom.keys().find(|k| Self::BUILTIN.contains(&k.as_str())) {
+            bail!(r#"The custom template variable "{name}" shadows a built-in variable"#);
+        }
+
+        let mut vars = custom;
+        vars.insert("username".into(), username.to_string());
+        vars.insert("profile".into(), profile.name.clone());
+        vars.insert("serial".into(), record.serial.clone());
+        vars.insert("expiry".into(), record.expiry.to_rfc3339());
+        Ok(Self(vars))
+    }
+
+    /// Substitute all `{{ name }}` placeholders in a template.
+    ///
+    /// Referencing an undefined variable is an error.
+    pub fn render(&self, template: &str) -> color_eyre::Result<String> {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            output.push_str(&rest[..start]);
+            let after_open = &rest[start + 2..];
+            let end = after_open
+                .find("}}")
+                .ok_or_else(|| eyre!("Unclosed placeholder in template"))?;
+            let name = after_open[..end].trim();
+            let value = self
+                .0
+                .get(name)
+                .ok_or_else(|| eyre!(r#"Template references an undefined variable "{name}""#))?;
+            output.push_str(value);
+            rest = &after_open[end + 2..];
+        }
+        output.push_str(rest);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars_with(packaging: &str) -> color_eyre::Result<TemplateVars> {
+        let profile = toml_edit::de::from_str::<Profile>(&format!(
+            "name = \"test\"\neasy-rsa-pki-dir = \"pki\"\n[packaging]\n{packaging}"
+        ))?;
+        let record = "V\t271231000000Z\t\t9F1234\tunknown\t/CN=alice".parse::<IndexRecord>()?;
+        TemplateVars::for_user(&profile, &"alice".parse()?, &record)
+    }
+
+    #[test]
+    fn render_builtin_and_custom_variables() -> color_eyre::Result<()> {
+        let vars = vars_with("template-variables = { server = \"vpn.example.com\" }")?;
+        let rendered = vars.render("{{username}}@{{profile}} {{serial}} {{expiry}} {{server}}")?;
+        assert_eq!(
+            rendered,
+            "alice@test 9F1234 2027-12-31T00:00:00+00:00 vpn.example.com"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_trims_whitespace_in_placeholders() -> color_eyre::Result<()> {
+        let vars = vars_with("")?;
+        assert_eq!(
+            vars.render("remote {{ username }}.{{\tprofile  }}")?,
+            "remote alice.test"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_leaves_plain_text_alone() -> color_eyre::Result<()> {
+        let vars = vars_with("")?;
+        let template = "no placeholders, just {braces} and }} closing ones\n";
+        assert_eq!(vars.render(template)?, template);
+        // substituted values are not rendered again
+        let vars = vars_with("template-variables = { nested = \"{{username}}\" }")?;
+        assert_eq!(vars.render("{{nested}}")?, "{{username}}");
+        Ok(())
+    }
+
+    #[test]
+    fn render_rejects_undefined_variables() -> color_eyre::Result<()> {
+        let err = vars_with("")?
+            .render("{{ username }} {{ missing }}")
+            .unwrap_err();
+        assert!(err.to_string().contains(r#""missing""#), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn render_rejects_unclosed_placeholders() -> color_eyre::Result<()> {
+        assert!(vars_with("")?.render("{{username}} {{ profile").is_err());
+        assert!(vars_with("")?.render("{{username}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn custom_variables_cannot_shadow_builtin_ones() {
+        assert!(vars_with("template-variables = { serial = \"1\" }").is_err());
+    }
+}
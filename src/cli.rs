@@ -1,11 +1,17 @@
-use std::path::PathBuf;
+use std::{net::Ipv4Addr, path::PathBuf};
 
-use chrono::Duration;
-use clap::{Parser, Subcommand, ValueHint};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueHint};
 use clap_complete::Shell;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use color_eyre::eyre::{eyre, Context};
+use ipnet::Ipv4Net;
 
-use crate::types::Username;
+use crate::{
+    metadata::MetadataChanges,
+    output::OutputFormat,
+    types::{ExportFormat, PassphraseSource, Username},
+};
 
 #[derive(Clone, Debug, Parser)]
 #[command(author, about, version)]
@@ -27,10 +33,49 @@ pub struct CliArgs {
     #[arg(short = 'f', long = "force", global = true)]
     pub force: bool,
 
+    /// Print the easy-rsa commands, scripts and file writes instead of executing them.
+    #[arg(long = "dry-run", global = true)]
+    pub dry_run: bool,
+
+    /// When acting on several users, carry on past users that fail.
+    ///
+    /// A summary of the outcome for every user is printed at the end, and the
+    /// exit status is non-zero if any of them failed.
+    #[arg(long = "keep-going", global = true)]
+    pub keep_going: bool,
+
+    /// Do not restore the PKI when an action fails.
+    ///
+    /// By default, the PKI is snapshotted before issuing, renewing or revoking
+    /// certificates, and restored if any step fails.
+    #[arg(long = "no-rollback", global = true)]
+    pub no_rollback: bool,
+
+    /// Do not run pre-action scripts.
+    #[arg(long = "no-pre-action-scripts", global = true)]
+    pub no_pre_action_scripts: bool,
+
     /// Do not run post-action scripts.
     #[arg(long = "no-post-action-scripts", global = true)]
     pub no_post_action_scripts: bool,
 
+    /// Write generated passphrases into this file, instead of to stderr.
+    ///
+    /// The file is only readable by its owner. Each passphrase is written as a
+    /// `USERNAME: PASSPHRASE` line as soon as its user has been processed.
+    #[arg(long = "passphrase-output", value_name = "PATH", value_hint = ValueHint::FilePath, global = true)]
+    pub passphrase_output: Option<PathBuf>,
+
+    /// The format in which to print query results.
+    #[arg(
+        long = "output",
+        value_name = "FORMAT",
+        default_value_t,
+        value_enum,
+        global = true
+    )]
+    pub output_format: OutputFormat,
+
     #[command(subcommand)]
     pub action: Action,
 
@@ -58,6 +103,88 @@ pub enum Action {
         #[command(subcommand)]
         action: UserAction,
     },
+
+    /// Operations on the PKI of a profile.
+    Pki {
+        #[command(subcommand)]
+        action: PkiAction,
+    },
+
+    /// Query the audit log of credential operations.
+    ///
+    /// The profile must have "audit-log" set.
+    Log {
+        /// Only show entries for these users.
+        #[arg(index = 1, value_name = "NAME")]
+        usernames: Vec<Username>,
+
+        /// Only show entries from this time on.
+        ///
+        /// Either an RFC 3339 timestamp, a local date like "2024-01-31",
+        /// or a duration before now like "7d".
+        #[arg(long = "since", value_name = "TIME", value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only show entries before this time, in the same formats as `--since`.
+        #[arg(long = "until", value_name = "TIME", value_parser = parse_time)]
+        until: Option<DateTime<Utc>>,
+    },
+}
+
+impl Action {
+    /// Get the usernames explicitly specified for this action.
+    pub fn usernames(&self) -> &[Username] {
+        match self {
+            Self::Gen { .. } | Self::Profile { .. } | Self::Pki { .. } => &[],
+            Self::Log { usernames, .. } => usernames,
+            Self::User { action } => match action {
+                UserAction::List { .. } | UserAction::Import { .. } => &[],
+                UserAction::Info { usernames }
+                | UserAction::Online { usernames, .. }
+                | UserAction::New { usernames, .. }
+                | UserAction::Edit { usernames, .. }
+                | UserAction::Renew { usernames, .. }
+                | UserAction::Remove { usernames }
+                | UserAction::Kick { usernames }
+                | UserAction::Package { usernames, .. }
+                | UserAction::Export { usernames, .. } => usernames,
+                UserAction::History { username, .. } => std::slice::from_ref(username),
+                UserAction::Ccd { action } => match action {
+                    CcdAction::SetIp { username, .. }
+                    | CcdAction::AddRoute { username, .. }
+                    | CcdAction::Push { username, .. }
+                    | CcdAction::Show { username } => std::slice::from_ref(username),
+                },
+            },
+        }
+    }
+
+    /// Same as [`Action::usernames`], but mutable for normalisation.
+    pub fn usernames_mut(&mut self) -> &mut [Username] {
+        match self {
+            Self::Gen { .. } | Self::Profile { .. } | Self::Pki { .. } => &mut [],
+            Self::Log { usernames, .. } => usernames,
+            Self::User { action } => match action {
+                UserAction::List { .. } | UserAction::Import { .. } => &mut [],
+                UserAction::Info { usernames }
+                | UserAction::Online { usernames, .. }
+                | UserAction::New { usernames, .. }
+                | UserAction::Edit { usernames, .. }
+                | UserAction::Renew { usernames, .. }
+                | UserAction::Remove { usernames }
+                | UserAction::Kick { usernames }
+                | UserAction::Package { usernames, .. }
+                | UserAction::Export { usernames, .. } => usernames,
+                UserAction::History { username, .. } => std::slice::from_mut(username),
+                UserAction::Ccd { action } => match action {
+                    CcdAction::SetIp { username, .. }
+                    | CcdAction::AddRoute { username, .. }
+                    | CcdAction::Push { username, .. }
+                    | CcdAction::Show { username } => std::slice::from_mut(username),
+                },
+            },
+        }
+    }
 }
 
 /// All supported generate actions.
@@ -84,6 +211,46 @@ pub enum ProfileAction {
     List,
 }
 
+/// All supported PKI actions.
+#[derive(Clone, Debug, Subcommand)]
+pub enum PkiAction {
+    /// Bootstrap a new PKI, including the CA, the server certificate and the CRL.
+    ///
+    /// The profile must contain a "pki" section.
+    Init {
+        /// Also generate a tls-crypt key.
+        #[arg(long = "tls-crypt")]
+        tls_crypt: bool,
+    },
+
+    /// Write an archive of the PKI directory and the profile's config section.
+    Backup {
+        /// The path of the archive.
+        ///
+        /// Defaults to a timestamped file in the current directory.
+        #[arg(short = 'o', long = "output-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+        output_path: Option<PathBuf>,
+
+        /// Encrypt the archive with the password in the first line of this file.
+        #[arg(long = "password-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+        password_file: Option<PathBuf>,
+    },
+
+    /// Restore the PKI directory from an archive written by `pki backup`.
+    ///
+    /// The archive is verified, and the certificates in it are checked against
+    /// its CA, before anything is restored.
+    Restore {
+        /// The archive to restore from.
+        #[arg(value_hint = ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// Decrypt the archive with the password in the first line of this file.
+        #[arg(long = "password-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+        password_file: Option<PathBuf>,
+    },
+}
+
 /// All supported user actions.
 #[derive(Clone, Debug, Subcommand)]
 pub enum UserAction {
@@ -103,6 +270,14 @@ pub enum UserAction {
             value_parser = humantime_parse_duration
         )]
         near_expiry_period: Option<Duration>,
+
+        /// Only show users with this tag. Can be repeated to require several tags.
+        #[arg(short = 't', long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Only show users whose display name, email or note contains this text.
+        #[arg(short = 's', long = "search", value_name = "TEXT")]
+        search: Option<String>,
     },
 
     /// Show info on the certificates of specified users.
@@ -113,6 +288,35 @@ pub enum UserAction {
         usernames: Vec<Username>,
     },
 
+    /// Show every certificate ever issued to a user, including renewed and
+    /// revoked ones.
+    History {
+        /// The username of the user.
+        #[arg(index = 1, value_name = "NAME")]
+        username: Username,
+
+        /// Only show certificates that were valid at this time.
+        ///
+        /// Either an RFC 3339 timestamp, a local date like "2024-01-31",
+        /// or a duration before now like "7d".
+        #[arg(long = "at", value_name = "TIME", value_parser = parse_time)]
+        at: Option<DateTime<Utc>>,
+    },
+
+    /// Show which users are connected, according to the OpenVPN server's status file.
+    ///
+    /// The profile must have "status-file" set.
+    #[command(visible_alias = "status")]
+    Online {
+        /// Only show these users.
+        #[arg(index = 1, value_name = "NAME")]
+        usernames: Vec<Username>,
+
+        /// Only show users that are connected.
+        #[arg(long = "connected")]
+        only_connected: bool,
+    },
+
     /// Generate certificates for new users.
     #[command(visible_aliases = ["add", "create"])]
     New {
@@ -123,14 +327,84 @@ pub enum UserAction {
         /// The number of days the certificate stays valid.
         #[arg(short = 'd', long = "days", value_name = "N")]
         days: Option<usize>,
+
+        #[command(flatten)]
+        metadata: MetadataArgs,
+
+        /// Protect the keys with passphrases from this source.
+        ///
+        /// One of "prompt", "generate", "stdin" (one line per user) or "file:PATH"
+        /// (the first line, for all users). Generated passphrases are delivered
+        /// via `--passphrase-output`. Defaults to "generate" if the profile requires passphrases.
+        #[arg(long = "passphrase", value_name = "SOURCE")]
+        passphrase: Option<PassphraseSource>,
+    },
+
+    /// Generate certificates for new users listed in a CSV file.
+    ///
+    /// The file must start with a header row. The "username" column is required;
+    /// the optional "days", "display-name", "email", "note" and "tags" columns set
+    /// the validity and metadata of each user. Tags are separated by commas.
+    ///
+    /// All rows are validated before anything is issued. Afterwards, a failing row
+    /// does not stop the remaining rows from being processed.
+    Import {
+        /// The CSV file to import.
+        #[arg(index = 1, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+
+        /// Package each new user afterwards, using the profile's packaging settings.
+        #[arg(long = "package")]
+        package: bool,
+
+        /// Output packages to a directory other than the current working directory.
+        #[arg(short = 'o', long = "output-dir", value_name = "DIR", value_hint = ValueHint::DirPath, requires = "package")]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Edit the email, display name, note and tags of users.
+    ///
+    /// Fields that are not specified are left unchanged. Specify a field with
+    /// an empty value to clear it.
+    Edit {
+        /// The usernames of the users to edit.
+        #[arg(index = 1, value_name = "NAME", required = true)]
+        usernames: Vec<Username>,
+
+        #[command(flatten)]
+        metadata: MetadataArgs,
+
+        /// Remove a tag from the users. Can be repeated.
+        #[arg(long = "untag", value_name = "TAG")]
+        untags: Vec<String>,
     },
 
     /// Renew certificates for existing users.
+    ///
+    /// Users can either be named explicitly, or selected by the expiry of their certificates.
     Renew {
         /// The usernames of the users to renew.
-        #[arg(index = 1, value_name = "NAME", required = true)]
+        #[arg(
+            index = 1,
+            value_name = "NAME",
+            required_unless_present_any = ["only_expired", "expiring_within"]
+        )]
         usernames: Vec<Username>,
 
+        /// Renew all users whose certificates have expired.
+        #[arg(short = 'e', long = "expired", conflicts_with = "usernames")]
+        only_expired: bool,
+
+        /// Renew all users whose certificates expire within a specific duration.
+        #[arg(
+            short = 'w',
+            long = "expiring-within",
+            value_name = "DURATION",
+            conflicts_with_all = ["usernames", "only_expired"],
+            value_parser = humantime_parse_duration
+        )]
+        expiring_within: Option<Duration>,
+
         /// The number of days the renewed certificate stays valid.
         #[arg(short = 'd', long = "days", value_name = "N")]
         days: Option<usize>,
@@ -138,6 +412,16 @@ pub enum UserAction {
         /// Do not revoke the replaced certificates.
         #[arg(short = 'k', long = "keep-old")]
         keep_old: bool,
+
+        /// Package the renewed users afterwards, using the profile's packaging settings.
+        ///
+        /// Their existing packages in the output directory are replaced.
+        #[arg(long = "repackage")]
+        repackage: bool,
+
+        /// Output packages to a directory other than the current working directory.
+        #[arg(short = 'o', long = "output-dir", value_name = "DIR", value_hint = ValueHint::DirPath, requires = "repackage")]
+        output_dir: Option<PathBuf>,
     },
 
     /// Revoke the certificates for existing users.
@@ -148,6 +432,15 @@ pub enum UserAction {
         usernames: Vec<Username>,
     },
 
+    /// Disconnect users from the OpenVPN server.
+    ///
+    /// The profile must contain a "management" section.
+    Kick {
+        /// The usernames of the users to disconnect.
+        #[arg(index = 1, value_name = "NAME", required = true)]
+        usernames: Vec<Username>,
+    },
+
     /// Create redistributable packages for the specified users.
     #[command(visible_alias = "pkg")]
     Package {
@@ -167,6 +460,146 @@ pub enum UserAction {
         /// Helpful for debugging.
         #[arg(long = "keep-temp")]
         keep_temp: bool,
+
+        /// Encrypt the packaged keys with passphrases from this source.
+        ///
+        /// Takes the same sources as `user new --passphrase`. Generated passphrases
+        /// are delivered via `--passphrase-output`, and not written into the packages. The stored keys are
+        /// left unchanged.
+        #[arg(long = "passphrase", value_name = "SOURCE")]
+        passphrase: Option<PassphraseSource>,
+
+        /// The source of the current passphrases of keys that are already protected,
+        /// for re-encrypting them with `--passphrase`, or bundling them as PKCS#12.
+        #[arg(long = "current-passphrase", value_name = "SOURCE")]
+        current_passphrase: Option<PassphraseSource>,
+    },
+
+    /// Export the credentials of users as a single file each, for clients that
+    /// import them directly.
+    ///
+    /// Unlike `package`, the skeleton directory and templates are not used.
+    Export {
+        /// The usernames of the users to export.
+        #[arg(index = 1, value_name = "NAME", required = true)]
+        usernames: Vec<Username>,
+
+        /// The format to export in.
+        ///
+        /// "ovpn" requires a "unified-profile" section in the profile's packaging section.
+        #[arg(short = 'F', long = "format", value_name = "FORMAT", value_enum)]
+        export_format: ExportFormat,
+
+        /// Encrypt PKCS#12 bundles with 3DES and SHA-1, for older clients.
+        ///
+        /// OpenSSL 3 uses AES and SHA-256 by default, which some older clients
+        /// cannot import. Same as "legacy-p12" in the profile's packaging section.
+        #[arg(long = "legacy")]
+        legacy: bool,
+
+        /// Add the profile name as a prefix to the file name.
+        #[arg(long = "add-prefix", visible_aliases = ["pre"])]
+        add_prefix: bool,
+
+        /// Output to a directory other than the current working directory.
+        #[arg(short = 'o', long = "output-dir", value_name = "DIR", value_hint = ValueHint::DirPath)]
+        output_dir: Option<PathBuf>,
+
+        /// Protect the exported keys with passphrases from this source.
+        ///
+        /// Takes the same sources as `user new --passphrase`. PKCS#12 bundles are
+        /// protected by the same passphrase, or by an empty password if not given.
+        #[arg(long = "passphrase", value_name = "SOURCE")]
+        passphrase: Option<PassphraseSource>,
+
+        /// The source of the current passphrases of keys that are already protected.
+        #[arg(long = "current-passphrase", value_name = "SOURCE")]
+        current_passphrase: Option<PassphraseSource>,
+    },
+
+    /// Manage the client-specific configs of users in the client-config-dir.
+    ///
+    /// The profile must have "ccd-dir" set.
+    Ccd {
+        #[command(subcommand)]
+        action: CcdAction,
+    },
+}
+
+/// Options that set the metadata of users.
+#[derive(Clone, Debug, Args)]
+pub struct MetadataArgs {
+    /// The display name, e.g. the full name of the person.
+    #[arg(long = "display-name", value_name = "NAME")]
+    pub display_name: Option<String>,
+
+    /// The email address.
+    #[arg(long = "email", value_name = "ADDRESS")]
+    pub email: Option<String>,
+
+    /// A free-form note.
+    #[arg(long = "note", value_name = "TEXT")]
+    pub note: Option<String>,
+
+    /// Add a tag, e.g. "contractors". Can be repeated.
+    #[arg(short = 't', long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+}
+impl From<&MetadataArgs> for MetadataChanges {
+    fn from(args: &MetadataArgs) -> Self {
+        Self {
+            display_name: args.display_name.clone(),
+            email: args.email.clone(),
+            note: args.note.clone(),
+            add_tags: args.tags.clone(),
+            remove_tags: vec![],
+        }
+    }
+}
+
+/// All supported client-config-dir actions.
+#[derive(Clone, Debug, Subcommand)]
+pub enum CcdAction {
+    /// Assign a static tunnel address to a user.
+    ///
+    /// The profile must have "ccd-subnet" set.
+    SetIp {
+        /// The username of the user.
+        #[arg(index = 1, value_name = "NAME")]
+        username: Username,
+
+        /// The address to assign.
+        #[arg(index = 2, value_name = "ADDRESS")]
+        address: Ipv4Addr,
+    },
+
+    /// Push a route to a network to a user.
+    AddRoute {
+        /// The username of the user.
+        #[arg(index = 1, value_name = "NAME")]
+        username: Username,
+
+        /// The network to route, e.g. "192.168.10.0/24".
+        #[arg(index = 2, value_name = "NETWORK")]
+        network: Ipv4Net,
+    },
+
+    /// Push an arbitrary option to a user.
+    Push {
+        /// The username of the user.
+        #[arg(index = 1, value_name = "NAME")]
+        username: Username,
+
+        /// The option to push, e.g. "dhcp-option DNS 10.8.0.1".
+        #[arg(index = 2, value_name = "OPTION")]
+        option: String,
+    },
+
+    /// Show the client-specific config of a user.
+    Show {
+        /// The username of the user.
+        #[arg(index = 1, value_name = "NAME")]
+        username: Username,
     },
 }
 
@@ -176,3 +609,20 @@ fn humantime_parse_duration(duration: &str) -> color_eyre::Result<Duration> {
     let parsed_chrono = Duration::from_std(*parsed)?;
     Ok(parsed_chrono)
 }
+
+/// Helper parser to accept a point in time, either absolute or relative to now.
+fn parse_time(time: &str) -> color_eyre::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.to_utc());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Local
+            .from_local_datetime(&date.and_time(NaiveTime::MIN))
+            .earliest()
+            .map(|time| time.to_utc())
+            .ok_or_else(|| eyre!("{date} has no midnight in the local time zone"));
+    }
+    let ago = humantime_parse_duration(time)
+        .wrap_err("Expected an RFC 3339 timestamp, a date, or a duration")?;
+    Ok(Utc::now() - ago)
+}
@@ -1,5 +1,6 @@
 use std::{
     any::type_name,
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
 };
@@ -7,12 +8,14 @@ use std::{
 use color_eyre::eyre::{bail, eyre, Context, OptionExt};
 use directories::ProjectDirs;
 use documented::{Documented, DocumentedFields};
+use ipnet::Ipv4Net;
 use itertools::Itertools;
 use log::warn;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use toml_edit::{ArrayOfTables, Decor, DocumentMut, RawString, Table};
 
-use crate::types::CustomScriptsMap;
+use crate::types::{CustomScriptsMap, PassphraseSource, Username};
 
 fn project_dirs() -> color_eyre::Result<ProjectDirs> {
     ProjectDirs::from("net", "scheimong", "openvpn-cred-management")
@@ -50,28 +53,337 @@ impl AsRef<Path> for RelativePathBuf {
     }
 }
 
+/// The kind of output produced for each user when packaging.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackagingMode {
+    /// A zip archive containing the skeleton directory and the credentials.
+    #[default]
+    Archive,
+    /// A single unified `.ovpn` profile with all credentials inlined.
+    Ovpn,
+}
+
+/// Options for rendering a unified `.ovpn` profile.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
+#[serde(rename_all = "kebab-case")]
+#[documented_fields(rename_all = "kebab-case")]
+pub struct UnifiedProfile {
+    /// The client config template, relative to the location of this config file
+    /// (if relative).
+    ///
+    /// The `<ca>`, `<cert>`, `<key>` and `<tls-crypt>` blocks are appended to its end,
+    /// so it should not reference credentials by path. Placeholders are substituted
+    /// in the same way as in `templates`.
+    pub template: PathBuf,
+
+    /// The tls-crypt key to inline, relative to the location of this config file
+    /// (if relative).
+    pub tls_crypt_key: Option<PathBuf>,
+
+    /// The subpath within the package to write the profile, in archive mode.
+    ///
+    /// Defaults to `<username>.ovpn`.
+    pub subpath: Option<RelativePathBuf>,
+}
+
 /// Options related to the `package-for` subcommand.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
 #[serde(rename_all = "kebab-case")]
 #[documented_fields(rename_all = "kebab-case")]
 pub struct Packaging {
+    /// The kind of package to produce for each user.
+    ///
+    /// - "archive": a zip archive of the skeleton directory, with the certificate,
+    ///   key and unified profile written into it where configured.
+    /// - "ovpn": only the unified profile, as `<username>.ovpn`.
+    #[serde(default)]
+    pub mode: PackagingMode,
+
     /// The skeleton directory that contains files to be included in all packages,
     /// relative to the location of this config file (if relative).
     ///
     /// Any contained symlinks will be followed.
-    pub skel_dir: PathBuf,
+    pub skel_dir: Option<PathBuf>,
 
     /// Scripts to be run on the skeleton directory before being used.
     ///
     /// These scripts are run on a temporary copy of the skeleton directory;
     /// the actual skeleton directory remains unchanged.
+    #[serde(default)]
     pub skel_map_scripts: Vec<String>,
 
+    /// Files within the skeleton directory to be rendered as templates for each user.
+    ///
+    /// Placeholders in the form of `{{ name }}` are substituted. The built-in variables
+    /// are `username`, `profile`, `serial` and `expiry`; additional ones can be defined
+    /// in `template-variables`. The unified profile template is rendered the same way.
+    #[serde(default)]
+    pub templates: Vec<RelativePathBuf>,
+
+    /// Additional variables available to templates, such as the server's address.
+    #[serde(default)]
+    pub template_variables: BTreeMap<String, String>,
+
     /// The subpath within the skeleton directory to write the user's certificate.
-    pub cert_subpath: RelativePathBuf,
+    pub cert_subpath: Option<RelativePathBuf>,
 
     /// The subpath within the skeleton directory to write the user's key.
-    pub key_subpath: RelativePathBuf,
+    pub key_subpath: Option<RelativePathBuf>,
+
+    /// The subpath within the skeleton directory to write a PKCS#12 bundle of the
+    /// user's key, certificate and the CA certificate.
+    ///
+    /// The bundle is protected by the same passphrase as the packaged key, or by
+    /// an empty password if the key is not protected.
+    pub p12_subpath: Option<RelativePathBuf>,
+
+    /// Encrypt PKCS#12 bundles with 3DES and SHA-1, instead of the AES and SHA-256
+    /// that OpenSSL 3 uses by default.
+    ///
+    /// Some older clients and operating systems cannot import bundles otherwise.
+    /// This also applies to `user export`.
+    #[serde(default)]
+    pub legacy_p12: bool,
+
+    /// Settings for rendering a unified `.ovpn` profile with inlined credentials.
+    pub unified_profile: Option<UnifiedProfile>,
+}
+
+/// The implementation used to issue and revoke certificates.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Run the easy-rsa executable.
+    #[default]
+    EasyRsa,
+    /// Sign certificates and CRLs directly, without easy-rsa.
+    Native,
+}
+
+/// The algorithm used for generating keys.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    Rsa,
+    Ec,
+    Ed,
+}
+
+/// Options related to bootstrapping a new PKI with the `pki init` subcommand.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
+#[serde(rename_all = "kebab-case")]
+#[documented_fields(rename_all = "kebab-case")]
+pub struct PkiSettings {
+    /// The common name of the CA.
+    pub ca_common_name: String,
+
+    /// The number of days for which the CA certificate should be valid.
+    pub ca_days: Option<usize>,
+
+    /// The common name of the server certificate.
+    pub server_name: String,
+
+    /// The country (C) in the CA's subject.
+    ///
+    /// If any of the organisational subject fields are set,
+    /// easy-rsa is run in "org" DN mode; otherwise only the common name is used.
+    pub country: Option<String>,
+
+    /// The state or province (ST) in the CA's subject.
+    pub province: Option<String>,
+
+    /// The city (L) in the CA's subject.
+    pub city: Option<String>,
+
+    /// The organisation (O) in the CA's subject.
+    pub organisation: Option<String>,
+
+    /// The organisational unit (OU) in the CA's subject.
+    pub organisational_unit: Option<String>,
+
+    /// The email address in the CA's subject.
+    pub email: Option<String>,
+
+    /// The key algorithm: one of "rsa", "ec" or "ed".
+    #[serde(default)]
+    pub key_algorithm: KeyAlgorithm,
+
+    /// The key size in bits, for RSA keys.
+    pub key_size: Option<usize>,
+
+    /// The curve name, for EC and EdDSA keys (e.g. "secp384r1", "ed25519").
+    pub curve: Option<String>,
+}
+impl PkiSettings {
+    /// Get the easy-rsa arguments that set the subject and key algorithm.
+    pub fn easy_rsa_args(&self) -> Vec<String> {
+        let org_fields = [
+            ("--req-c", &self.country),
+            ("--req-st", &self.province),
+            ("--req-city", &self.city),
+            ("--req-org", &self.organisation),
+            ("--req-ou", &self.organisational_unit),
+            ("--req-email", &self.email),
+        ];
+        let is_org_mode = org_fields.iter().any(|(_, v)| v.is_some());
+
+        let mut args = vec![format!("--use-algo={}", self.key_algorithm)];
+        if let Some(size) = self.key_size {
+            args.push(format!("--keysize={size}"));
+        }
+        if let Some(ref curve) = self.curve {
+            args.push(format!("--curve={curve}"));
+        }
+        if is_org_mode {
+            args.push("--dn-mode=org".into());
+            args.extend(
+                org_fields
+                    .into_iter()
+                    .filter_map(|(flag, v)| v.as_ref().map(|v| format!("{flag}={v}"))),
+            );
+        } else {
+            args.push("--dn-mode=cn_only".into());
+        }
+        args
+    }
+}
+
+/// The address of an OpenVPN management interface.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ManagementAddress {
+    /// A "host:port" address to connect to over TCP.
+    Tcp(String),
+    /// A unix socket, relative to the location of this config file (if relative).
+    Unix(PathBuf),
+}
+
+/// Options for connecting to the OpenVPN server's management interface.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
+#[serde(rename_all = "kebab-case")]
+#[documented_fields(rename_all = "kebab-case")]
+pub struct Management {
+    /// The address of the management interface: either `{ tcp = "host:port" }`
+    /// or `{ unix = "path/to/socket" }`.
+    pub address: ManagementAddress,
+
+    /// The file containing the management password on its first line, relative
+    /// to the location of this config file (if relative).
+    pub password_file: Option<PathBuf>,
+}
+
+/// How usernames are normalised to a single case.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsernameCase {
+    /// Keep usernames as they are given.
+    #[default]
+    Preserve,
+    /// Convert usernames to lowercase.
+    Lower,
+    /// Convert usernames to uppercase.
+    Upper,
+}
+
+/// Rules that usernames must follow, in addition to the built-in ones.
+///
+/// Usernames given on the command line are normalised and checked against these
+/// rules. Users on disk that do not follow them are ignored with a warning, so
+/// relax the rules before tightening them if existing users need to be managed.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
+#[serde(rename_all = "kebab-case")]
+#[documented_fields(rename_all = "kebab-case")]
+pub struct UsernamePolicy {
+    /// A regular expression that usernames must match in full.
+    pub pattern: Option<UsernamePattern>,
+
+    /// The minimum number of characters in a username.
+    pub min_length: Option<usize>,
+
+    /// The maximum number of characters in a username.
+    pub max_length: Option<usize>,
+
+    /// Names that cannot be used for users, ignoring case.
+    ///
+    /// The server name in the "pki" section is always reserved.
+    #[serde(default = "UsernamePolicy::default_reserved_names")]
+    pub reserved_names: Vec<String>,
+
+    /// Normalise usernames to a single case: "preserve", "lower" or "upper".
+    #[serde(default)]
+    pub case: UsernameCase,
+}
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            pattern: None,
+            min_length: None,
+            max_length: None,
+            reserved_names: Self::default_reserved_names(),
+            case: UsernameCase::default(),
+        }
+    }
+}
+impl UsernamePolicy {
+    fn default_reserved_names() -> Vec<String> {
+        vec!["ca".into(), "server".into()]
+    }
+}
+
+/// A username pattern, compiled when it is deserialised.
+///
+/// Patterns are compared by their source.
+#[derive(Clone, Debug, derive_more::Display, Serialize, Deserialize)]
+#[display("{source}")]
+#[serde(try_from = "String", into = "String")]
+pub struct UsernamePattern {
+    source: String,
+    /// `source`, anchored so that it matches whole usernames.
+    regex: Regex,
+}
+impl TryFrom<String> for UsernamePattern {
+    type Error = color_eyre::Report;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        match Regex::new(&format!("^(?:{source})$")) {
+            Ok(regex) => Ok(Self { source, regex }),
+            // only the outermost error is shown by the deserialiser
+            Err(err) => Err(eyre!(r#"Invalid username pattern "{source}": {err}"#)),
+        }
+    }
+}
+impl From<UsernamePattern> for String {
+    fn from(pattern: UsernamePattern) -> Self {
+        pattern.source
+    }
+}
+impl PartialEq for UsernamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+impl Eq for UsernamePattern {}
+impl UsernamePattern {
+    /// Whether a username matches this pattern in full.
+    pub fn is_match(&self, username: &str) -> bool {
+        self.regex.is_match(username)
+    }
+}
+
+/// Whether the keys of users are protected by a passphrase.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyPassphrasePolicy {
+    /// Only if `--passphrase` is given.
+    #[default]
+    Optional,
+    /// Always; a passphrase is generated unless `--passphrase` is given.
+    Required,
+    /// Never; `--passphrase` is rejected.
+    Forbidden,
 }
 
 /// Define a single profile.
@@ -85,19 +397,167 @@ pub struct Profile {
     /// The EasyRSA PKI directory.
     pub easy_rsa_pki_dir: PathBuf,
 
+    /// The backend used to issue and revoke certificates.
+    ///
+    /// - "easy-rsa": run the easy-rsa executable.
+    /// - "native": sign certificates and CRLs directly, without easy-rsa.
+    ///   Keys are generated according to `pki.key-algorithm`, except that RSA is
+    ///   not supported; EC with "secp384r1" is used if there is no `pki` section.
+    ///
+    /// Both backends keep the easy-rsa PKI layout, so they can be switched freely.
+    /// `pki init` always uses easy-rsa.
+    #[serde(default)]
+    pub backend: BackendKind,
+
     /// The default number of days for which issued certificates should be valid,
     /// if not explicitly specified via CLI.
     pub default_days: Option<usize>,
 
+    /// The OpenVPN client-config-dir, relative to the location of this config file
+    /// (if relative).
+    ///
+    /// Files in it are named after users, since OpenVPN looks them up by common name.
+    /// A user's file is removed when the user is removed.
+    pub ccd_dir: Option<PathBuf>,
+
+    /// The VPN subnet from which static addresses are assigned, e.g. "10.8.0.0/24".
+    ///
+    /// OpenVPN's "subnet" topology is assumed, so the first usable address is
+    /// reserved for the server. If set, new users are assigned the next free
+    /// address automatically.
+    pub ccd_subnet: Option<Ipv4Net>,
+
+    /// The status file written by the OpenVPN server, relative to the location of
+    /// this config file (if relative).
+    ///
+    /// `status-version` 1, 2 and 3 are supported.
+    pub status_file: Option<PathBuf>,
+
+    /// The audit log, relative to the location of this config file (if relative).
+    ///
+    /// Every issuance, renewal, revocation, packaging, export and CRL regeneration is
+    /// appended to it as a line of JSON. Use the `log` subcommand to query it.
+    pub audit_log: Option<PathBuf>,
+
+    /// The file in which the email, display name, note and tags of users are
+    /// stored, relative to the location of this config file (if relative).
+    ///
+    /// Defaults to `<profile name>.users.toml` next to this config file.
+    pub metadata_file: Option<PathBuf>,
+
     /// Packaging settings.
     pub packaging: Option<Packaging>,
 
+    /// Settings for bootstrapping a new PKI.
+    pub pki: Option<PkiSettings>,
+
+    /// Settings for the OpenVPN server's management interface.
+    ///
+    /// If set, removed users are disconnected immediately, instead of staying
+    /// connected until their next renegotiation.
+    pub management: Option<Management>,
+
+    /// Rules for the names of users.
+    #[serde(default)]
+    pub username_policy: UsernamePolicy,
+
+    /// Whether the keys of users are protected by a passphrase.
+    ///
+    /// - "optional": only if `--passphrase` is given when creating or packaging users.
+    /// - "required": always; a passphrase is generated unless `--passphrase` is given.
+    ///   Keys that are not protected yet are encrypted when packaged.
+    /// - "forbidden": never; `--passphrase` is rejected.
+    ///
+    /// Generated passphrases are printed, and not stored anywhere. Encrypting keys
+    /// requires `openssl` in `PATH`.
+    #[serde(default)]
+    pub key_passphrase: KeyPassphrasePolicy,
+
+    /// Additional scripts to be run before running an action,
+    /// defined separately for each type of action.
+    ///
+    /// These scripts are run in the current working directory. If any of them
+    /// exits with a non-zero status, the action is aborted.
+    pub pre_action_scripts: Option<CustomScriptsMap>,
+
     /// Additional scripts to be run after running an action,
     /// defined separately for each type of action.
     ///
-    /// These scripts are run in the current working directory.
+    /// These scripts are run in the current working directory. Information about
+    /// the action is passed in environment variables prefixed with `OCM_`. Only
+    /// the users the action succeeded for are passed to them.
     pub post_action_scripts: Option<CustomScriptsMap>,
 }
+impl Profile {
+    /// Normalise a username and check it against the naming policy of this profile.
+    pub fn normalise_username(&self, username: &Username) -> color_eyre::Result<Username> {
+        let profile_name = &self.name;
+        let policy = &self.username_policy;
+
+        let username: Username = match policy.case {
+            UsernameCase::Preserve => username.clone(),
+            UsernameCase::Lower => username.to_lowercase().parse()?,
+            UsernameCase::Upper => username.to_uppercase().parse()?,
+        };
+
+        let length = username.chars().count();
+        if let Some(min) = policy.min_length.filter(|min| length < *min) {
+            bail!(r#"Username "{username}" is shorter than {min} characters"#);
+        }
+        if let Some(max) = policy.max_length.filter(|max| length > *max) {
+            bail!(r#"Username "{username}" is longer than {max} characters"#);
+        }
+        if let Some(pattern) = &policy.pattern {
+            if !pattern.is_match(&username) {
+                bail!(
+                    r#"Username "{username}" does not match "{pattern}" of profile "{profile_name}""#
+                );
+            }
+        }
+        if self.is_reserved_name(&username) {
+            bail!(r#"Username "{username}" is reserved in profile "{profile_name}""#);
+        }
+
+        Ok(username)
+    }
+
+    /// Check that a username read from disk already follows the naming policy of
+    /// this profile, including its normalisation.
+    pub fn check_username(&self, username: &Username) -> color_eyre::Result<()> {
+        let normalised = self.normalise_username(username)?;
+        if normalised != *username {
+            bail!(r#"Username "{username}" is not normalised, which would be "{normalised}""#);
+        }
+        Ok(())
+    }
+
+    /// Whether a name is reserved by the naming policy of this profile, so that
+    /// it does not belong to a user.
+    pub fn is_reserved_name(&self, name: &str) -> bool {
+        let server_name = self.pki.as_ref().map(|pki| &pki.server_name);
+        (self.username_policy.reserved_names.iter())
+            .chain(server_name)
+            .any(|reserved| reserved.to_lowercase() == name.to_lowercase())
+    }
+
+    /// Get where to take the passphrases of new keys from, according to the key
+    /// passphrase policy of this profile.
+    ///
+    /// Returns `None` if keys are not to be protected.
+    pub fn passphrase_source(
+        &self,
+        requested: Option<&PassphraseSource>,
+    ) -> color_eyre::Result<Option<PassphraseSource>> {
+        let profile_name = &self.name;
+        match (self.key_passphrase, requested) {
+            (KeyPassphrasePolicy::Forbidden, Some(_)) => {
+                bail!(r#"Profile "{profile_name}" does not allow passphrase-protected keys"#)
+            }
+            (KeyPassphrasePolicy::Required, None) => Ok(Some(PassphraseSource::Generate)),
+            (_, requested) => Ok(requested.cloned()),
+        }
+    }
+}
 
 /// The whole configuration.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Documented, DocumentedFields)]
@@ -110,6 +570,13 @@ pub struct Config {
     /// The default profile to operate on.
     pub default_profile: Option<String>,
 
+    /// How many seconds to wait for another run to release its lock on a PKI
+    /// directory before giving up. Defaults to 30.
+    ///
+    /// Actions that modify a PKI directory hold an exclusive lock on a file next
+    /// to it, named after it, e.g. `.pki.lock` for `pki/`.
+    pub lock_timeout: Option<u64>,
+
     /// The list of known profiles.
     #[serde(rename = "profile")]
     #[documented_fields(rename = "profile")]
@@ -131,25 +598,70 @@ impl Config {
         .to_owned();
 
         let packaging = Packaging {
-            skel_dir: "skel/example/".into(),
+            mode: PackagingMode::Archive,
+            skel_dir: Some("skel/example/".into()),
             skel_map_scripts: vec![
                 r#"echo "You can apply custom transforms to your skeleton directory""#.into(),
                 r#"echo "before they are used to create user packages""#.into(),
             ],
-            cert_subpath: "creds/client.crt".try_into().unwrap(),
-            key_subpath: "creds/client.key".try_into().unwrap(),
+            templates: vec!["README.txt".try_into().unwrap()],
+            template_variables: [("remote".into(), "vpn.example.com 1194".into())].into(),
+            cert_subpath: Some("creds/client.crt".try_into().unwrap()),
+            key_subpath: Some("creds/client.key".try_into().unwrap()),
+            p12_subpath: None,
+            legacy_p12: false,
+            unified_profile: Some(UnifiedProfile {
+                template: "skel/example.ovpn.template".into(),
+                tls_crypt_key: Some("/etc/openvpn/server/example.tls-crypt.key".into()),
+                subpath: Some("client.ovpn".try_into().unwrap()),
+            }),
+        };
+        let pki = PkiSettings {
+            ca_common_name: "Example VPN CA".into(),
+            ca_days: Some(3650),
+            server_name: "server".into(),
+            country: None,
+            province: None,
+            city: None,
+            organisation: Some("Example".into()),
+            organisational_unit: None,
+            email: None,
+            key_algorithm: KeyAlgorithm::Ec,
+            key_size: None,
+            curve: Some("secp384r1".into()),
         };
         let profile = Profile {
             name: "example".into(),
             easy_rsa_pki_dir: "/etc/openvpn/server/example.auth.d/".into(),
+            backend: BackendKind::EasyRsa,
             default_days: Some(365),
+            ccd_dir: Some("/etc/openvpn/server/example.ccd.d/".into()),
+            ccd_subnet: Some("10.8.0.0/24".parse().unwrap()),
+            status_file: Some("/run/openvpn-server/status-example.log".into()),
+            audit_log: Some("/var/log/openvpn-cred-management/example.jsonl".into()),
+            metadata_file: Some("/etc/openvpn/server/example.users.toml".into()),
             packaging: Some(packaging),
+            pki: Some(pki),
+            management: Some(Management {
+                address: ManagementAddress::Unix("/run/openvpn-server/example.sock".into()),
+                password_file: None,
+            }),
+            username_policy: UsernamePolicy {
+                pattern: Some("[a-z][a-z0-9-]*".to_owned().try_into().unwrap()),
+                min_length: Some(2),
+                max_length: Some(32),
+                reserved_names: UsernamePolicy::default_reserved_names(),
+                case: UsernameCase::Lower,
+            },
+            key_passphrase: KeyPassphrasePolicy::Optional,
+            pre_action_scripts: Some(CustomScriptsMap::default()),
             post_action_scripts: Some(CustomScriptsMap::example()),
         };
 
         Self {
             easy_rsa_path,
             default_profile: Some("example".into()),
+            lock_timeout: Some(30),
             profiles: vec![profile],
         }
     }
@@ -172,6 +684,54 @@ impl Config {
         annotate_toml_array_of_tables::<Profile>(profiles)
             .wrap_err("Failed to annotate `Profile`")?;
 
+        // annotate `PkiSettings`
+        for (i, profile) in profiles.iter_mut().enumerate() {
+            let Some(pki) = profile.get_mut("pki") else {
+                continue; // could be no PKI section
+            };
+            let Some(pki) = pki.as_table_mut() else {
+                unreachable!("`pki` is not a table");
+            };
+            annotate_toml_table::<PkiSettings>(pki, false)
+                .wrap_err_with(|| format!("Failed to annotate `PkiSettings` #{i}"))?;
+        }
+
+        // annotate `Management`
+        for (i, profile) in profiles.iter_mut().enumerate() {
+            let Some(management) = profile.get_mut("management") else {
+                continue; // could be no management section
+            };
+            let Some(management) = management.as_table_mut() else {
+                unreachable!("`management` is not a table");
+            };
+            // otherwise `management` is an implicit table without its annotations
+            if let Some(address) = management.get_mut("address") {
+                if let Some(table) = address.as_table() {
+                    let mut inline = table.clone().into_inline_table();
+                    inline.decor_mut().set_prefix(" ");
+                    *address = toml_edit::value(inline);
+                }
+            }
+            if let Some(mut key) = management.key_mut("address") {
+                key.leaf_decor_mut().set_suffix(" ");
+            }
+            management.decor_mut().set_prefix("\n");
+            annotate_toml_table::<Management>(management, false)
+                .wrap_err_with(|| format!("Failed to annotate `Management` #{i}"))?;
+        }
+
+        // annotate `UsernamePolicy`
+        for (i, profile) in profiles.iter_mut().enumerate() {
+            let Some(policy) = profile.get_mut("username-policy") else {
+                continue; // could be no username policy section
+            };
+            let Some(policy) = policy.as_table_mut() else {
+                unreachable!("`username-policy` is not a table");
+            };
+            annotate_toml_table::<UsernamePolicy>(policy, false)
+                .wrap_err_with(|| format!("Failed to annotate `UsernamePolicy` #{i}"))?;
+        }
+
         // annotate `Packaging`
         for (i, profile) in profiles.iter_mut().enumerate() {
             let Some(packaging) = profile.get_mut("packaging") else {
@@ -182,6 +742,16 @@ impl Config {
             };
             annotate_toml_table::<Packaging>(packaging, false)
                 .wrap_err_with(|| format!("Failed to annotate `Packaging` #{i}"))?;
+
+            // annotate `UnifiedProfile`
+            let Some(unified_profile) = packaging.get_mut("unified-profile") else {
+                continue; // could be no unified profile section
+            };
+            let Some(unified_profile) = unified_profile.as_table_mut() else {
+                unreachable!("`unified-profile` is not a table");
+            };
+            annotate_toml_table::<UnifiedProfile>(unified_profile, false)
+                .wrap_err_with(|| format!("Failed to annotate `UnifiedProfile` #{i}"))?;
         }
 
         Ok(toml)
@@ -222,6 +792,7 @@ impl Config {
 struct ConfigValidator {
     easy_rsa_path: PathBuf,
     default_profile: Option<String>,
+    lock_timeout: Option<u64>,
     #[serde(rename = "profile")]
     profiles: Vec<Profile>,
 }
@@ -229,7 +800,7 @@ impl TryFrom<ConfigValidator> for Config {
     type Error = color_eyre::Report;
 
     fn try_from(config: ConfigValidator) -> Result<Self, Self::Error> {
-        let ConfigValidator { easy_rsa_path, default_profile, profiles } = config;
+        let ConfigValidator { easy_rsa_path, default_profile, lock_timeout, profiles } = config;
 
         // `default_profile` has to reference an existing profile
         if let Some(ref name) = default_profile {
@@ -240,7 +811,7 @@ impl TryFrom<ConfigValidator> for Config {
             }
         }
 
-        Ok(Self { easy_rsa_path, default_profile, profiles })
+        Ok(Self { easy_rsa_path, default_profile, lock_timeout, profiles })
     }
 }
 
@@ -326,3 +897,106 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_with(policy: &str) -> Profile {
+        let toml =
+            format!("name = \"test\"\neasy-rsa-pki-dir = \"pki\"\n[username-policy]\n{policy}");
+        toml_edit::de::from_str(&toml).unwrap()
+    }
+
+    fn normalise(profile: &Profile, username: &str) -> color_eyre::Result<String> {
+        Ok(profile.normalise_username(&username.parse()?)?.to_string())
+    }
+
+    #[test]
+    fn policy_limits_length() -> color_eyre::Result<()> {
+        let profile = profile_with("min-length = 3\nmax-length = 5");
+        assert!(normalise(&profile, "ab").is_err(), "too short");
+        assert_eq!(normalise(&profile, "abc")?, "abc");
+        assert_eq!(normalise(&profile, "abcde")?, "abcde");
+        assert!(normalise(&profile, "abcdef").is_err(), "too long");
+        Ok(())
+    }
+
+    #[test]
+    fn policy_reserves_names_ignoring_case() -> color_eyre::Result<()> {
+        let profile = profile_with("");
+        assert!(normalise(&profile, "ca").is_err(), "reserved by default");
+        assert!(
+            normalise(&profile, "Server").is_err(),
+            "reserved by default"
+        );
+        assert_eq!(normalise(&profile, "alice")?, "alice");
+
+        let profile = profile_with("reserved-names = [\"admin\"]");
+        assert!(normalise(&profile, "ADMIN").is_err(), "reserved");
+        assert_eq!(normalise(&profile, "ca")?, "ca");
+        Ok(())
+    }
+
+    #[test]
+    fn policy_normalises_case() -> color_eyre::Result<()> {
+        assert_eq!(normalise(&profile_with(""), "Alice")?, "Alice");
+        assert_eq!(
+            normalise(&profile_with("case = \"lower\""), "Alice")?,
+            "alice"
+        );
+        assert_eq!(
+            normalise(&profile_with("case = \"upper\""), "Alice")?,
+            "ALICE"
+        );
+
+        // the rest of the policy applies to the normalised username
+        let profile = profile_with("case = \"lower\"\nreserved-names = [\"admin\"]");
+        assert!(
+            normalise(&profile, "Admin").is_err(),
+            "reserved once lowercased"
+        );
+        assert!(
+            profile.check_username(&"Alice".parse()?).is_err(),
+            "not normalised"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn policy_pattern_matches_whole_username() -> color_eyre::Result<()> {
+        let profile = profile_with("pattern = \"[a-z]+|admin[0-9]\"");
+        assert_eq!(normalise(&profile, "alice")?, "alice");
+        assert_eq!(normalise(&profile, "admin1")?, "admin1");
+        assert!(
+            normalise(&profile, "alice2").is_err(),
+            "only a prefix matches"
+        );
+        assert!(
+            normalise(&profile, "2admin1").is_err(),
+            "only a suffix matches"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let err = toml_edit::de::from_str::<Profile>(
+            "name = \"test\"\neasy-rsa-pki-dir = \"pki\"\n[username-policy]\npattern = \"[a-z\"",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid username pattern"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn patterns_compare_by_source() -> color_eyre::Result<()> {
+        let pattern = UsernamePattern::try_from("[a-z]+".to_owned())?;
+        assert_eq!(pattern, UsernamePattern::try_from("[a-z]+".to_owned())?);
+        assert_ne!(pattern, UsernamePattern::try_from("[a-z]*".to_owned())?);
+        assert_eq!(String::from(pattern), "[a-z]+");
+        Ok(())
+    }
+}
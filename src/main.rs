@@ -1,22 +1,38 @@
 mod action;
 mod cli;
 mod config;
+mod management;
+mod metadata;
+mod output;
+mod pki;
+mod shell;
+mod status;
 mod types;
 
-use std::{env, io, path::Path};
+use std::{
+    collections::BTreeMap,
+    env, io,
+    path::{Path, PathBuf},
+};
 
 use chrono::Duration;
 use clap::{CommandFactory, Parser};
-use color_eyre::eyre::{bail, Context};
+use color_eyre::eyre::{bail, eyre, Context};
+use log::info;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 
 use crate::{
     action::{
-        info_user, init_config, list_near_expired, list_profiles, list_users, new_user, package,
-        remove_user, renew_user,
+        backup_pki, ccd_add_route, ccd_push, ccd_set_ip, ccd_show, edit_user, export_user,
+        import_users, info_user, init_config, init_pki, kick_user, list_near_expired, list_online,
+        list_profiles, list_users, new_user, package, read_import_file, remove_user, renew_user,
+        restore_pki, select_expiring_users, show_log, user_history, BatchOutcome, ExecOptions,
+        PassphraseOutput,
     },
-    cli::{Action, CliArgs, GenAction, ProfileAction, UserAction},
+    cli::{Action, CcdAction, CliArgs, GenAction, PkiAction, ProfileAction, UserAction},
     config::{default_config_path, Config, Profile},
+    metadata::{MetadataChanges, MetadataFilter},
+    types::{ScriptContext, ScriptPhase},
 };
 
 fn main() -> color_eyre::Result<()> {
@@ -28,10 +44,17 @@ fn main() -> color_eyre::Result<()> {
         config_path,
         profile,
         force,
+        dry_run,
+        keep_going,
+        no_rollback,
+        no_pre_action_scripts,
         no_post_action_scripts,
+        passphrase_output,
+        output_format,
         action,
         verbosity,
     } = CliArgs::parse();
+    let exec_opts = ExecOptions { force, dry_run, keep_going, no_rollback };
 
     // init logging
     let logger_config = simplelog::ConfigBuilder::new().build();
@@ -67,7 +90,7 @@ fn main() -> color_eyre::Result<()> {
 
     // handle config init
     if let Action::Gen { action: GenAction::Config } = &action {
-        init_config(&config_path, force)
+        init_config(&config_path, exec_opts)
             .wrap_err_with(|| format!("Failed to initialise config {config_path:?}"))?;
         return Ok(());
     }
@@ -82,101 +105,415 @@ fn main() -> color_eyre::Result<()> {
         .wrap_err("Cannot select a profile")?;
     let profile_name = &profile.name;
 
+    // apply the naming policy of the profile
+    let mut action = action;
+    for username in action.usernames_mut() {
+        *username = profile
+            .normalise_username(username)
+            .wrap_err_with(|| format!(r#"Invalid username for profile "{profile_name}""#))?;
+    }
+
+    // resolve users selected by the expiry of their certificates
+    let expiry_selector = match &action {
+        Action::User {
+            action: UserAction::Renew { only_expired, expiring_within, .. },
+        } => only_expired.then(Duration::zero).or(*expiring_within),
+        _ => None,
+    };
+    // read the users to import up front, so that pre-action scripts get them too
+    let mut import_entries = match &action {
+        Action::User { action: UserAction::Import { file, .. } } => Some(
+            read_import_file(config_dir, profile, file).wrap_err_with(|| {
+                format!(r#"Failed while importing users into profile "{profile_name}""#)
+            })?,
+        ),
+        _ => None,
+    };
+    let usernames = match (expiry_selector, &import_entries) {
+        (Some(period), _) => select_expiring_users(config_dir, profile, period)?,
+        (None, Some(entries)) => entries.iter().map(|e| e.username.clone()).collect(),
+        (None, None) => action.usernames().to_vec(),
+    };
+
+    // pre-action scripts
+    let mut script_context = ScriptContext {
+        profile: profile_name.clone(),
+        // allow `easy_rsa_pki_dir` to be relative to the config file
+        pki_dir: config_dir.join(&profile.easy_rsa_pki_dir),
+        usernames: usernames.clone(),
+        output_paths: BTreeMap::new(),
+    };
+    if !no_pre_action_scripts {
+        run_action_scripts(profile, &action, ScriptPhase::Pre, &script_context, dry_run)?;
+    }
+
     // other actions
+    // the failure of some users in `--keep-going` mode, reported only after the
+    // post-action scripts ran for the users that succeeded
+    let mut deferred_err = None;
+    let mut passphrase_output = PassphraseOutput::new(passphrase_output, exec_opts)?;
     match &action {
         Action::Gen { .. } => unreachable!(), // already handled
         Action::Profile { action } => match action {
-            ProfileAction::List => list_profiles(&config, profile),
+            ProfileAction::List => list_profiles(&config, profile, output_format)
+                .wrap_err("Failed to list profiles")?,
         },
+        Action::Pki { action } => match action {
+            PkiAction::Init { tls_crypt } => {
+                init_pki(config_dir, &config, profile, *tls_crypt, exec_opts).wrap_err_with(
+                    || format!(r#"Failed to initialise the PKI of profile "{profile_name}""#),
+                )?
+            }
+            PkiAction::Backup { output_path, password_file } => backup_pki(
+                config_dir,
+                &config,
+                profile,
+                output_path.as_deref(),
+                password_file.as_deref(),
+                exec_opts,
+            )
+            .wrap_err_with(|| {
+                format!(r#"Failed to back up the PKI of profile "{profile_name}""#)
+            })?,
+            PkiAction::Restore { archive, password_file } => restore_pki(
+                config_dir,
+                &config,
+                profile,
+                archive,
+                password_file.as_deref(),
+                exec_opts,
+            )
+            .wrap_err_with(|| {
+                format!(r#"Failed to restore the PKI of profile "{profile_name}""#)
+            })?,
+        },
+        Action::Log { usernames, since, until } => show_log(
+            config_dir,
+            profile,
+            usernames,
+            *since,
+            *until,
+            output_format,
+        )
+        .wrap_err_with(|| {
+            format!(r#"Failed to query the audit log of profile "{profile_name}""#)
+        })?,
         Action::User { action } => match action {
-            UserAction::List { only_expired, near_expiry_period } => {
+            UserAction::List { only_expired, near_expiry_period, tags, search } => {
+                let filter = MetadataFilter { tags: tags.clone(), search: search.clone() };
                 if *only_expired {
-                    list_near_expired(config_dir, &config, profile, Duration::zero())
-                        .wrap_err_with(|| {
-                            format!(r#"Failed to list expired users of profile "{profile_name}""#)
-                        })?
+                    list_near_expired(
+                        config_dir,
+                        profile,
+                        Duration::zero(),
+                        &filter,
+                        output_format,
+                    )
+                    .wrap_err_with(|| {
+                        format!(r#"Failed to list expired users of profile "{profile_name}""#)
+                    })?
                 } else if let Some(duration) = near_expiry_period {
-                    list_near_expired(config_dir, &config, profile, *duration).wrap_err_with(
-                        || {
+                    list_near_expired(config_dir, profile, *duration, &filter, output_format)
+                        .wrap_err_with(|| {
                             format!(
                                 r#"Failed to list near-expired users of profile "{profile_name}""#
                             )
-                        },
-                    )?
+                        })?
                 } else {
-                    list_users(config_dir, profile).wrap_err_with(|| {
+                    list_users(config_dir, profile, &filter, output_format).wrap_err_with(|| {
                         format!(r#"Failed to list users of profile "{profile_name}""#)
                     })?
                 }
             }
-            UserAction::Info { usernames } => info_user(config_dir, &config, profile, usernames)
-                .wrap_err_with(|| {
+            UserAction::Info { usernames } => {
+                info_user(config_dir, profile, usernames, output_format).wrap_err_with(|| {
                     format!(r#"Failed while querying users of profile "{profile_name}""#)
-                })?,
-            UserAction::New { usernames, days } => {
-                new_user(config_dir, &config, profile, usernames, *days, force).wrap_err_with(
-                    || format!(r#"Failed while adding users to profile "{profile_name}""#),
+                })?
+            }
+            UserAction::History { username, at } => {
+                user_history(config_dir, profile, username, *at, output_format).wrap_err_with(
+                    || format!(r#"Failed while querying the history of user "{username}""#),
                 )?
             }
-            UserAction::Renew { usernames, days, keep_old } => renew_user(
-                config_dir, &config, profile, usernames, *days, *keep_old, force,
+            UserAction::Online { usernames, only_connected } => list_online(
+                config_dir,
+                profile,
+                usernames,
+                *only_connected,
+                output_format,
             )
             .wrap_err_with(|| {
-                format!(r#"Failed while renewing users in profile "{profile_name}""#)
+                format!(r#"Failed to get online users of profile "{profile_name}""#)
             })?,
+            UserAction::New { usernames, days, metadata, passphrase } => {
+                let context =
+                    || format!(r#"Failed while adding users to profile "{profile_name}""#);
+                let outcome = new_user(
+                    config_dir,
+                    &config,
+                    profile,
+                    usernames,
+                    *days,
+                    &metadata.into(),
+                    passphrase.as_ref(),
+                    &mut passphrase_output,
+                    exec_opts,
+                    output_format,
+                )
+                .wrap_err_with(context)?;
+                deferred_err = defer_failures(outcome, &mut script_context, context);
+            }
+            UserAction::Import { package, output_dir, .. } => {
+                let output_dir = match package {
+                    true => Some(output_dir_or_cwd(output_dir)?),
+                    false => None,
+                };
+                let entries = import_entries.take().unwrap_or_default(); // read above
+                let records = import_users(
+                    config_dir,
+                    &config,
+                    profile,
+                    entries,
+                    output_dir.as_deref(),
+                    &mut passphrase_output,
+                    exec_opts,
+                    output_format,
+                )
+                .wrap_err_with(|| {
+                    format!(r#"Failed while importing users into profile "{profile_name}""#)
+                })?;
+
+                let imported = records.iter().filter(|r| r.issued);
+                script_context.usernames = imported.clone().map(|r| r.username.clone()).collect();
+                script_context.output_paths = imported
+                    .filter_map(|r| Some((r.username.clone(), r.package_path.clone()?)))
+                    .collect();
+
+                let failed_count = records.iter().filter(|r| r.error.is_some()).count();
+                if failed_count > 0 {
+                    let err = eyre!("{failed_count} of {} users failed to import", records.len());
+                    deferred_err = Some(err.wrap_err(format!(
+                        r#"Failed while importing users into profile "{profile_name}""#
+                    )));
+                }
+            }
+            UserAction::Edit { usernames, metadata, untags } => {
+                let changes = MetadataChanges { remove_tags: untags.clone(), ..metadata.into() };
+                edit_user(config_dir, &config, profile, usernames, &changes, exec_opts)
+                    .wrap_err_with(|| {
+                        format!(r#"Failed while editing users of profile "{profile_name}""#)
+                    })?
+            }
+            UserAction::Renew { days, keep_old, repackage, output_dir, .. } => {
+                let context =
+                    || format!(r#"Failed while renewing users in profile "{profile_name}""#);
+                if usernames.is_empty() {
+                    info!("No users selected for renewal");
+                }
+                let outcome = renew_user(
+                    config_dir,
+                    &config,
+                    profile,
+                    &usernames,
+                    *days,
+                    *keep_old,
+                    exec_opts,
+                    output_format,
+                )
+                .wrap_err_with(context)?;
+                deferred_err = defer_failures(outcome, &mut script_context, context);
+
+                // only the users that were renewed, so that the packages of the others are kept
+                let renewed = script_context.usernames.clone();
+                if *repackage && !renewed.is_empty() {
+                    let context =
+                        || format!(r#"Failed while repackaging users of profile "{profile_name}""#);
+                    let output_dir = output_dir_or_cwd(output_dir)?;
+                    // the previous packages hold the replaced certificates
+                    let package_opts = ExecOptions { force: true, ..exec_opts };
+                    let outcome;
+                    (script_context.output_paths, outcome) = package(
+                        config_dir,
+                        profile,
+                        &renewed,
+                        false,
+                        output_dir,
+                        false,
+                        None,
+                        None,
+                        &mut passphrase_output,
+                        package_opts,
+                        output_format,
+                    )
+                    .wrap_err_with(context)?;
+                    // the renewal failure comes first, if any
+                    let package_err = outcome.error.map(|err| err.wrap_err(context()));
+                    deferred_err = deferred_err.or(package_err);
+                }
+            }
             UserAction::Remove { usernames } => {
-                remove_user(config_dir, &config, profile, usernames, force).wrap_err_with(|| {
-                    format!(r#"Failed while removing users from profile "{profile_name}""#)
-                })?
+                let context =
+                    || format!(r#"Failed while removing users from profile "{profile_name}""#);
+                let outcome = remove_user(
+                    config_dir,
+                    &config,
+                    profile,
+                    usernames,
+                    exec_opts,
+                    output_format,
+                )
+                .wrap_err_with(context)?;
+                deferred_err = defer_failures(outcome, &mut script_context, context);
             }
+            UserAction::Kick { usernames } => kick_user(config_dir, profile, usernames, exec_opts)
+                .wrap_err_with(|| {
+                    format!(r#"Failed while disconnecting users of profile "{profile_name}""#)
+                })?,
             UserAction::Package {
                 usernames,
                 add_prefix,
                 output_dir,
                 keep_temp,
+                passphrase,
+                current_passphrase,
             } => {
-                let output_dir = match output_dir {
-                        Some(dir) => dir.to_owned(),
-                        None => env::current_dir().wrap_err(
-                            "No output directory specified, and failed to get current working directory",
-                        )?,
-                    };
-                package(
+                let context =
+                    || format!(r#"Failed while packaging users of profile "{profile_name}""#);
+                let output_dir = output_dir_or_cwd(output_dir)?;
+                let outcome;
+                (script_context.output_paths, outcome) = package(
                     config_dir,
                     profile,
                     usernames,
                     *add_prefix,
                     output_dir,
-                    force,
                     *keep_temp,
+                    passphrase.as_ref(),
+                    current_passphrase.as_ref(),
+                    &mut passphrase_output,
+                    exec_opts,
+                    output_format,
                 )
-                .wrap_err_with(|| {
-                    format!(r#"Failed while packaging users of profile "{profile_name}""#)
-                })?
+                .wrap_err_with(context)?;
+                deferred_err = defer_failures(outcome, &mut script_context, context);
+            }
+            UserAction::Export {
+                usernames,
+                export_format,
+                legacy,
+                add_prefix,
+                output_dir,
+                passphrase,
+                current_passphrase,
+            } => {
+                let context =
+                    || format!(r#"Failed while exporting users of profile "{profile_name}""#);
+                let output_dir = output_dir_or_cwd(output_dir)?;
+                let outcome;
+                (script_context.output_paths, outcome) = export_user(
+                    config_dir,
+                    profile,
+                    usernames,
+                    *export_format,
+                    *legacy,
+                    *add_prefix,
+                    output_dir,
+                    passphrase.as_ref(),
+                    current_passphrase.as_ref(),
+                    &mut passphrase_output,
+                    exec_opts,
+                    output_format,
+                )
+                .wrap_err_with(context)?;
+                deferred_err = defer_failures(outcome, &mut script_context, context);
             }
+            UserAction::Ccd { action } => match action {
+                CcdAction::SetIp { username, address } => {
+                    ccd_set_ip(config_dir, &config, profile, username, *address, exec_opts)
+                        .wrap_err_with(|| {
+                            format!(r#"Failed to assign an address to user "{username}""#)
+                        })?
+                }
+                CcdAction::AddRoute { username, network } => {
+                    ccd_add_route(config_dir, &config, profile, username, *network, exec_opts)
+                        .wrap_err_with(|| {
+                            format!(r#"Failed to add a route for user "{username}""#)
+                        })?
+                }
+                CcdAction::Push { username, option } => {
+                    ccd_push(config_dir, &config, profile, username, option, exec_opts)
+                        .wrap_err_with(|| {
+                            format!(r#"Failed to add a pushed option for user "{username}""#)
+                        })?
+                }
+                CcdAction::Show { username } => ccd_show(config_dir, profile, username)
+                    .wrap_err_with(|| format!(r#"Failed to show the CCD file of "{username}""#))?,
+            },
         },
     }
 
     // post-action scripts
     if !no_post_action_scripts {
-        run_post_action_scripts(profile, &action)?;
+        run_action_scripts(
+            profile,
+            &action,
+            ScriptPhase::Post,
+            &script_context,
+            dry_run,
+        )?;
     }
 
-    Ok(())
+    match deferred_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
 
-fn run_post_action_scripts(profile: &Profile, action: &Action) -> color_eyre::Result<()> {
+/// Let post-action scripts see only the users that an action succeeded for, and
+/// hold back the failure of the others until after the scripts.
+fn defer_failures(
+    outcome: BatchOutcome,
+    script_context: &mut ScriptContext,
+    context: impl FnOnce() -> String,
+) -> Option<color_eyre::Report> {
+    script_context.usernames = outcome.succeeded;
+    outcome.error.map(|err| err.wrap_err(context()))
+}
+
+/// Use the specified output directory, or the current working directory if unspecified.
+fn output_dir_or_cwd(output_dir: &Option<PathBuf>) -> color_eyre::Result<PathBuf> {
+    match output_dir {
+        Some(dir) => Ok(dir.to_owned()),
+        None => env::current_dir()
+            .wrap_err("No output directory specified, and failed to get current working directory"),
+    }
+}
+
+fn run_action_scripts(
+    profile: &Profile,
+    action: &Action,
+    phase: ScriptPhase,
+    context: &ScriptContext,
+    dry_run: bool,
+) -> color_eyre::Result<()> {
     let Ok(action_kind) = action.try_into() else {
         // action does not support scripting
         return Ok(());
     };
-    let Some(scripts) = &profile.post_action_scripts else {
+    let scripts = match phase {
+        ScriptPhase::Pre => &profile.pre_action_scripts,
+        ScriptPhase::Post => &profile.post_action_scripts,
+    };
+    let Some(scripts) = scripts else {
         // no scripts specified
         return Ok(());
     };
 
     scripts
-        .run_for(action_kind)
-        .wrap_err("Failed while running post-action scripts")?;
+        .run_for(action_kind, phase, context, dry_run)
+        .wrap_err_with(|| match phase {
+            ScriptPhase::Pre => "A pre-action script failed; the action was not run",
+            ScriptPhase::Post => "Failed while running post-action scripts",
+        })?;
     Ok(())
 }
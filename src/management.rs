@@ -0,0 +1,304 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use log::debug;
+
+use crate::config::{Management, ManagementAddress};
+
+/// How long to wait for the server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The prompt sent by the server when a password is required.
+const PASSWORD_PROMPT: &str = "ENTER PASSWORD:";
+
+/// A connection to the management interface, over TCP or a unix socket.
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            Self::Unix(s) => s.read(buf),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            Self::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            Self::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// A client of the OpenVPN management interface.
+///
+/// See https://openvpn.net/community-resources/management-interface/.
+pub struct ManagementClient {
+    stream: BufReader<Stream>,
+}
+impl ManagementClient {
+    /// Connect to the management interface of a profile and log in if needed.
+    ///
+    /// Paths in the settings are resolved relative to `config_dir`.
+    pub fn connect(
+        config_dir: impl AsRef<Path>,
+        management: &Management,
+    ) -> color_eyre::Result<Self> {
+        let config_dir = config_dir.as_ref();
+
+        let stream = match management.address {
+            ManagementAddress::Tcp(ref address) => {
+                let stream = TcpStream::connect(address)
+                    .wrap_err_with(|| format!(r#"Failed to connect to "{address}""#))?;
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                Stream::Tcp(stream)
+            }
+            ManagementAddress::Unix(ref path) => {
+                // allow the socket path to be relative to the config file
+                let path = config_dir.join(path);
+                let stream = UnixStream::connect(&path)
+                    .wrap_err_with(|| format!("Failed to connect to {path:?}"))?;
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                Stream::Unix(stream)
+            }
+        };
+        let mut client = Self { stream: BufReader::new(stream) };
+
+        if let Some(ref password_file) = management.password_file {
+            // allow `password_file` to be relative to the config file
+            let password_path = config_dir.join(password_file);
+            let password = std::fs::read_to_string(&password_path)
+                .wrap_err_with(|| format!("Failed to read password file {password_path:?}"))?;
+            let password = password.lines().next().unwrap_or_default();
+            client.log_in(password)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Answer the password prompt.
+    fn log_in(&mut self, password: &str) -> color_eyre::Result<()> {
+        // the prompt is not terminated by a line break
+        let mut prompt = [0; PASSWORD_PROMPT.len()];
+        self.stream
+            .read_exact(&mut prompt)
+            .wrap_err("Failed to read the password prompt")?;
+        if prompt != PASSWORD_PROMPT.as_bytes() {
+            let prompt = String::from_utf8_lossy(&prompt);
+            bail!(r#"Expected a password prompt, but got "{prompt}""#);
+        }
+        self.send(password)?;
+        self.read_response().wrap_err("Failed to log in")?;
+        Ok(())
+    }
+
+    /// Disconnect all clients with a common name.
+    ///
+    /// Returns whether any client was connected.
+    pub fn kill(&mut self, common_name: &str) -> color_eyre::Result<bool> {
+        self.send(&format!("kill {common_name}"))?;
+        match self.read_response() {
+            Ok(_) => Ok(true),
+            Err(err) if err.to_string().contains("not found") => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn send(&mut self, command: &str) -> color_eyre::Result<()> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{command}\n").as_bytes())
+            .and_then(|_| stream.flush())
+            .wrap_err("Failed to send command to the management interface")
+    }
+
+    /// Read lines until the response to the last command, skipping notifications.
+    fn read_response(&mut self) -> color_eyre::Result<String> {
+        loop {
+            let mut line = String::new();
+            let len = self
+                .stream
+                .read_line(&mut line)
+                .wrap_err("Failed to read from the management interface")?;
+            if len == 0 {
+                bail!("The management interface closed the connection");
+            }
+            let line = line.trim_end();
+            if let Some(message) = line.strip_prefix("SUCCESS:") {
+                return Ok(message.trim().to_owned());
+            }
+            if let Some(message) = line.strip_prefix("ERROR:") {
+                return Err(eyre!("{}", message.trim()));
+            }
+            // real-time notifications start with '>'
+            debug!("Ignoring management interface output: {line}");
+        }
+    }
+}
+impl Drop for ManagementClient {
+    fn drop(&mut self) {
+        // the server closes the connection after this
+        let _ = self.send("quit");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::net::UnixListener,
+        thread::{self, JoinHandle},
+    };
+
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    /// Accept one connection, send `greeting`, answer each line received with
+    /// the next reply, and return every line received until the client
+    /// disconnects.
+    fn serve(
+        listener: UnixListener,
+        greeting: &'static str,
+        replies: &'static [&'static str],
+    ) -> JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(greeting.as_bytes()).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut replies = replies.iter();
+            let mut received = vec![];
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return received;
+                }
+                received.push(line.trim_end().to_owned());
+                if let Some(reply) = replies.next() {
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        })
+    }
+
+    /// Start a fake management interface on a unix socket in `dir`.
+    fn fake_server(
+        dir: &TempDir,
+        password: Option<&str>,
+        greeting: &'static str,
+        replies: &'static [&'static str],
+    ) -> color_eyre::Result<(Management, JoinHandle<Vec<String>>)> {
+        let socket_path = dir.child("management.sock");
+        let listener = UnixListener::bind(&socket_path)?;
+        let password_file = match password {
+            Some(password) => {
+                let path = dir.child("management.pw");
+                fs::write(&path, format!("{password}\n"))?;
+                Some(path)
+            }
+            None => None,
+        };
+        let management =
+            Management { address: ManagementAddress::Unix(socket_path), password_file };
+        Ok((management, serve(listener, greeting, replies)))
+    }
+
+    #[test]
+    fn log_in_with_password() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let (management, server) = fake_server(
+            &dir,
+            Some("secret"),
+            "ENTER PASSWORD:",
+            &["SUCCESS: password is correct\n\
+                 >INFO:OpenVPN Management Interface Version 5 -- type 'help' for more info\n"],
+        )?;
+
+        let client = ManagementClient::connect(dir.path(), &management)?;
+        drop(client);
+        assert_eq!(server.join().unwrap(), ["secret", "quit"]);
+        Ok(())
+    }
+
+    #[test]
+    fn log_in_with_wrong_password() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let (management, server) = fake_server(
+            &dir,
+            Some("wrong"),
+            "ENTER PASSWORD:",
+            &["ERROR: bad password\n"],
+        )?;
+
+        let err = ManagementClient::connect(dir.path(), &management)
+            .err()
+            .expect("logging in should fail");
+        assert!(format!("{err:#}").contains("bad password"));
+        server.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn kill_connected_client() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let (management, server) = fake_server(
+            &dir,
+            None,
+            ">INFO:OpenVPN Management Interface Version 5 -- type 'help' for more info\n",
+            &[">CLIENT:DISCONNECT,0\nSUCCESS: common name 'alice' found, 1 client(s) killed\n"],
+        )?;
+
+        let mut client = ManagementClient::connect(dir.path(), &management)?;
+        assert!(client.kill("alice")?);
+        drop(client);
+        assert_eq!(server.join().unwrap(), ["kill alice", "quit"]);
+        Ok(())
+    }
+
+    #[test]
+    fn kill_disconnected_client() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let (management, server) =
+            fake_server(&dir, None, "", &["ERROR: common name 'bob' not found\n"])?;
+
+        let mut client = ManagementClient::connect(dir.path(), &management)?;
+        assert!(!client.kill("bob")?);
+        drop(client);
+        assert_eq!(server.join().unwrap(), ["kill bob", "quit"]);
+        Ok(())
+    }
+
+    #[test]
+    fn kill_fails_on_other_errors() -> color_eyre::Result<()> {
+        let dir = TempDir::new()?;
+        let (management, server) = fake_server(
+            &dir,
+            None,
+            "",
+            &["ERROR: unknown command, enter 'help' for more options\n"],
+        )?;
+
+        let mut client = ManagementClient::connect(dir.path(), &management)?;
+        let err = client.kill("carol").expect_err("killing should fail");
+        assert!(err.to_string().contains("unknown command"));
+        drop(client);
+        server.join().unwrap();
+        Ok(())
+    }
+}
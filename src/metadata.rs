@@ -0,0 +1,171 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Profile, types::Username};
+
+/// Information about a user that is not part of their certificate.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UserMetadata {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+}
+
+/// Changes to the metadata of a user.
+///
+/// Fields that are `None` are left unchanged, and fields set to an empty string
+/// are cleared.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MetadataChanges {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub note: Option<String>,
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+}
+impl MetadataChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, metadata: &mut UserMetadata) {
+        let fields = [
+            (&mut metadata.display_name, &self.display_name),
+            (&mut metadata.email, &self.email),
+            (&mut metadata.note, &self.note),
+        ];
+        for (field, change) in fields {
+            match change.as_deref() {
+                Some("") => *field = None,
+                Some(value) => *field = Some(value.to_owned()),
+                None => {}
+            }
+        }
+        metadata.tags.extend(self.add_tags.iter().cloned());
+        metadata.tags.retain(|t| !self.remove_tags.contains(t));
+    }
+}
+
+/// Criteria for selecting users by their metadata.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MetadataFilter {
+    /// Tags that a user must all have.
+    pub tags: Vec<String>,
+    /// Text that the display name, email or note must contain, ignoring case.
+    pub search: Option<String>,
+}
+impl MetadataFilter {
+    pub fn matches(&self, metadata: Option<&UserMetadata>) -> bool {
+        let Some(metadata) = metadata else {
+            return self.tags.is_empty() && self.search.is_none();
+        };
+        let has_tags = self.tags.iter().all(|t| metadata.tags.contains(t));
+        let contains_text = self.search.as_ref().is_none_or(|text| {
+            let text = text.to_lowercase();
+            [&metadata.display_name, &metadata.email, &metadata.note]
+                .into_iter()
+                .flatten()
+                .any(|field| field.to_lowercase().contains(&text))
+        });
+        has_tags && contains_text
+    }
+}
+
+/// The metadata of all users of a profile, stored as a TOML file.
+///
+/// Metadata is kept when a user is removed, so that removed users can still be
+/// identified.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MetadataStore {
+    path: PathBuf,
+    users: BTreeMap<Username, UserMetadata>,
+}
+impl MetadataStore {
+    /// Load the metadata file of a profile, or start an empty one if it does not exist.
+    pub fn load(config_dir: impl AsRef<Path>, profile: &Profile) -> color_eyre::Result<Self> {
+        let config_dir = config_dir.as_ref();
+        let path = Self::path_for(config_dir, profile);
+
+        // older versions kept it in the PKI directory by default
+        // allow `easy_rsa_pki_dir` to be relative to the config file
+        let legacy_path = config_dir
+            .join(&profile.easy_rsa_pki_dir)
+            .join("users.toml");
+        let read_path = if profile.metadata_file.is_none()
+            && !path.exists()
+            && legacy_path.is_file()
+        {
+            warn!("Reading metadata from {legacy_path:?}, which is no longer the default location; it is saved to {path:?} from now on");
+            &legacy_path
+        } else {
+            &path
+        };
+
+        let users = match fs::read_to_string(read_path) {
+            Ok(content) => toml_edit::de::from_str(&content)
+                .wrap_err_with(|| format!("Failed to parse metadata file {read_path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Failed to read metadata file {read_path:?}"))
+            }
+        };
+        Ok(Self { path, users })
+    }
+
+    /// Get the path of the metadata file of a profile.
+    pub fn path_for(config_dir: impl AsRef<Path>, profile: &Profile) -> PathBuf {
+        let config_dir = config_dir.as_ref();
+        match profile.metadata_file {
+            // allow `metadata_file` to be relative to the config file
+            Some(ref path) => config_dir.join(path),
+            // kept out of the PKI directory, which is replaced by `pki init` and `pki restore`
+            None => config_dir.join(format!("{}.users.toml", profile.name)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the metadata of a user, if any has been set.
+    pub fn get(&self, username: &Username) -> Option<&UserMetadata> {
+        self.users.get(username)
+    }
+
+    /// Get the metadata of a user for editing, starting with none set.
+    pub fn get_mut(&mut self, username: &Username) -> &mut UserMetadata {
+        self.users.entry(username.clone()).or_default()
+    }
+
+    pub fn content(&self) -> color_eyre::Result<String> {
+        // users without any metadata are left out
+        let users = self
+            .users
+            .iter()
+            .filter(|(_, metadata)| **metadata != UserMetadata::default())
+            .collect::<BTreeMap<_, _>>();
+        toml_edit::ser::to_string_pretty(&users).wrap_err("Failed to serialise user metadata")
+    }
+
+    /// Write the file, creating its parent directory if needed.
+    pub fn save(&self) -> color_eyre::Result<()> {
+        let path = &self.path;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        fs::write(path, self.content()?)
+            .wrap_err_with(|| format!("Failed to write metadata file {path:?}"))
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use color_eyre::eyre::Context;
+use itertools::Itertools;
+use log::{debug, warn};
+use serde::Serialize;
+use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
+
+use crate::{
+    config::Profile,
+    metadata::UserMetadata,
+    pki::{find_cert_by_serial, read_cert, CertStatus, IndexRecord, RevocationReason},
+    types::Username,
+};
+
+/// The format in which query results are printed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    Json,
+    Yaml,
+    Csv,
+}
+
+/// A single certificate of a user, in a format suitable for printing.
+#[serde_as]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UserRecord {
+    pub username: String,
+    pub profile: String,
+    pub serial: String,
+    pub status: CertStatus,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revocation_reason: Option<RevocationReason>,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub note: Option<String>,
+    /// Joined by commas, so that it fits into a CSV column.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    pub tags: Vec<String>,
+}
+impl UserRecord {
+    /// Create a record from an entry in the PKI database.
+    ///
+    /// The start of validity is not stored in the database, so it is read from
+    /// the archived certificate if possible.
+    pub fn new(
+        pki_dir: impl AsRef<Path>,
+        profile: &Profile,
+        record: &IndexRecord,
+        metadata: Option<&UserMetadata>,
+    ) -> Self {
+        let IndexRecord { status, expiry, revocation, serial, .. } = record;
+
+        let not_before = match find_cert_by_serial(&pki_dir, serial) {
+            Some(path) => read_cert(path)
+                .inspect_err(|err| warn!("Cannot read certificate {serial}: {err:?}"))
+                .ok()
+                .map(|info| info.not_before),
+            None => {
+                debug!("Cannot find the archived copy of certificate {serial}");
+                None
+            }
+        };
+
+        Self {
+            username: record.common_name().unwrap_or_default().to_owned(),
+            profile: profile.name.clone(),
+            serial: serial.clone(),
+            status: *status,
+            not_before,
+            not_after: *expiry,
+            revoked_at: revocation.map(|r| r.date),
+            revocation_reason: revocation.and_then(|r| r.reason),
+            display_name: metadata.and_then(|m| m.display_name.clone()),
+            email: metadata.and_then(|m| m.email.clone()),
+            note: metadata.and_then(|m| m.note.clone()),
+            tags: metadata
+                .map(|m| m.tags.iter().cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A single certificate ever issued to a user, in a format suitable for printing.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HistoryRecord {
+    pub username: String,
+    pub profile: String,
+    pub serial: String,
+    /// The status in the PKI database, except that certificates past their
+    /// expiry are always shown as expired.
+    pub status: CertStatus,
+    /// Whether this is the user's current certificate.
+    pub is_current: bool,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revocation_reason: Option<RevocationReason>,
+    /// The archived copy of the certificate, if it can be found.
+    pub path: Option<PathBuf>,
+}
+impl HistoryRecord {
+    /// Create a record from an entry in the PKI database.
+    ///
+    /// As with [`UserRecord::new`], the start of validity is read from the
+    /// archived certificate if possible.
+    pub fn new(
+        pki_dir: impl AsRef<Path>,
+        profile: &Profile,
+        record: &IndexRecord,
+        is_current: bool,
+    ) -> Self {
+        let IndexRecord { status, expiry, revocation, serial, .. } = record;
+
+        let path = find_cert_by_serial(&pki_dir, serial);
+        let not_before = match path {
+            Some(ref path) => read_cert(path)
+                .inspect_err(|err| warn!("Cannot read certificate {serial}: {err:?}"))
+                .ok()
+                .map(|info| info.not_before),
+            None => {
+                debug!("Cannot find the archived copy of certificate {serial}");
+                None
+            }
+        };
+        // easy-rsa does not update the status flag of expired certificates
+        let status = match status {
+            CertStatus::Valid if *expiry <= Utc::now() => CertStatus::Expired,
+            status => *status,
+        };
+
+        Self {
+            username: record.common_name().unwrap_or_default().to_owned(),
+            profile: profile.name.clone(),
+            serial: serial.clone(),
+            status,
+            is_current,
+            not_before,
+            not_after: *expiry,
+            revoked_at: revocation.map(|r| r.date),
+            revocation_reason: revocation.and_then(|r| r.reason),
+            path,
+        }
+    }
+
+    /// Whether the certificate was valid at a point in time.
+    ///
+    /// Certificates whose start of validity is unknown are assumed to have been
+    /// valid from the beginning.
+    pub fn was_valid_at(&self, time: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|t| t <= time)
+            && time < self.not_after
+            && self.revoked_at.is_none_or(|t| time < t)
+    }
+}
+
+/// The details of the current certificate of a user, in a format suitable for
+/// printing.
+#[serde_as]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UserInfoRecord {
+    pub username: String,
+    pub profile: String,
+    pub subject: String,
+    pub serial: String,
+    pub issuer: String,
+    pub status: CertStatus,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    /// Negative once the certificate has expired.
+    pub days_remaining: i64,
+    pub key_type: String,
+    pub key_size: Option<usize>,
+    pub fingerprint_sha256: String,
+    /// Whether the certificate is listed in the CRL, if there is one.
+    pub in_crl: Option<bool>,
+    pub has_ccd_file: bool,
+    /// The serial numbers of the user's other certificates, e.g. renewed or
+    /// revoked ones, joined by commas.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    pub other_serials: Vec<String>,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub note: Option<String>,
+    /// Joined by commas, so that it fits into a CSV column.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    pub tags: Vec<String>,
+}
+
+/// The connection status of a user, in a format suitable for printing.
+///
+/// A user connected several times has one record per connection.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OnlineRecord {
+    pub username: String,
+    pub profile: String,
+    /// Whether the user has a current certificate.
+    pub is_known: bool,
+    pub is_online: bool,
+    pub real_address: Option<String>,
+    pub virtual_address: Option<String>,
+    pub bytes_received: Option<u64>,
+    pub bytes_sent: Option<u64>,
+    pub connected_since: Option<DateTime<Utc>>,
+}
+
+/// Whether an action on several users succeeded for a single user.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum OutcomeStatus {
+    Succeeded,
+    Failed,
+    /// Not attempted, e.g. because the user does not exist.
+    Skipped,
+}
+
+/// The outcome of an action on several users for a single user, in a format
+/// suitable for printing.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutcomeRecord {
+    pub username: String,
+    pub status: OutcomeStatus,
+    pub error: Option<String>,
+}
+
+/// The outcome of importing a single row of a CSV file, in a format suitable
+/// for printing.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImportRecord {
+    /// The line of the row in the file.
+    pub line: u64,
+    pub username: Username,
+    pub issued: bool,
+    pub package_path: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+/// A single profile, in a format suitable for printing.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProfileRecord {
+    pub name: String,
+    pub pki_dir: PathBuf,
+    pub is_active: bool,
+    pub is_default: bool,
+}
+
+/// Print records to stdout in the specified format.
+///
+/// `as_text` is used to render each record as a line in the text format.
+pub fn print_records<T, F>(
+    records: &[T],
+    format: OutputFormat,
+    as_text: F,
+) -> color_eyre::Result<()>
+where
+    T: Serialize,
+    F: Fn(&T) -> String,
+{
+    match format {
+        OutputFormat::Text => {
+            let output = records.iter().map(as_text).join("\n");
+            println!("{output}");
+        }
+        OutputFormat::Json => {
+            let output =
+                serde_json::to_string_pretty(records).wrap_err("Failed to serialise as JSON")?;
+            println!("{output}");
+        }
+        OutputFormat::Yaml => {
+            let output = serde_yaml::to_string(records).wrap_err("Failed to serialise as YAML")?;
+            print!("{output}");
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            for record in records {
+                writer
+                    .serialize(record)
+                    .wrap_err("Failed to serialise as CSV")?;
+            }
+            writer.flush().wrap_err("Failed to write CSV to stdout")?;
+        }
+    }
+    Ok(())
+}
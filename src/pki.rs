@@ -0,0 +1,561 @@
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use color_eyre::eyre::{bail, eyre, Context, OptionExt};
+use itertools::Itertools;
+use log::warn;
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use x509_parser::{
+    oid_registry::{OID_SIG_ED25519, OID_SIG_ED448},
+    parse_x509_crl,
+    pem::{parse_x509_pem, Pem},
+    public_key::PublicKey,
+    x509::SubjectPublicKeyInfo,
+};
+
+/// The status flag of a certificate in the PKI database.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, strum::Display, Serialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum CertStatus {
+    Valid,
+    Revoked,
+    Expired,
+}
+impl FromStr for CertStatus {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let status = match s {
+            "V" => Self::Valid,
+            "R" => Self::Revoked,
+            "E" => Self::Expired,
+            other => bail!(r#"Unknown certificate status flag "{other}""#),
+        };
+        Ok(status)
+    }
+}
+impl CertStatus {
+    /// Get the status flag used in the PKI database.
+    pub fn as_flag(&self) -> &'static str {
+        match self {
+            Self::Valid => "V",
+            Self::Revoked => "R",
+            Self::Expired => "E",
+        }
+    }
+}
+
+/// The reason recorded for a certificate revocation, as defined in RFC 5280.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, strum::Display, strum::EnumString, Serialize)]
+#[strum(serialize_all = "camelCase")]
+#[serde(rename_all = "camelCase")]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    #[strum(serialize = "CACompromise")]
+    #[serde(rename = "CACompromise")]
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    #[strum(serialize = "removeFromCRL")]
+    #[serde(rename = "removeFromCRL")]
+    RemoveFromCrl,
+}
+
+/// The revocation details of a certificate.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Revocation {
+    pub date: DateTime<Utc>,
+    pub reason: Option<RevocationReason>,
+}
+impl FromStr for Revocation {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, reason) = match s.split_once(',') {
+            Some((date, reason)) => (date, Some(reason)),
+            None => (s, None),
+        };
+        let date = parse_asn1_time(date)?;
+        let reason = reason
+            .map(|r| {
+                r.parse::<RevocationReason>()
+                    .wrap_err_with(|| format!(r#"Unknown revocation reason "{r}""#))
+            })
+            .transpose()?;
+        Ok(Self { date, reason })
+    }
+}
+impl fmt::Display for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_asn1_time(self.date))?;
+        if let Some(reason) = self.reason {
+            write!(f, ",{reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A single record in the PKI database (`index.txt`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexRecord {
+    pub status: CertStatus,
+    pub expiry: DateTime<Utc>,
+    pub revocation: Option<Revocation>,
+    /// The serial number, normalised with [`normalise_serial`].
+    pub serial: String,
+    /// The certificate's filename, which OpenSSL leaves as "unknown".
+    pub filename: String,
+    /// The subject distinguished name, in OpenSSL's `/K=V/K=V` form.
+    pub subject: String,
+}
+impl FromStr for IndexRecord {
+    type Err = color_eyre::Report;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let [status, expiry, revocation, serial, filename, subject] = fields[..] else {
+            bail!("Expected 6 tab-separated fields, found {}", fields.len());
+        };
+
+        let status = status.parse()?;
+        let expiry = parse_asn1_time(expiry).wrap_err("Invalid expiry date")?;
+        let revocation = match revocation {
+            "" => None,
+            s => Some(s.parse().wrap_err("Invalid revocation field")?),
+        };
+        if status == CertStatus::Revoked && revocation.is_none() {
+            bail!("Certificate is marked as revoked but has no revocation date");
+        }
+
+        Ok(Self {
+            status,
+            expiry,
+            revocation,
+            serial: normalise_serial(serial),
+            filename: filename.to_owned(),
+            subject: subject.to_owned(),
+        })
+    }
+}
+/// Formats the record as a line in the PKI database, without the line break.
+impl fmt::Display for IndexRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { status, expiry, revocation, serial, filename, subject } = self;
+        let revocation = revocation.map(|r| r.to_string()).unwrap_or_default();
+        write!(
+            f,
+            "{}\t{}\t{revocation}\t{serial}\t{filename}\t{subject}",
+            status.as_flag(),
+            format_asn1_time(*expiry)
+        )
+    }
+}
+impl IndexRecord {
+    /// Get the common name (CN) in the subject, if any.
+    pub fn common_name(&self) -> Option<&str> {
+        self.subject
+            .split('/')
+            .find_map(|component| component.strip_prefix("CN="))
+    }
+
+    /// Whether this certificate has not been revoked.
+    ///
+    /// Note that easy-rsa does not update the status flag of expired certificates,
+    /// so a certificate past its expiry may still be marked as valid.
+    pub fn is_active(&self) -> bool {
+        self.status != CertStatus::Revoked
+    }
+}
+
+/// Read and parse all records in the PKI database.
+///
+/// Malformed lines are skipped with a warning.
+pub fn read_index(pki_dir: impl AsRef<Path>) -> color_eyre::Result<Vec<IndexRecord>> {
+    let index_path = pki_dir.as_ref().join("index.txt");
+    let content = fs::read_to_string(&index_path)
+        .wrap_err_with(|| format!("Failed to read PKI database {index_path:?}"))?;
+
+    let records = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(i, line)| {
+            line.parse::<IndexRecord>()
+                .inspect_err(|err| {
+                    warn!(
+                        "Line {} of {index_path:?} is malformed; ignoring: {err:?}",
+                        i + 1
+                    )
+                })
+                .ok()
+        })
+        .collect();
+    Ok(records)
+}
+
+/// Append a record to the PKI database.
+pub fn append_index_record(
+    pki_dir: impl AsRef<Path>,
+    record: &IndexRecord,
+) -> color_eyre::Result<()> {
+    let index_path = pki_dir.as_ref().join("index.txt");
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&index_path)
+        .wrap_err_with(|| format!("Failed to open PKI database {index_path:?}"))?;
+    writeln!(file, "{record}")
+        .wrap_err_with(|| format!("Failed to write into PKI database {index_path:?}"))?;
+    Ok(())
+}
+
+/// Replace the record with the same serial number in the PKI database.
+///
+/// All other lines are kept verbatim, including malformed ones.
+pub fn update_index_record(
+    pki_dir: impl AsRef<Path>,
+    record: &IndexRecord,
+) -> color_eyre::Result<()> {
+    let index_path = pki_dir.as_ref().join("index.txt");
+    let content = fs::read_to_string(&index_path)
+        .wrap_err_with(|| format!("Failed to read PKI database {index_path:?}"))?;
+
+    let mut found = false;
+    let mut output = String::with_capacity(content.len());
+    for line in content.lines() {
+        let is_match = line
+            .parse::<IndexRecord>()
+            .is_ok_and(|r| r.serial == record.serial);
+        if is_match {
+            found = true;
+            output.push_str(&record.to_string());
+        } else {
+            output.push_str(line);
+        }
+        output.push('\n');
+    }
+    if !found {
+        bail!("Certificate {} is not in the PKI database", record.serial);
+    }
+
+    fs::write(&index_path, output)
+        .wrap_err_with(|| format!("Failed to write into PKI database {index_path:?}"))?;
+    Ok(())
+}
+
+/// Find the archived copy of a certificate by its serial number.
+///
+/// easy-rsa keeps a copy of every certificate it issued under `certs_by_serial/`,
+/// and moves it elsewhere when the certificate is revoked or renewed.
+pub fn find_cert_by_serial(pki_dir: impl AsRef<Path>, serial: &str) -> Option<PathBuf> {
+    let pki_dir = pki_dir.as_ref();
+    ["", "revoked", "renewed"]
+        .into_iter()
+        .map(|parent| pki_dir.join(parent).join("certs_by_serial"))
+        .flat_map(|dir| ["pem", "crt"].map(|ext| dir.join(format!("{serial}.{ext}"))))
+        .find(|path| path.is_file())
+}
+
+/// Details read from a PEM-encoded certificate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CertInfo {
+    /// The serial number, normalised with [`normalise_serial`].
+    pub serial: String,
+    /// The subject distinguished name, in RFC 4514 form.
+    pub subject: String,
+    /// The issuer distinguished name, in RFC 4514 form.
+    pub issuer: String,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    /// The algorithm of the public key, e.g. "RSA" or "EC".
+    pub key_type: String,
+    /// The size of the public key in bits, if known.
+    pub key_size: Option<usize>,
+    /// The SHA-256 fingerprint of the certificate, in colon-separated uppercase
+    /// hexadecimal as OpenSSL prints it.
+    pub fingerprint: String,
+}
+
+/// Read a PEM-encoded certificate, to be parsed with [`Pem::parse_x509`].
+fn read_cert_pem(path: &Path) -> color_eyre::Result<Pem> {
+    let content =
+        fs::read(path).wrap_err_with(|| format!("Failed to read certificate {path:?}"))?;
+    let (_, pem) = parse_x509_pem(&content)
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{path:?} is not PEM-encoded"))?;
+    Ok(pem)
+}
+
+/// Read and parse a PEM-encoded certificate.
+pub fn read_cert(path: impl AsRef<Path>) -> color_eyre::Result<CertInfo> {
+    let path = path.as_ref();
+    let pem = read_cert_pem(path)?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{path:?} is not a valid X.509 certificate"))?;
+
+    let validity = cert.validity();
+    let not_before = DateTime::from_timestamp(validity.not_before.timestamp(), 0)
+        .ok_or_eyre("Start of validity is out of range")?;
+    let not_after = DateTime::from_timestamp(validity.not_after.timestamp(), 0)
+        .ok_or_eyre("End of validity is out of range")?;
+
+    let serial = normalise_serial(&to_hex(cert.raw_serial(), ""));
+    let (key_type, key_size) = describe_public_key(cert.public_key());
+    let fingerprint = to_hex(digest(&SHA256, &pem.contents).as_ref(), ":");
+
+    Ok(CertInfo {
+        serial,
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        not_before,
+        not_after,
+        key_type,
+        key_size,
+        fingerprint,
+    })
+}
+
+/// Get the algorithm and size in bits of a public key.
+fn describe_public_key(key: &SubjectPublicKeyInfo) -> (String, Option<usize>) {
+    let key_size = |size: usize| (size > 0).then_some(size);
+    match key.parsed() {
+        Ok(PublicKey::RSA(rsa)) => ("RSA".into(), key_size(rsa.key_size())),
+        Ok(PublicKey::EC(ec)) => ("EC".into(), key_size(ec.key_size())),
+        Ok(PublicKey::DSA(_)) => ("DSA".into(), None),
+        Ok(PublicKey::GostR3410(_) | PublicKey::GostR3410_2012(_)) => ("GOST".into(), None),
+        // x509-parser does not recognise EdDSA keys
+        Ok(PublicKey::Unknown(_)) | Err(_) => {
+            let oid = &key.algorithm.algorithm;
+            let key_type = if *oid == OID_SIG_ED25519 {
+                "Ed25519".into()
+            } else if *oid == OID_SIG_ED448 {
+                "Ed448".into()
+            } else {
+                oid.to_id_string()
+            };
+            (key_type, key_size(key.subject_public_key.data.len() * 8))
+        }
+    }
+}
+
+/// Read the serial numbers of the certificates listed in a PEM-encoded CRL.
+pub fn read_crl_serials(path: impl AsRef<Path>) -> color_eyre::Result<BTreeSet<String>> {
+    let path = path.as_ref();
+    let content = fs::read(path).wrap_err_with(|| format!("Failed to read CRL {path:?}"))?;
+    let (_, pem) = parse_x509_pem(&content)
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{path:?} is not PEM-encoded"))?;
+    let (_, crl) = parse_x509_crl(&pem.contents)
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{path:?} is not a valid CRL"))?;
+
+    let serials = crl
+        .iter_revoked_certificates()
+        .map(|revoked| normalise_serial(&to_hex(revoked.raw_serial(), "")))
+        .collect();
+    Ok(serials)
+}
+
+/// Normalise a hexadecimal serial number to the form OpenSSL uses in the PKI
+/// database and in `certs_by_serial/`.
+///
+/// That is uppercase and without leading zero bytes, such as the one DER adds to
+/// keep a serial with its top bit set positive.
+pub fn normalise_serial(serial: &str) -> String {
+    let serial = serial.to_uppercase();
+    let mut rest = serial.as_str();
+    while rest.len() > 2 && rest.starts_with("00") {
+        rest = &rest[2..];
+    }
+    rest.to_owned()
+}
+
+/// Format bytes as uppercase hexadecimal, joined by a separator.
+pub fn to_hex(bytes: &[u8], separator: &str) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).join(separator)
+}
+
+/// Check that a PEM-encoded certificate is signed by a PEM-encoded CA certificate.
+pub fn verify_cert_issuer(
+    cert_path: impl AsRef<Path>,
+    ca_path: impl AsRef<Path>,
+) -> color_eyre::Result<()> {
+    let (cert_path, ca_path) = (cert_path.as_ref(), ca_path.as_ref());
+    let (cert_pem, ca_pem) = (read_cert_pem(cert_path)?, read_cert_pem(ca_path)?);
+    let cert = cert_pem
+        .parse_x509()
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{cert_path:?} is not a valid X.509 certificate"))?;
+    let ca = ca_pem
+        .parse_x509()
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!("{ca_path:?} is not a valid X.509 certificate"))?;
+
+    if cert.issuer() != ca.subject() {
+        bail!(
+            r#"Issued by "{}", not by the CA "{}""#,
+            cert.issuer(),
+            ca.subject()
+        );
+    }
+    cert.verify_signature(Some(ca.public_key()))
+        .map_err(|err| eyre!("{err}"))
+        .wrap_err_with(|| format!(r#"Not signed by the CA "{}""#, ca.subject()))
+}
+
+/// Format a time in the same form as [`parse_asn1_time`] accepts.
+///
+/// `UTCTime` is used where possible, as OpenSSL does.
+fn format_asn1_time(time: DateTime<Utc>) -> String {
+    if (1950..2050).contains(&time.year()) {
+        time.format("%y%m%d%H%M%SZ").to_string()
+    } else {
+        time.format("%Y%m%d%H%M%SZ").to_string()
+    }
+}
+
+/// Parse an ASN.1 `UTCTime` (`YYMMDDHHMMSSZ`) or `GeneralizedTime`
+/// (`YYYYMMDDHHMMSSZ`), as used by OpenSSL's database.
+fn parse_asn1_time(s: &str) -> color_eyre::Result<DateTime<Utc>> {
+    let digits = s
+        .strip_suffix('Z')
+        .ok_or_else(|| eyre!(r#"Time "{s}" is not in UTC"#))?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        bail!(r#"Time "{s}" contains non-digit characters"#);
+    }
+    let full = match digits.len() {
+        // RFC 5280: two-digit years below 50 are in the 21st century
+        12 => {
+            let yy = digits[..2].parse::<u8>()?; // all digits
+            let century = if yy < 50 { "20" } else { "19" };
+            format!("{century}{digits}")
+        }
+        14 => digits.to_owned(),
+        _ => bail!(r#"Time "{s}" has an unexpected length"#),
+    };
+    let time = NaiveDateTime::parse_from_str(&full, "%Y%m%d%H%M%S")
+        .wrap_err_with(|| format!(r#"Time "{s}" is malformed"#))?;
+    Ok(time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        CertificateParams, CertificateRevocationListParams, IsCa, Issuer, KeyIdMethod, KeyPair,
+        RevokedCertParams, SerialNumber,
+    };
+    use temp_dir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn normalise_serial_strips_leading_zero_bytes() {
+        assert_eq!(normalise_serial("00abcdef"), "ABCDEF");
+        assert_eq!(normalise_serial("0000ABCDEF"), "ABCDEF");
+        // a zero byte within the serial is significant
+        assert_eq!(normalise_serial("0A00"), "0A00");
+        assert_eq!(normalise_serial("00"), "00");
+    }
+
+    #[test]
+    fn read_cert_serial_without_sign_byte() -> color_eyre::Result<()> {
+        let serial = [0x9F, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE];
+        let mut params = CertificateParams::new(vec!["test".into()])?;
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        let cert = params.self_signed(&KeyPair::generate()?)?;
+
+        let dir = TempDir::new()?;
+        let path = dir.child("test.crt");
+        fs::write(&path, cert.pem())?;
+
+        let info = read_cert(&path)?;
+        assert_eq!(info.serial, "9F123456789ABCDE");
+        Ok(())
+    }
+
+    #[test]
+    fn read_crl_serials_without_sign_byte() -> color_eyre::Result<()> {
+        let mut params = CertificateParams::new(vec![])?;
+        params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let issuer = Issuer::new(params, KeyPair::generate()?);
+        let now = time::OffsetDateTime::now_utc();
+        let crl = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + time::Duration::days(1),
+            crl_number: SerialNumber::from_slice(&[1]),
+            issuing_distribution_point: None,
+            revoked_certs: vec![RevokedCertParams {
+                serial_number: SerialNumber::from_slice(&[0x9F, 0x12, 0x34]),
+                revocation_time: now,
+                reason_code: None,
+                invalidity_date: None,
+            }],
+            key_identifier_method: KeyIdMethod::Sha256,
+        }
+        .signed_by(&issuer)?;
+
+        let dir = TempDir::new()?;
+        let path = dir.child("crl.pem");
+        fs::write(&path, crl.pem()?)?;
+
+        let serials = read_crl_serials(&path)?;
+        assert_eq!(serials, BTreeSet::from(["9F1234".to_owned()]));
+        Ok(())
+    }
+
+    #[test]
+    fn index_record_serial_is_normalised() -> color_eyre::Result<()> {
+        let line = "V\t271231000000Z\t\t009f1234\tunknown\t/CN=test";
+        let record = line.parse::<IndexRecord>()?;
+        assert_eq!(record.serial, "9F1234");
+        Ok(())
+    }
+
+    #[test]
+    fn index_record_round_trip() -> color_eyre::Result<()> {
+        let lines = [
+            "V\t271231000000Z\t\t9F1234\tunknown\t/CN=alice",
+            "R\t271231000000Z\t250102030405Z\t1A2B\tunknown\t/CN=bob",
+            "R\t271231000000Z\t250102030405Z,superseded\t1A2C\tunknown\t/CN=bob",
+            "R\t271231000000Z\t250102030405Z,keyCompromise\t1A2D\tunknown\t/CN=carol",
+            "E\t230101000000Z\t\t0ABC\tunknown\t/CN=dave/O=Example",
+            // GeneralizedTime past 2049, and a filename that is not the default
+            "V\t20600101000000Z\t\t0DEF\tdave.pem\t/CN=dave",
+        ];
+        for line in lines {
+            let record = line.parse::<IndexRecord>()?;
+            assert_eq!(record.to_string(), line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn index_record_fields() -> color_eyre::Result<()> {
+        let line = "R\t271231000000Z\t250102030405Z,superseded\t1A2C\tcert.pem\t/CN=bob/O=Example";
+        let record = line.parse::<IndexRecord>()?;
+        assert_eq!(record.status, CertStatus::Revoked);
+        let revocation = record.revocation.expect("revoked");
+        assert_eq!(revocation.date.to_rfc3339(), "2025-01-02T03:04:05+00:00");
+        assert_eq!(revocation.reason, Some(RevocationReason::Superseded));
+        assert_eq!(record.filename, "cert.pem");
+        assert_eq!(record.common_name(), Some("bob"));
+
+        // a revoked certificate needs a revocation date
+        assert!("R\t271231000000Z\t\t1A2C\tunknown\t/CN=bob"
+            .parse::<IndexRecord>()
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use std::fmt::Display;
+
+use xshell::Cmd;
+
+/// Run a command interactively, or only print it in dry-run mode.
+pub fn run_cmd(cmd: Cmd, dry_run: bool) -> xshell::Result<()> {
+    if dry_run {
+        print_dry_run(cmd);
+        Ok(())
+    } else {
+        cmd.run_interactive()
+    }
+}
+
+/// Print an operation that is skipped in dry-run mode.
+pub fn print_dry_run(operation: impl Display) {
+    println!("[dry-run] {operation}");
+}
@@ -0,0 +1,374 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use color_eyre::eyre::{bail, eyre, Context, OptionExt};
+
+/// A single client connection listed in an OpenVPN status file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Connection {
+    pub common_name: String,
+    /// The client's address and port, as seen by the server.
+    pub real_address: String,
+    /// The address assigned to the client within the tunnel.
+    pub virtual_address: Option<String>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub connected_since: Option<DateTime<Utc>>,
+}
+
+/// Read and parse all client connections in an OpenVPN status file.
+///
+/// `status-version` 1, 2 and 3 are supported; the version is detected automatically.
+pub fn read_status(path: impl AsRef<Path>) -> color_eyre::Result<Vec<Connection>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read status file {path:?}"))?;
+    parse_status(&content).wrap_err_with(|| format!("Failed to parse status file {path:?}"))
+}
+
+fn parse_status(content: &str) -> color_eyre::Result<Vec<Connection>> {
+    let first_line = content.lines().next().unwrap_or_default();
+    if first_line.starts_with("OpenVPN CLIENT LIST") {
+        parse_v1(content)
+    } else if first_line.starts_with("TITLE\t") {
+        parse_v2_v3(content, '\t')
+    } else if first_line.starts_with("TITLE,") {
+        parse_v2_v3(content, ',')
+    } else {
+        bail!("Unrecognised status file format")
+    }
+}
+
+/// Parse `status-version 1`, which has a client list and a routing table in
+/// separate sections, each with a header line.
+fn parse_v1(content: &str) -> color_eyre::Result<Vec<Connection>> {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum Section {
+        Preamble,
+        ClientList,
+        RoutingTable,
+        Other,
+    }
+
+    let mut section = Section::Preamble;
+    let mut client_header = None;
+    let mut routing_header = None;
+    let mut connections = vec![];
+    let mut routes = BTreeMap::new();
+    for line in content.lines() {
+        match line {
+            "ROUTING TABLE" => {
+                section = Section::RoutingTable;
+                continue;
+            }
+            "GLOBAL STATS" | "END" => {
+                section = Section::Other;
+                continue;
+            }
+            _ => {}
+        }
+        let fields = line.split(',').collect::<Vec<_>>();
+        match section {
+            Section::Preamble if fields.first() == Some(&"Common Name") => {
+                client_header = Some(Header::new(&fields));
+                section = Section::ClientList;
+            }
+            Section::ClientList => {
+                let header = client_header
+                    .as_ref()
+                    .ok_or_eyre("Missing client list header")?;
+                connections.push(header.connection(&fields)?);
+            }
+            Section::RoutingTable if routing_header.is_none() => {
+                routing_header = Some(Header::new(&fields));
+            }
+            Section::RoutingTable => {
+                let header = routing_header
+                    .as_ref()
+                    .ok_or_eyre("Missing routing table header")?;
+                let common_name = header.get(&fields, "Common Name")?;
+                let real_address = header.get(&fields, "Real Address")?;
+                let virtual_address = header.get(&fields, "Virtual Address")?;
+                // only the first route of a client is its virtual address
+                routes
+                    .entry((common_name.to_owned(), real_address.to_owned()))
+                    .or_insert_with(|| virtual_address.to_owned());
+            }
+            Section::Preamble | Section::Other => {}
+        }
+    }
+
+    for connection in &mut connections {
+        let key = (
+            connection.common_name.clone(),
+            connection.real_address.clone(),
+        );
+        connection.virtual_address = routes.remove(&key);
+    }
+    Ok(connections)
+}
+
+/// Parse `status-version` 2 (comma-separated) or 3 (tab-separated), where each
+/// line is prefixed with its kind and column names are defined by `HEADER` lines.
+fn parse_v2_v3(content: &str, separator: char) -> color_eyre::Result<Vec<Connection>> {
+    let mut headers = BTreeMap::new();
+    let mut connections = vec![];
+    for line in content.lines() {
+        let fields = line.split(separator).collect::<Vec<_>>();
+        match fields[..] {
+            ["HEADER", kind, ..] => {
+                headers.insert(kind, Header::new(&fields[2..]));
+            }
+            ["CLIENT_LIST", ..] => {
+                let header = headers
+                    .get("CLIENT_LIST")
+                    .ok_or_eyre("Missing client list header")?;
+                connections.push(header.connection(&fields[1..])?);
+            }
+            _ => {}
+        }
+    }
+    Ok(connections)
+}
+
+/// The column names of a section.
+#[derive(Clone, Debug)]
+struct Header(Vec<String>);
+impl Header {
+    fn new(fields: &[&str]) -> Self {
+        Self(fields.iter().map(|f| f.to_string()).collect())
+    }
+
+    fn index(&self, column: &str) -> Option<usize> {
+        self.0.iter().position(|c| c == column)
+    }
+
+    fn get<'a>(&self, fields: &[&'a str], column: &str) -> color_eyre::Result<&'a str> {
+        self.index(column)
+            .and_then(|i| fields.get(i).copied())
+            .ok_or_else(|| eyre!(r#"Missing column "{column}""#))
+    }
+
+    /// Parse a line of the client list.
+    fn connection(&self, fields: &[&str]) -> color_eyre::Result<Connection> {
+        let parse_bytes = |column| {
+            self.get(fields, column)?
+                .parse::<u64>()
+                .wrap_err_with(|| format!(r#"Invalid value in column "{column}""#))
+        };
+
+        // prefer the unambiguous timestamp, which is only in version 2 and 3
+        let connected_since = match self.get(fields, "Connected Since (time_t)") {
+            Ok(timestamp) => timestamp
+                .parse()
+                .ok()
+                .and_then(|t| DateTime::from_timestamp(t, 0)),
+            Err(_) => parse_local_time(self.get(fields, "Connected Since")?),
+        };
+        let virtual_address = self
+            .get(fields, "Virtual Address")
+            .ok()
+            .filter(|a| !a.is_empty())
+            .map(str::to_owned);
+
+        Ok(Connection {
+            common_name: self.get(fields, "Common Name")?.to_owned(),
+            real_address: self.get(fields, "Real Address")?.to_owned(),
+            virtual_address,
+            bytes_received: parse_bytes("Bytes Received")?,
+            bytes_sent: parse_bytes("Bytes Sent")?,
+            connected_since,
+        })
+    }
+}
+
+/// Parse a time in the server's local time zone.
+///
+/// OpenVPN writes either `ctime` format or ISO 8601 format, depending on its version.
+fn parse_local_time(s: &str) -> Option<DateTime<Utc>> {
+    ["%a %b %e %H:%M:%S %Y", "%Y-%m-%d %H:%M:%S"]
+        .into_iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-01 11:00:00 UTC.
+    const CONNECTED_SINCE: i64 = 1709290800;
+
+    fn local_time(s: &str) -> Option<DateTime<Utc>> {
+        let time = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        Some(
+            Local
+                .from_local_datetime(&time)
+                .earliest()?
+                .with_timezone(&Utc),
+        )
+    }
+
+    #[test]
+    fn parse_v1_with_routing_table() -> color_eyre::Result<()> {
+        let content = "\
+OpenVPN CLIENT LIST
+Updated,2024-03-01 12:00:00
+Common Name,Real Address,Bytes Received,Bytes Sent,Connected Since
+alice,203.0.113.5:51234,12345,67890,2024-03-01 11:00:00
+bob,198.51.100.7:40000,1,2,Fri Mar  1 10:00:00 2024
+ROUTING TABLE
+Virtual Address,Common Name,Real Address,Last Ref
+10.8.0.6,alice,203.0.113.5:51234,2024-03-01 11:59:00
+192.168.5.0/24,alice,203.0.113.5:51234,2024-03-01 11:59:00
+GLOBAL STATS
+Max bcast/mcast queue length,0
+END
+";
+        let connections = parse_status(content)?;
+        assert_eq!(
+            connections,
+            [
+                Connection {
+                    common_name: "alice".into(),
+                    real_address: "203.0.113.5:51234".into(),
+                    virtual_address: Some("10.8.0.6".into()),
+                    bytes_received: 12345,
+                    bytes_sent: 67890,
+                    connected_since: local_time("2024-03-01 11:00:00"),
+                },
+                Connection {
+                    common_name: "bob".into(),
+                    real_address: "198.51.100.7:40000".into(),
+                    // not in the routing table yet
+                    virtual_address: None,
+                    bytes_received: 1,
+                    bytes_sent: 2,
+                    connected_since: local_time("2024-03-01 10:00:00"),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_v2_without_optional_columns() -> color_eyre::Result<()> {
+        // only the columns that every version writes
+        let content = "\
+TITLE,OpenVPN 2.4.12 x86_64-pc-linux-gnu
+TIME,Fri Mar  1 12:00:00 2024,1709294400
+HEADER,CLIENT_LIST,Common Name,Real Address,Bytes Received,Bytes Sent,Connected Since
+CLIENT_LIST,alice,203.0.113.5:51234,12345,67890,2024-03-01 11:00:00
+HEADER,ROUTING_TABLE,Virtual Address,Common Name,Real Address,Last Ref,Last Ref (time_t)
+ROUTING_TABLE,10.8.0.6,alice,203.0.113.5:51234,Fri Mar  1 11:59:00 2024,1709294340
+GLOBAL_STATS,Max bcast/mcast queue length,0
+END
+";
+        let connections = parse_status(content)?;
+        assert_eq!(
+            connections,
+            [Connection {
+                common_name: "alice".into(),
+                real_address: "203.0.113.5:51234".into(),
+                virtual_address: None,
+                bytes_received: 12345,
+                bytes_sent: 67890,
+                connected_since: local_time("2024-03-01 11:00:00"),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_v2_with_optional_columns() -> color_eyre::Result<()> {
+        // as written by OpenVPN 2.6
+        let content = "\
+TITLE,OpenVPN 2.6.9 x86_64-pc-linux-gnu
+TIME,2024-03-01 12:00:00,1709294400
+HEADER,CLIENT_LIST,Common Name,Real Address,Virtual Address,Virtual IPv6 Address,Bytes Received,Bytes Sent,Connected Since,Connected Since (time_t),Username,Client ID,Peer ID,Data Channel Cipher
+CLIENT_LIST,alice,203.0.113.5:51234,10.8.0.6,,12345,67890,2024-03-01 11:00:00,1709290800,UNDEF,0,0,AES-256-GCM
+CLIENT_LIST,bob,198.51.100.7:40000,,,1,2,2024-03-01 11:00:00,1709290800,UNDEF,1,1,AES-256-GCM
+HEADER,ROUTING_TABLE,Virtual Address,Common Name,Real Address,Last Ref,Last Ref (time_t)
+ROUTING_TABLE,10.8.0.6,alice,203.0.113.5:51234,2024-03-01 11:59:00,1709294340
+GLOBAL_STATS,Max bcast/mcast queue length,0
+END
+";
+        let connections = parse_status(content)?;
+        let connected_since = DateTime::from_timestamp(CONNECTED_SINCE, 0);
+        assert_eq!(
+            connections,
+            [
+                Connection {
+                    common_name: "alice".into(),
+                    real_address: "203.0.113.5:51234".into(),
+                    virtual_address: Some("10.8.0.6".into()),
+                    bytes_received: 12345,
+                    bytes_sent: 67890,
+                    connected_since,
+                },
+                Connection {
+                    common_name: "bob".into(),
+                    real_address: "198.51.100.7:40000".into(),
+                    // an empty column means no address was assigned
+                    virtual_address: None,
+                    bytes_received: 1,
+                    bytes_sent: 2,
+                    connected_since,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_v3() -> color_eyre::Result<()> {
+        let content = "\
+TITLE\tOpenVPN 2.6.9 x86_64-pc-linux-gnu
+TIME\t2024-03-01 12:00:00\t1709294400
+HEADER\tCLIENT_LIST\tCommon Name\tReal Address\tVirtual Address\tVirtual IPv6 Address\tBytes Received\tBytes Sent\tConnected Since\tConnected Since (time_t)\tUsername\tClient ID\tPeer ID\tData Channel Cipher
+CLIENT_LIST\talice\t203.0.113.5:51234\t10.8.0.6\t\t12345\t67890\t2024-03-01 11:00:00\t1709290800\tUNDEF\t0\t0\tAES-256-GCM
+END
+";
+        let connections = parse_status(content)?;
+        assert_eq!(
+            connections,
+            [Connection {
+                common_name: "alice".into(),
+                real_address: "203.0.113.5:51234".into(),
+                virtual_address: Some("10.8.0.6".into()),
+                bytes_received: 12345,
+                bytes_sent: 67890,
+                connected_since: DateTime::from_timestamp(CONNECTED_SINCE, 0),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_empty_client_list() -> color_eyre::Result<()> {
+        let content = "\
+TITLE,OpenVPN 2.6.9 x86_64-pc-linux-gnu
+HEADER,CLIENT_LIST,Common Name,Real Address,Virtual Address,Bytes Received,Bytes Sent,Connected Since
+END
+";
+        assert_eq!(parse_status(content)?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_rows() {
+        let missing_header = "TITLE,OpenVPN\nCLIENT_LIST,alice,203.0.113.5:51234,1,2,x\n";
+        assert!(parse_status(missing_header).is_err());
+
+        let bad_bytes = "\
+TITLE,OpenVPN
+HEADER,CLIENT_LIST,Common Name,Real Address,Bytes Received,Bytes Sent,Connected Since
+CLIENT_LIST,alice,203.0.113.5:51234,many,2,2024-03-01 11:00:00
+";
+        let err = parse_status(bad_bytes).unwrap_err();
+        assert!(err.to_string().contains("Bytes Received"));
+
+        assert!(parse_status("something else\n").is_err());
+    }
+}
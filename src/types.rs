@@ -1,26 +1,63 @@
-use std::{collections::BTreeMap, ffi::OsStr, path::Path, str::FromStr, sync::LazyLock};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::LazyLock,
+};
 
-use color_eyre::eyre::{bail, eyre, Context};
+use color_eyre::eyre::{bail, Context};
+use itertools::Itertools;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use xshell::{cmd, Shell};
 
-use crate::cli::Action;
+use crate::{cli::Action, shell::run_cmd};
 
 /// A validated username.
-#[derive(Clone, Debug, derive_more::Deref, derive_more::Display, Eq, PartialEq)]
+#[derive(
+    Clone,
+    Debug,
+    derive_more::Deref,
+    derive_more::Display,
+    Eq,
+    PartialEq,
+    Hash,
+    Ord,
+    PartialOrd,
+    Serialize,
+    Deserialize,
+)]
+#[serde(try_from = "String")]
 pub struct Username(String);
 impl FromStr for Username {
     type Err = color_eyre::Report;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        const REGEX: &str = r"[\w\d\-_]+";
+        const REGEX: &str = r"^[\w\d\-_]+$";
+        // the upper bound of a certificate's common name
+        const MAX_LENGTH: usize = 64;
         static VALIDATOR: LazyLock<Regex> = LazyLock::new(|| Regex::new(REGEX).unwrap());
-        VALIDATOR
-            .is_match(s)
-            .then(|| Self(s.to_owned()))
-            .ok_or_else(|| eyre!(r#"Username "{s}" does not match "{REGEX}""#))
+
+        if !VALIDATOR.is_match(s) {
+            bail!(r#"Username "{s}" does not match "{REGEX}""#);
+        }
+        // would be taken as an option by easy-rsa and OpenSSL
+        if s.starts_with('-') {
+            bail!(r#"Username "{s}" cannot start with a hyphen"#);
+        }
+        if s.chars().count() > MAX_LENGTH {
+            bail!(r#"Username "{s}" is longer than {MAX_LENGTH} characters"#);
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+impl TryFrom<String> for Username {
+    type Error = <Self as FromStr>::Err;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
 }
 /// Required by xshell.
@@ -36,6 +73,53 @@ impl AsRef<Path> for Username {
     }
 }
 
+/// Where the passphrases of users' keys are taken from.
+#[derive(Clone, Debug, Eq, PartialEq, derive_more::Display)]
+pub enum PassphraseSource {
+    /// Prompt on the terminal for each user.
+    #[display("prompt")]
+    Prompt,
+    /// Generate a random passphrase for each user.
+    #[display("generate")]
+    Generate,
+    /// The first line of a file, shared by all users.
+    #[display("file:{}", _0.display())]
+    File(PathBuf),
+    /// One line from stdin for each user, in order.
+    #[display("stdin")]
+    Stdin,
+}
+impl FromStr for PassphraseSource {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = match s {
+            "prompt" => Self::Prompt,
+            "generate" => Self::Generate,
+            "stdin" => Self::Stdin,
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Self::File(path.into()),
+                _ => bail!(
+                    r#"Unknown passphrase source "{s}"; expected "prompt", "generate", "stdin" or "file:PATH""#
+                ),
+            },
+        };
+        Ok(source)
+    }
+}
+
+/// The file format in which the credentials of a user are exported.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ExportFormat {
+    /// A PKCS#12 bundle of the key, certificate and CA certificate.
+    P12,
+    /// The key, certificate and CA certificate concatenated as PEM.
+    Pem,
+    /// A unified profile, as configured in the profile's packaging section.
+    Ovpn,
+}
+
 #[allow(clippy::enum_variant_names)]
 /// A known action that supports custom scripting.
 #[derive(
@@ -48,38 +132,62 @@ impl AsRef<Path> for Username {
     Ord,
     PartialOrd,
     strum::EnumIter,
+    strum::Display,
     Serialize,
     Deserialize,
 )]
 #[strum(serialize_all = "kebab-case")]
 #[serde(rename_all = "kebab-case")]
 pub enum ScriptableActionKind {
+    PkiInit,
+    PkiBackup,
+    PkiRestore,
     UserList,
     UserInfo,
+    UserHistory,
+    UserOnline,
     UserNew,
+    UserImport,
+    UserEdit,
     UserRenew,
     UserRm,
+    UserKick,
     UserPkg,
+    UserExport,
+    UserCcd,
 }
 impl TryFrom<&Action> for ScriptableActionKind {
     type Error = color_eyre::Report;
     fn try_from(action: &Action) -> Result<Self, Self::Error> {
-        use crate::cli::{GenAction as G, ProfileAction as P, UserAction as U};
+        use crate::cli::{GenAction as G, PkiAction as K, ProfileAction as P, UserAction as U};
 
         // don't use wildcard matching here, so that the compiler will complain
         // if we added an action but forgot to update this
         let kind = match action {
             Action::Gen { action: G::Completion { .. } | G::Config }
-            | Action::Profile { action: P::List } => {
+            | Action::Profile { action: P::List }
+            | Action::Log { .. } => {
                 bail!("This action is not scriptable")
             }
+            Action::Pki { action } => match action {
+                K::Init { .. } => Self::PkiInit,
+                K::Backup { .. } => Self::PkiBackup,
+                K::Restore { .. } => Self::PkiRestore,
+            },
             Action::User { action, .. } => match action {
                 U::List { .. } => Self::UserList,
                 U::Info { .. } => Self::UserInfo,
+                U::History { .. } => Self::UserHistory,
+                U::Online { .. } => Self::UserOnline,
                 U::New { .. } => Self::UserNew,
+                U::Import { .. } => Self::UserImport,
+                U::Edit { .. } => Self::UserEdit,
                 U::Renew { .. } => Self::UserRenew,
                 U::Remove { .. } => Self::UserRm,
+                U::Kick { .. } => Self::UserKick,
                 U::Package { .. } => Self::UserPkg,
+                U::Export { .. } => Self::UserExport,
+                U::Ccd { .. } => Self::UserCcd,
             },
         };
         Ok(kind)
@@ -92,9 +200,114 @@ impl TryFrom<Action> for ScriptableActionKind {
     }
 }
 
+/// Whether a custom script runs before or after its action.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ScriptPhase {
+    Pre,
+    Post,
+}
+
+/// Information about an action, passed to custom scripts as environment variables.
+///
+/// - `OCM_PHASE`: "pre" or "post"
+/// - `OCM_ACTION`: the kind of action, e.g. "user-new"
+/// - `OCM_PROFILE`: the name of the selected profile
+/// - `OCM_PKI_DIR`: the PKI directory of the selected profile
+/// - `OCM_USERNAMES`: the affected usernames, separated by spaces; after an
+///   action, only the users it succeeded for
+/// - `OCM_OUTPUT_PATHS`: the paths of written packages, separated by newlines
+///
+/// Scripts run once per user additionally receive:
+///
+/// - `OCM_USERNAME`: the user's name
+/// - `OCM_CERT_PATH`: the path of the user's certificate
+/// - `OCM_KEY_PATH`: the path of the user's key
+/// - `OCM_PACKAGE_PATH`: the path of the user's package, if one was written
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScriptContext {
+    pub profile: String,
+    pub pki_dir: PathBuf,
+    /// The selected users before an action, and the users it succeeded for after it.
+    pub usernames: Vec<Username>,
+    pub output_paths: BTreeMap<Username, PathBuf>,
+}
+impl ScriptContext {
+    /// Get the environment variables to pass to scripts.
+    fn env_vars(
+        &self,
+        action: ScriptableActionKind,
+        phase: ScriptPhase,
+    ) -> Vec<(&'static str, String)> {
+        vec![
+            ("OCM_PHASE", phase.to_string()),
+            ("OCM_ACTION", action.to_string()),
+            ("OCM_PROFILE", self.profile.clone()),
+            ("OCM_PKI_DIR", self.pki_dir.to_string_lossy().into_owned()),
+            ("OCM_USERNAMES", self.usernames.iter().join(" ")),
+            (
+                "OCM_OUTPUT_PATHS",
+                self.output_paths
+                    .values()
+                    .map(|p| p.to_string_lossy())
+                    .join("\n"),
+            ),
+        ]
+    }
+
+    /// Get the additional environment variables to pass to per-user scripts.
+    fn user_env_vars(&self, username: &Username) -> Vec<(&'static str, String)> {
+        let cert_path = self.pki_dir.join("issued").join(format!("{username}.crt"));
+        let key_path = self.pki_dir.join("private").join(format!("{username}.key"));
+        let package_path = self
+            .output_paths
+            .get(username)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        vec![
+            ("OCM_USERNAME", username.to_string()),
+            ("OCM_CERT_PATH", cert_path.to_string_lossy().into_owned()),
+            ("OCM_KEY_PATH", key_path.to_string_lossy().into_owned()),
+            ("OCM_PACKAGE_PATH", package_path),
+        ]
+    }
+}
+
+/// A single custom script.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CustomScript {
+    /// A script that is run once per action.
+    Simple(String),
+    /// A script with additional options.
+    #[serde(rename_all = "kebab-case")]
+    Detailed {
+        script: String,
+        /// Run the script once for each affected user, instead of once per action.
+        #[serde(default)]
+        per_user: bool,
+    },
+}
+impl From<&str> for CustomScript {
+    fn from(script: &str) -> Self {
+        Self::Simple(script.into())
+    }
+}
+impl CustomScript {
+    fn script(&self) -> &str {
+        match self {
+            Self::Simple(script) | Self::Detailed { script, .. } => script,
+        }
+    }
+
+    fn is_per_user(&self) -> bool {
+        matches!(self, Self::Detailed { per_user: true, .. })
+    }
+}
+
 /// A map of custom scripts to be run before or after a particular action.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CustomScriptsMap(BTreeMap<ScriptableActionKind, Vec<String>>);
+pub struct CustomScriptsMap(BTreeMap<ScriptableActionKind, Vec<CustomScript>>);
 impl Default for CustomScriptsMap {
     fn default() -> Self {
         let map = ScriptableActionKind::iter().map(|a| (a, vec![])).collect();
@@ -111,14 +324,30 @@ impl CustomScriptsMap {
             .entry(ScriptableActionKind::UserList)
             .or_default()
             .push("echo 'Never play f6' >/dev/stderr".into());
+        map.0
+            .entry(ScriptableActionKind::UserPkg)
+            .or_default()
+            .push(CustomScript::Detailed {
+                script: r#"echo "Packaged $OCM_USERNAME at $OCM_PACKAGE_PATH""#.into(),
+                per_user: true,
+            });
 
         map
     }
 
     /// Run all custom scripts defined for a kind of action.
     ///
-    /// The scripts are run in the current working directory.
-    pub fn run_for(&self, action: ScriptableActionKind) -> color_eyre::Result<()> {
+    /// The scripts are run in the current working directory, with the context
+    /// passed as environment variables. Per-user scripts are run once for each
+    /// user in the context, in order, so after an action users that failed or
+    /// were skipped are left out. In dry-run mode they are only printed.
+    pub fn run_for(
+        &self,
+        action: ScriptableActionKind,
+        phase: ScriptPhase,
+        context: &ScriptContext,
+        dry_run: bool,
+    ) -> color_eyre::Result<()> {
         // skip if map key is not found or if the map entry is empty
         let Some(scripts) = self
             .0
@@ -129,12 +358,47 @@ impl CustomScriptsMap {
         };
 
         let sh = Shell::new().wrap_err("Failed to create subshell")?;
+        let env_vars = context.env_vars(action, phase);
         for script in scripts {
-            cmd!(sh, "bash -c {script}")
-                .run_interactive()
-                .wrap_err("A custom script failed to execute")?;
+            let script_str = script.script();
+            if !script.is_per_user() {
+                let cmd = cmd!(sh, "bash -c {script_str}").envs(env_vars.iter().cloned());
+                run_cmd(cmd, dry_run).wrap_err("A custom script failed to execute")?;
+                continue;
+            }
+            for username in &context.usernames {
+                let cmd = cmd!(sh, "bash -c {script_str}")
+                    .envs(env_vars.iter().cloned())
+                    .envs(context.user_env_vars(username));
+                run_cmd(cmd, dry_run).wrap_err_with(|| {
+                    format!(r#"A custom script failed to execute for user "{username}""#)
+                })?;
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_accepts_valid_names() -> color_eyre::Result<()> {
+        for name in ["alice", "bob_smith", "carol-2", "D4ve", &"x".repeat(64)] {
+            assert_eq!(name.parse::<Username>()?.to_string(), name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn username_rejects_invalid_names() {
+        for name in ["bob smith", "../ca", "-rf", "", &"x".repeat(65)] {
+            assert!(
+                name.parse::<Username>().is_err(),
+                "{name:?} should be rejected"
+            );
+        }
+    }
+}